@@ -1,23 +1,644 @@
 //! CLI args
 
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches};
+
+use crate::{
+    error::OrFail,
+    logging::{LogArea, LogFormat},
+    CliError, CloneBranches, DiscoveryOptions, RepoHost,
+};
+
+/// The file read for default options, if it exists in the current directory
+static DEFAULTS_FILE: &str = "gfsources.toml";
+
+/// The exit code when discovery fails, or an input can't be read
+pub const EXIT_FAILED: i32 = 1;
+/// The exit code when discovery completed with warnings, with `--strict`
+///
+/// (clap uses 2 for invalid arguments)
+pub const EXIT_PROBLEMS: i32 = 3;
 
 #[derive(Clone, Debug, Default, clap::Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 #[doc(hidden)] // only intended to be used from our binary
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Path to a directory where we will store font sources.
     ///
     /// This should be a directory dedicated to this task; the tool will
     /// assume that anything in it can be modified or deleted as needed.
-    pub fonts_dir: PathBuf,
+    #[arg(env = "GFSOURCES_FONTS_DIR")]
+    pub fonts_dir: Option<PathBuf>,
     /// Path to write output. If omitted, output is printed to stdout
+    ///
+    /// If the path ends in '.gz' the output is gzip-compressed.
     #[arg(short, long)]
     pub out: Option<PathBuf>,
-    /// Just print a list of repository URLs
+    /// Just print a list of repository URLs; the same as '--format list'
     #[arg(short, long)]
     pub list: bool,
-    /// Print more info to stderr
-    #[arg(short, long)]
-    pub verbose: bool,
+    /// The format of the output.
+    ///
+    /// If 'json' or 'ndjson' is requested, errors are also written to stderr
+    /// as json.
+    #[arg(long, value_enum, default_value_t, env = "GFSOURCES_FORMAT")]
+    pub format: OutputFormat,
+    /// `true` if the format was chosen explicitly, rather than by default
+    #[arg(skip)]
+    pub format_is_explicit: bool,
+    /// Only include repositories owned by this org (may be repeated)
+    #[arg(long, env = "GFSOURCES_ORG", value_delimiter = ',')]
+    pub org: Vec<String>,
+    /// Only include this family (may be repeated)
+    #[arg(long, env = "GFSOURCES_FAMILY", value_delimiter = ',')]
+    pub family: Vec<String>,
+    /// Only include repositories on this host: 'github', 'gitlab' or 'other'
+    /// (may be repeated)
+    #[arg(long, value_parser = parse_host, env = "GFSOURCES_HOST", value_delimiter = ',')]
+    pub host: Vec<RepoHost>,
+    /// Only check a stable pseudo-random sample of this many fonts
+    #[arg(long)]
+    pub sample: Option<usize>,
+    /// The seed used to choose the fonts for --sample
+    #[arg(long, default_value_t, requires = "sample")]
+    pub seed: u64,
+    /// Only check at most this many fonts
+    #[arg(long, env = "GFSOURCES_LIMIT")]
+    pub limit: Option<usize>,
+    /// The number of threads to use. Defaults to the number of cpus
+    #[arg(short, long, env = "GFSOURCES_JOBS")]
+    pub jobs: Option<usize>,
+    /// Clone every branch of the repositories we check out, instead of only
+    /// the one we need.
+    ///
+    /// Clones take longer and use more space, but all the refs are available
+    /// for bisecting in the checkouts later.
+    #[arg(long)]
+    pub all_branches: bool,
+    /// A file providing defaults for these options.
+    ///
+    /// Defaults to 'gfsources.toml' in the current directory, if it exists.
+    /// Options given on the command line or in the environment take
+    /// precedence over the file.
+    #[arg(long, env = "GFSOURCES_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Environment variables holding tokens for private repositories, keyed
+    /// by url prefix (ignoring the scheme), e.g. 'github.com/my-org'.
+    ///
+    /// These can only be set in the config file.
+    #[arg(skip)]
+    pub tokens: BTreeMap<String, String>,
+    /// Only use repositories that are already in the cache, without cloning,
+    /// fetching or making any http requests
+    #[arg(long)]
+    pub offline: bool,
+    /// Don't update google/fonts or repositories that are already in the
+    /// cache; missing repositories are still cloned
+    #[arg(long, conflicts_with = "offline")]
+    pub no_fetch: bool,
+    /// Only include fonts whose metadata changed since this google/fonts rev
+    /// or date
+    #[arg(long)]
+    pub since: Option<String>,
+    /// Keep running, refreshing the output at this interval (e.g. '30m').
+    ///
+    /// Each check only looks at fonts whose metadata in google/fonts has
+    /// changed since the last one; the output is replaced atomically, and the
+    /// changes are printed to stderr.
+    #[arg(
+        long,
+        value_parser = humantime::parse_duration,
+        requires = "out",
+        conflicts_with_all = ["stdin", "since", "resume", "strict"]
+    )]
+    pub watch: Option<std::time::Duration>,
+    /// With '--watch', serve prometheus metrics at 'http://ADDR/metrics'
+    /// (e.g. '127.0.0.1:9090')
+    #[arg(long, requires = "watch")]
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    /// Resume an interrupted run, skipping fonts it had already checked
+    #[arg(long)]
+    pub resume: bool,
+    /// If google/fonts has not changed since the last run with this flag (and
+    /// the same filters), reuse its results instead of checking every font.
+    ///
+    /// New commits in the fonts' own repositories are not noticed until
+    /// google/fonts changes.
+    #[arg(long, conflicts_with = "stdin")]
+    pub reuse_cached: bool,
+    /// Read repository urls from stdin, one per line, instead of discovering
+    /// them from google/fonts.
+    ///
+    /// Each repository is probed for config files, as during discovery. Blank
+    /// lines and lines starting with '#' are ignored, so the output of
+    /// '--format list' can be used as input.
+    #[arg(
+        long,
+        conflicts_with_all = ["org", "family", "host", "sample", "limit", "since", "resume"]
+    )]
+    pub stdin: bool,
+    /// Exit with an error if there were any warnings.
+    ///
+    /// Warnings are families that were skipped because of a problem,
+    /// repositories on unknown hosts, and repositories pinned at more than one
+    /// rev. The output is still written.
+    ///
+    /// Exit codes are 0 if there were no warnings, 1 if discovery failed,
+    /// and 3 if discovery completed with warnings.
+    #[arg(long)]
+    pub strict: bool,
+    /// Write a json report of every font that was left out during discovery,
+    /// and why, to this path
+    #[arg(long, conflicts_with_all = ["stdin", "watch"])]
+    pub discovery_report: Option<PathBuf>,
+    /// Print each problem found during discovery to stderr as a GitHub Actions
+    /// workflow command, so that it is shown as an annotation on the run
+    #[arg(long)]
+    pub github_annotations: bool,
+    /// Print how long each stage of discovery took, and the slowest
+    /// repositories, to stderr
+    #[arg(long, conflicts_with_all = ["stdin", "watch"])]
+    pub timings: bool,
+    /// Print the time spent reading metadata, probing, cloning and checking
+    /// out, in total and for the slowest repositories, to stderr
+    #[arg(long, conflicts_with_all = ["stdin", "watch"])]
+    pub profile: bool,
+    /// Print more log messages to stderr: '-v' shows info messages, '-vv'
+    /// debug messages and '-vvv' everything
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Only make log messages from these parts of the program more verbose,
+    /// for instance '-vv --log-area git,config'
+    #[arg(long, value_enum, value_delimiter = ',', global = true)]
+    pub log_area: Vec<LogArea>,
+    /// Don't print a summary to stderr when discovery finishes
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Also write log messages to this file.
+    ///
+    /// The file includes info messages, unless 'RUST_LOG' selects otherwise.
+    #[arg(long, global = true, env = "GFSOURCES_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// The format of log messages
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        env = "GFSOURCES_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+}
+
+/// Tasks other than discovering sources
+#[derive(Clone, Debug, clap::Subcommand)]
+#[doc(hidden)] // only intended to be used from our binary
+pub enum Command {
+    /// Print the JSON Schema describing the output format
+    Schema,
+    /// Print statistics about a previously generated output file
+    Stats {
+        /// The output of a previous run
+        input: PathBuf,
+        /// Print the statistics as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check out every repository in a previous output and write a lock file
+    /// recording the exact commits and file digests that were found
+    Lock {
+        /// The output of a previous run
+        input: PathBuf,
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// Path to write the lock file. If omitted, it is printed to stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Fetch every checkout in the cache, reporting which ones changed
+    ///
+    /// If an input is provided its repositories are cloned if needed and
+    /// checked out at their pinned revs; otherwise each existing checkout
+    /// fetches its upstream default branch.
+    Update {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// The output of a previous run
+        input: Option<PathBuf>,
+    },
+    /// Delete checkouts that are no longer needed
+    ///
+    /// Checkouts are deleted if they are not used by the input, or if they
+    /// have not been used for `--max-age-days`.
+    #[command(group = clap::ArgGroup::new("criteria").required(true).multiple(true))]
+    Gc {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// The output of a previous run; other checkouts are deleted
+        #[arg(group = "criteria")]
+        input: Option<PathBuf>,
+        /// Delete checkouts that have not been fetched or checked out for
+        /// this many days
+        #[arg(long, group = "criteria")]
+        max_age_days: Option<u64>,
+        /// Print what would be deleted, without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check out a single source, printing its path and the resolved sha
+    Fetch {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// A repository url, or the name of a family in the input
+        target: String,
+        /// The output of a previous run, used to find the pinned rev.
+        ///
+        /// Required when fetching a family by name. Without it, a repository
+        /// url is probed for config files as with discovery.
+        #[arg(long)]
+        input: Option<PathBuf>,
+    },
+    /// Print the source files in every repository
+    ///
+    /// By default this prints one path per line; sources that are listed in
+    /// a config file but do not exist are skipped.
+    Sources {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// The output of a previous run. If omitted, sources are discovered
+        input: Option<PathBuf>,
+        /// Print sources grouped by repository, as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check a previously generated output file for problems
+    ///
+    /// This checks the format version and every repository url, and exits
+    /// with an error if any problems are found.
+    Validate {
+        /// The output of a previous run
+        input: PathBuf,
+        /// Also check out every repository into this directory, and verify
+        /// that its config files parse and its sources exist
+        #[arg(long)]
+        fonts_dir: Option<PathBuf>,
+    },
+    /// Summarize the state of every checkout in the cache
+    Status {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// The output of a previous run, to compare checkouts with the revs
+        /// they are pinned at
+        input: Option<PathBuf>,
+    },
+    /// Check out every source and verify that it can be built
+    ///
+    /// This checks that every config file parses and that every source it
+    /// lists exists, and prints a json report of any failures. Exits with an
+    /// error if anything failed.
+    Check {
+        /// Path to a directory where we will store font sources
+        fonts_dir: PathBuf,
+        /// The output of a previous run. If omitted, sources are discovered
+        input: Option<PathBuf>,
+        /// Path to write the report. If omitted, it is printed to stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Print the entries that differ between two outputs
+    Diff {
+        /// The output of the earlier run
+        old: PathBuf,
+        /// The output of the later run
+        new: PathBuf,
+        /// The format of the output
+        #[arg(long, value_enum, default_value_t)]
+        format: DiffFormat,
+    },
+    /// Print a Markdown changelog of what changed between two runs
+    Changelog {
+        /// The output of the earlier run
+        old: PathBuf,
+        /// The output of the later run
+        new: PathBuf,
+    },
+    /// Render a summary of the output of a previous run
+    Report {
+        /// The output of a previous run
+        input: PathBuf,
+        /// The output of an earlier run; changes since then are highlighted
+        #[arg(long)]
+        previous: Option<PathBuf>,
+        /// The format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+        /// Path to write the report. If omitted, it is printed to stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// The shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print a man page, in roff format
+    Man,
+}
+
+/// Options that can be set in the config file
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileDefaults {
+    fonts_dir: Option<PathBuf>,
+    format: Option<OutputFormat>,
+    org: Vec<String>,
+    family: Vec<String>,
+    host: Vec<String>,
+    limit: Option<usize>,
+    jobs: Option<usize>,
+    all_branches: bool,
+    tokens: BTreeMap<String, String>,
+}
+
+/// Formats for the output of the `diff` subcommand
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[doc(hidden)] // only intended to be used from our binary
+pub enum DiffFormat {
+    /// One line per entry
+    #[default]
+    Text,
+    /// The added, removed and changed entries, as json
+    Json,
+    /// A changelog, as with the 'changelog' subcommand
+    Markdown,
+}
+
+/// Formats for the output of the `report` subcommand
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[doc(hidden)] // only intended to be used from our binary
+pub enum ReportFormat {
+    /// Markdown, for pasting into issues
+    #[default]
+    Markdown,
+    /// A standalone HTML page
+    Html,
+}
+
+/// Formats the tool can write
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[doc(hidden)] // only intended to be used from our binary
+pub enum OutputFormat {
+    /// A single json object
+    #[default]
+    Json,
+    /// Just the repository urls, one per line
+    List,
+    /// One json object per line for each repository, without a header.
+    ///
+    /// Problems are written to stderr, one json object per line.
+    Ndjson,
+    /// A csv table with one row per repository
+    Csv,
+    /// A single yaml document
+    Yaml,
+    /// A single toml document
+    Toml,
+}
+
+impl Args {
+    /// Parse the command line, filling in defaults from the config file.
+    ///
+    /// Exits with an error if the arguments or the config file are invalid.
+    pub fn parse_with_defaults() -> Self {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        args.format_is_explicit = !matches!(
+            matches.value_source("format"),
+            None | Some(ValueSource::DefaultValue)
+        );
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULTS_FILE)).filter(|path| path.exists()),
+        };
+        if let Some(path) = path {
+            let defaults = load_defaults(&path).unwrap_or_else(|e| {
+                Args::command()
+                    .error(clap::error::ErrorKind::InvalidValue, e)
+                    .exit()
+            });
+            args.apply_defaults(defaults, &matches).unwrap_or_else(|e| {
+                Args::command()
+                    .error(clap::error::ErrorKind::InvalidValue, e)
+                    .exit()
+            });
+        }
+        if args.command.is_none() && args.fonts_dir.is_none() {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "the following required arguments were not provided:\n  <FONTS_DIR>",
+                )
+                .exit()
+        }
+        args
+    }
+
+    // use values from the file for options that were not otherwise provided
+    fn apply_defaults(
+        &mut self,
+        defaults: FileDefaults,
+        matches: &ArgMatches,
+    ) -> Result<(), String> {
+        let unset = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                None | Some(ValueSource::DefaultValue)
+            )
+        };
+        if unset("fonts_dir") {
+            self.fonts_dir = defaults.fonts_dir.or(self.fonts_dir.take());
+        }
+        if let Some(format) = defaults.format.filter(|_| unset("format")) {
+            self.format = format;
+            self.format_is_explicit = true;
+        }
+        if unset("org") {
+            self.org = defaults.org;
+        }
+        if unset("family") {
+            self.family = defaults.family;
+        }
+        if unset("host") {
+            self.host = defaults
+                .host
+                .iter()
+                .map(|host| parse_host(host))
+                .collect::<Result<_, _>>()?;
+        }
+        if unset("limit") {
+            self.limit = defaults.limit.or(self.limit);
+        }
+        if unset("jobs") {
+            self.jobs = defaults.jobs.or(self.jobs);
+        }
+        if unset("all_branches") {
+            self.all_branches = defaults.all_branches;
+        }
+        self.tokens = defaults.tokens;
+        Ok(())
+    }
+
+    /// `true` if json output was requested, in which case errors are also
+    /// reported as json
+    pub fn json_errors(&self) -> bool {
+        self.format_is_explicit
+            && matches!(
+                self.output_format(),
+                OutputFormat::Json | OutputFormat::Ndjson
+            )
+    }
+
+    /// Which branches are fetched when a repository is cloned
+    pub fn clone_branches(&self) -> CloneBranches {
+        match self.all_branches {
+            true => CloneBranches::All,
+            false => CloneBranches::Single,
+        }
+    }
+
+    /// The format of the output, taking '--list' into account
+    pub fn output_format(&self) -> OutputFormat {
+        if self.list {
+            OutputFormat::List
+        } else {
+            self.format
+        }
+    }
+
+    /// Set up logging to stderr and the log file, if any
+    pub fn init_logging(&self) -> Result<(), CliError> {
+        crate::logging::init(
+            self.log_file.as_deref(),
+            self.log_format,
+            self.verbose,
+            &self.log_area,
+        )
+        .or_fail(|| {
+            let path = self.log_file.as_deref().unwrap_or(Path::new(""));
+            format!("failed to create log file '{}'", path.display())
+        })
+    }
+
+    /// The discovery options selected by these arguments
+    pub fn discovery_options(&self) -> DiscoveryOptions {
+        let mut options = DiscoveryOptions::default();
+        options.orgs.clone_from(&self.org);
+        options.families.clone_from(&self.family);
+        options.hosts.clone_from(&self.host);
+        options.sample = self.sample;
+        options.seed = self.seed;
+        options.limit = self.limit;
+        options.offline = self.offline;
+        options.no_fetch = self.no_fetch;
+        options.since.clone_from(&self.since);
+        options.resume = self.resume;
+        options.reuse_cached = self.reuse_cached;
+        options.clone_branches = self.clone_branches();
+        options.tokens.clone_from(&self.tokens);
+        options
+    }
+}
+
+fn parse_host(host: &str) -> Result<RepoHost, String> {
+    match host.to_ascii_lowercase().as_str() {
+        "github" => Ok(RepoHost::GitHub),
+        "gitlab" => Ok(RepoHost::GitLab),
+        "other" => Ok(RepoHost::Other),
+        _ => Err(format!("unknown host '{host}'")),
+    }
+}
+
+fn load_defaults(path: &Path) -> Result<FileDefaults, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read '{}': {e}", path.display()))?;
+    toml::from_str(&contents).map_err(|e| format!("failed to parse '{}': {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn defaults_from_file() {
+        let defaults: FileDefaults = toml::from_str(
+            r#"
+            fonts-dir = "from-file"
+            format = "yaml"
+            org = ["googlefonts"]
+            host = ["github"]
+            jobs = 4
+            all-branches = true
+
+            [tokens]
+            "github.com/private-org" = "PRIVATE_TOKEN"
+            "#,
+        )
+        .unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["gfsources", "from-cli", "--org", "other"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        args.apply_defaults(defaults, &matches).unwrap();
+
+        // the command line wins
+        assert_eq!(args.fonts_dir.as_deref(), Some(Path::new("from-cli")));
+        assert_eq!(args.org, ["other"]);
+        // otherwise the file is used
+        assert_eq!(args.format, OutputFormat::Yaml);
+        assert_eq!(args.host, [RepoHost::GitHub]);
+        assert_eq!(args.jobs, Some(4));
+        assert!(args.all_branches);
+        assert_eq!(args.tokens["github.com/private-org"], "PRIVATE_TOKEN");
+
+        assert!(toml::from_str::<FileDefaults>("colour = \"blue\"").is_err());
+    }
+
+    #[test]
+    fn completions_and_man_page() {
+        let mut cmd = Args::command();
+        cmd.build();
+        let mut out = Vec::new();
+        clap_complete::generate(clap_complete::Shell::Bash, &mut cmd, "gfs", &mut out);
+        assert!(String::from_utf8(out).unwrap().contains("changelog"));
+
+        let mut out = Vec::new();
+        clap_mangen::Man::new(Args::command())
+            .render(&mut out)
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("\\-\\-strict"));
+    }
+
+    #[test]
+    fn list_is_a_format() {
+        let args = Args::try_parse_from(["gfsources", "dir", "--list"]).unwrap();
+        assert_eq!(args.output_format(), OutputFormat::List);
+        let args = Args::try_parse_from(["gfsources", "dir", "--format", "list"]).unwrap();
+        assert_eq!(args.output_format(), OutputFormat::List);
+        let args = Args::try_parse_from(["gfsources", "dir"]).unwrap();
+        assert_eq!(args.output_format(), OutputFormat::Json);
+    }
 }
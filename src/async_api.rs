@@ -0,0 +1,141 @@
+//! an async api for discovery and checkouts, for use with tokio
+//!
+//! Discovery and checking out are made of blocking work (git commands, file
+//! io and http requests), so these functions run that work on tokio's
+//! blocking thread pool instead of the caller's task; checkouts of different
+//! repositories run concurrently.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{
+    batch::instantiate_with_retries, error::LoadRepoError, CloneBranches, DiscoveryOptions,
+    DiscoveryReport, Error, RepoInfo, SourceSet,
+};
+
+/// Discover sources, as with [`discover_sources_with_report`], without
+/// blocking the async runtime.
+///
+/// Repositories are probed concurrently, as they are by the blocking version.
+/// This must be called from within a tokio runtime.
+///
+/// [`discover_sources_with_report`]: crate::discover_sources_with_report
+pub async fn discover_sources_async(
+    git_cache_dir: impl Into<PathBuf>,
+    options: DiscoveryOptions,
+) -> Result<(SourceSet, DiscoveryReport), Error> {
+    let git_cache_dir = git_cache_dir.into();
+    run_blocking(move || crate::discover_sources_with_report(&git_cache_dir, &options)).await
+}
+
+impl RepoInfo {
+    /// Check out this repository, as with [`instantiate`](Self::instantiate),
+    /// without blocking the async runtime.
+    pub async fn instantiate_async(&self, cache_dir: &Path) -> Result<PathBuf, LoadRepoError> {
+        let info = self.clone();
+        let cache_dir = cache_dir.to_owned();
+        run_blocking(move || info.instantiate(&cache_dir)).await
+    }
+}
+
+impl SourceSet {
+    /// Check out every entry in this set, as with
+    /// [`instantiate_all`](Self::instantiate_all), without blocking the async
+    /// runtime.
+    ///
+    /// At most `max_concurrent` repositories are checked out at once. As with
+    /// the blocking version, temporary failures are retried, and entries that
+    /// share a checkout are handled one after another.
+    pub async fn instantiate_all_async(
+        &self,
+        cache_dir: &Path,
+        max_concurrent: usize,
+        branches: CloneBranches,
+    ) -> BTreeMap<&RepoInfo, Result<PathBuf, LoadRepoError>> {
+        let mut by_checkout = BTreeMap::<_, Vec<_>>::new();
+        for (i, info) in self.sources().iter().enumerate() {
+            by_checkout
+                .entry(info.repo_path(cache_dir))
+                .or_default()
+                .push((i, info.clone()));
+        }
+
+        let permits = Arc::new(Semaphore::new(max_concurrent.max(1)));
+        let mut tasks = JoinSet::new();
+        for group in by_checkout.into_values() {
+            let permits = permits.clone();
+            let cache_dir = cache_dir.to_owned();
+            tasks.spawn(async move {
+                // the semaphore is never closed
+                let _permit = permits.acquire_owned().await.unwrap();
+                run_blocking(move || {
+                    group
+                        .into_iter()
+                        .map(|(i, info)| {
+                            (
+                                i,
+                                instantiate_with_retries(&info, &cache_dir, branches, &()),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .await
+            });
+        }
+
+        let mut results = BTreeMap::new();
+        while let Some(finished) = tasks.join_next().await {
+            for (i, result) in
+                finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+            {
+                results.insert(&self.sources()[i], result);
+            }
+        }
+        results
+    }
+}
+
+// run `f` on the blocking thread pool, passing on any panic
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo_info::tests::file_repo;
+
+    #[test]
+    fn instantiate_concurrently() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut sources = Vec::new();
+        for name in ["One", "Two", "Three"] {
+            let upstream = temp.path().join("upstream").join(name);
+            sources.push(file_repo(
+                &upstream,
+                &[("sources/config.yaml", "sources: []\n")],
+            ));
+        }
+        let set = SourceSet::new(sources);
+        let cache = temp.path().join("cache");
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let results = runtime.block_on(set.instantiate_all_async(&cache, 2, CloneBranches::Single));
+        assert_eq!(results.len(), 3);
+        for (info, result) in results {
+            assert_eq!(result.unwrap(), info.repo_path(&cache));
+        }
+        let single = runtime.block_on(set.sources()[0].instantiate_async(&cache));
+        assert!(single.unwrap().join("sources/config.yaml").exists());
+    }
+}
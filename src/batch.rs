@@ -0,0 +1,506 @@
+//! operations on every repository in a source set
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    error::{GitFail, LoadRepoError},
+    problem::CheckReport,
+    CloneBranches, Event, GitRev, Observer, Problem, ProblemKind, RepoInfo, SourceFile, SourceSet,
+};
+
+// how many times we try to check out a repository that can't be reached
+const MAX_ATTEMPTS: u32 = 3;
+
+/// The result of updating a single checkout in the cache
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckoutUpdate {
+    /// The checkout's location
+    pub path: PathBuf,
+    /// The commit before updating, or `None` if there was no checkout
+    pub before: Option<GitRev>,
+    /// The commit after updating
+    pub after: GitRev,
+}
+
+impl CheckoutUpdate {
+    /// `true` if the checkout was created or moved to a different commit
+    pub fn is_changed(&self) -> bool {
+        self.before.as_ref() != Some(&self.after)
+    }
+}
+
+impl SourceSet {
+    /// Check out every entry in this set, as with [`RepoInfo::instantiate`].
+    ///
+    /// A failure to check out one repository does not stop the others; the
+    /// result for each entry is returned. Failures that are likely to be
+    /// temporary (see [`LoadRepoError::is_retryable`]) are retried a few
+    /// times, with a short delay, before giving up.
+    ///
+    /// If `concurrent` is `true` repositories are checked out in parallel
+    /// (using the global rayon thread pool.) Entries that share a checkout
+    /// (such as projects in the same repository) are always handled one
+    /// after another; if they are pinned at different revs only the last one
+    /// will be checked out when this returns (see
+    /// [`rev_conflicts`](Self::rev_conflicts).)
+    ///
+    /// `branches` chooses which branches are fetched for repositories that
+    /// have to be cloned (see [`RepoInfo::instantiate_with`].)
+    pub fn instantiate_all(
+        &self,
+        cache_dir: &Path,
+        concurrent: bool,
+        branches: CloneBranches,
+    ) -> BTreeMap<&RepoInfo, Result<PathBuf, LoadRepoError>> {
+        self.instantiate_all_with_observer(cache_dir, concurrent, branches, &())
+    }
+
+    /// Check out every entry, as with [`instantiate_all`](Self::instantiate_all),
+    /// sending an [`Event`] to `observer` as each one starts and finishes,
+    /// and before each retry.
+    pub fn instantiate_all_with_observer(
+        &self,
+        cache_dir: &Path,
+        concurrent: bool,
+        branches: CloneBranches,
+        observer: &dyn Observer,
+    ) -> BTreeMap<&RepoInfo, Result<PathBuf, LoadRepoError>> {
+        for_each_checkout(self, cache_dir, concurrent, |info| {
+            instantiate_with_retries(info, cache_dir, branches, observer)
+        })
+    }
+
+    /// Check out every entry in this set and return the sources listed in
+    /// its config files, as with [`RepoInfo::get_sources_with_status`].
+    ///
+    /// Entries that would produce the same sources (the same project in the
+    /// same repository, pinned at the same rev) are only checked once, and
+    /// only the first of them is included in the result.
+    ///
+    /// See [`instantiate_all`](Self::instantiate_all) for how failures and
+    /// `concurrent` are handled.
+    pub fn get_all_sources(
+        &self,
+        cache_dir: &Path,
+        concurrent: bool,
+    ) -> BTreeMap<&RepoInfo, Result<Vec<SourceFile>, LoadRepoError>> {
+        let mut seen = HashSet::new();
+        let unique = self
+            .iter()
+            .filter(|info| seen.insert((info.key(), info.git_rev(), &info.config_files)));
+        for_each_checkout(unique, cache_dir, concurrent, |info| {
+            info.get_sources_with_status(cache_dir)
+        })
+    }
+
+    /// Check every entry in this set, returning any problems found.
+    ///
+    /// Each entry's url is checked with [`RepoInfo::check_url`]. If a
+    /// `cache_dir` is provided each entry with a valid url is also checked
+    /// out and verified with [`RepoInfo::validate`].
+    ///
+    /// See [`instantiate_all`](Self::instantiate_all) for how `concurrent` is
+    /// handled.
+    pub fn validate_all(&self, cache_dir: Option<&Path>, concurrent: bool) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut valid = Vec::new();
+        for info in self {
+            match info.check_url() {
+                Ok(()) => valid.push(info),
+                Err(e) => problems.push(Problem::for_entry(info, ProblemKind::InvalidUrl, e)),
+            }
+        }
+        if let Some(cache_dir) = cache_dir {
+            let results = for_each_checkout(valid, cache_dir, concurrent, |info| {
+                info.validate(cache_dir)
+            });
+            for (info, result) in results {
+                match result {
+                    Ok(validation) => {
+                        for (path, e) in validation.bad_configs {
+                            let message = format!("bad config '{}': {e}", path.display());
+                            problems.push(Problem::for_entry(
+                                info,
+                                ProblemKind::BadConfig,
+                                message,
+                            ));
+                        }
+                        for path in validation.missing_sources {
+                            let message = format!("missing source '{}'", path.display());
+                            problems.push(Problem::for_entry(
+                                info,
+                                ProblemKind::MissingSource,
+                                message,
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        let kind = match e {
+                            LoadRepoError::NoCommit { .. } => ProblemKind::MissingCommit,
+                            LoadRepoError::NoConfig => ProblemKind::NoConfig,
+                            _ => ProblemKind::CheckoutFailed,
+                        };
+                        problems.push(
+                            Problem::for_entry(info, kind, e.to_string()).with_code(e.code()),
+                        );
+                    }
+                }
+            }
+        }
+        problems.sort();
+        problems
+    }
+
+    /// Check out and [validate](Self::validate_all) every entry in this set,
+    /// summarizing the results.
+    pub fn check(&self, cache_dir: &Path, concurrent: bool) -> CheckReport {
+        let failures = self.validate_all(Some(cache_dir), concurrent);
+        let failed = failures
+            .iter()
+            .filter_map(|problem| problem.repo_url.as_deref())
+            .collect::<HashSet<_>>();
+        let passed = self
+            .iter()
+            .filter(|info| !failed.contains(info.repo_url.as_str()))
+            .count();
+        CheckReport {
+            checked: self.len(),
+            passed,
+            failures,
+        }
+    }
+
+    /// Fetch or clone every entry in this set and check it out at its rev,
+    /// reporting which checkouts changed.
+    ///
+    /// This is useful to warm a cache before it is needed. See
+    /// [`instantiate_all`](Self::instantiate_all) for how failures,
+    /// `concurrent` and `branches` are handled.
+    pub fn update_all(
+        &self,
+        cache_dir: &Path,
+        concurrent: bool,
+        branches: CloneBranches,
+    ) -> BTreeMap<&RepoInfo, Result<CheckoutUpdate, LoadRepoError>> {
+        for_each_checkout(self, cache_dir, concurrent, |info| {
+            let path = info.repo_path(cache_dir);
+            let before = if path.exists() {
+                crate::resolve_rev(&path, "HEAD")?
+            } else {
+                None
+            };
+            let (_, after) = info.checkout(cache_dir, branches)?;
+            Ok(CheckoutUpdate {
+                path,
+                before,
+                after,
+            })
+        })
+    }
+}
+
+/// Fetch the upstream default branch of every checkout in `cache_dir`,
+/// without a [`SourceSet`].
+///
+/// Checkouts are found at `{cache_dir}/{org}/{repo}`. Since there is no rev
+/// to pin them to their working trees are left alone; [`CheckoutUpdate`]
+/// describes the fetched upstream commit, which changes when there are new
+/// commits upstream.
+pub fn update_cache_dir(
+    cache_dir: &Path,
+    concurrent: bool,
+) -> Result<BTreeMap<PathBuf, Result<CheckoutUpdate, GitFail>>, std::io::Error> {
+    let checkouts = crate::cache::list_checkouts(cache_dir)?;
+    let update = |path: &PathBuf| {
+        let result = crate::resolve_rev(path, "FETCH_HEAD").and_then(|before| {
+            let after = crate::fetch_upstream_head(path)?;
+            Ok(CheckoutUpdate {
+                path: path.clone(),
+                before,
+                after,
+            })
+        });
+        (path.clone(), result)
+    };
+    Ok(if concurrent {
+        checkouts.par_iter().map(update).collect::<Vec<_>>()
+    } else {
+        checkouts.iter().map(update).collect()
+    }
+    .into_iter()
+    .collect())
+}
+
+/// Check out a single entry for [`instantiate_all_with_observer`], retrying
+/// failures that are likely to be temporary.
+///
+/// [`instantiate_all_with_observer`]: SourceSet::instantiate_all_with_observer
+pub(crate) fn instantiate_with_retries(
+    info: &RepoInfo,
+    cache_dir: &Path,
+    branches: CloneBranches,
+    observer: &dyn Observer,
+) -> Result<PathBuf, LoadRepoError> {
+    observer.on_event(&Event::RepoStarted {
+        repo_url: &info.repo_url,
+        family: info.family_name(),
+    });
+    let mut attempt = 1;
+    let result = loop {
+        match info.instantiate_with(cache_dir, branches) {
+            Err(e) if e.is_retryable() && attempt < MAX_ATTEMPTS => {
+                let delay = Duration::from_secs(2u64.pow(attempt));
+                log::debug!(
+                    "attempt {attempt} to check out {} failed, retrying in {}s: {e}",
+                    info.repo_url,
+                    delay.as_secs()
+                );
+                observer.on_event(&Event::Retry {
+                    repo_url: &info.repo_url,
+                    attempt,
+                    delay,
+                    error: e.to_string(),
+                });
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+    if let Err(e) = &result {
+        let problem = Problem::for_entry(info, ProblemKind::CheckoutFailed, e.to_string())
+            .with_code(e.code());
+        observer.on_event(&Event::Error(&problem));
+    }
+    observer.on_event(&Event::RepoCompleted {
+        repo_url: &info.repo_url,
+        family: info.family_name(),
+        ok: result.is_ok(),
+    });
+    result
+}
+
+// run `f` on each entry, grouped so that entries sharing a checkout
+// are never handled at the same time
+fn for_each_checkout<'a, T: Send>(
+    entries: impl IntoIterator<Item = &'a RepoInfo>,
+    cache_dir: &Path,
+    concurrent: bool,
+    f: impl Fn(&RepoInfo) -> T + Sync,
+) -> BTreeMap<&'a RepoInfo, T> {
+    let mut by_checkout = BTreeMap::<_, Vec<_>>::new();
+    for info in entries {
+        by_checkout
+            .entry(info.repo_path(cache_dir))
+            .or_default()
+            .push(info);
+    }
+    let run_group = |group: &Vec<&'a RepoInfo>| {
+        group
+            .iter()
+            .map(|info| (*info, f(info)))
+            .collect::<Vec<_>>()
+    };
+    if concurrent {
+        by_checkout
+            .par_iter()
+            .flat_map_iter(|(_, group)| run_group(group))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .collect()
+    } else {
+        by_checkout.values().flat_map(run_group).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repo_info::tests::{file_repo, file_url, make_repo},
+        SourceStatus,
+    };
+
+    #[test]
+    fn instantiate_all() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut sources = Vec::new();
+        for name in ["One", "Two"] {
+            let upstream = temp.path().join("upstream").join(name);
+            sources.push(file_repo(
+                &upstream,
+                &[("sources/config.yaml", "sources: []\n")],
+            ));
+        }
+        let missing = temp.path().join("upstream/Missing");
+        sources.push(
+            RepoInfo::new(
+                file_url(&missing),
+                "HEAD".into(),
+                vec!["config.yaml".into()],
+            )
+            .unwrap(),
+        );
+        let set = SourceSet::new(sources);
+        let cache = temp.path().join("cache");
+
+        for concurrent in [true, false] {
+            let results = set.instantiate_all(&cache, concurrent, CloneBranches::Single);
+            assert_eq!(results.len(), 3);
+            for (info, result) in results {
+                if info.repo_name() == "Missing" {
+                    assert!(result.is_err());
+                } else {
+                    assert_eq!(result.unwrap(), info.repo_path(&cache));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instantiate_all_events() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream/One");
+        make_repo(&upstream, &[("sources/config.yaml", "sources: []\n")]);
+        let good = file_url(&upstream);
+        let bad = file_url(&temp.path().join("upstream/Missing"));
+        let set = SourceSet::new(
+            [&good, &bad]
+                .into_iter()
+                .map(|url| {
+                    RepoInfo::new(url.clone(), "HEAD".into(), vec!["config.yaml".into()]).unwrap()
+                })
+                .collect(),
+        );
+
+        let events = std::sync::Mutex::new(Vec::new());
+        let observer = |event: &Event| {
+            let summary = match event {
+                Event::RepoStarted { repo_url, .. } => format!("start {repo_url}"),
+                Event::Error(problem) => format!("error {}", problem.repo_url.as_deref().unwrap()),
+                Event::RepoCompleted { repo_url, ok, .. } => format!("done {repo_url} {ok}"),
+                _ => String::new(),
+            };
+            events.lock().unwrap().push(summary);
+        };
+        set.instantiate_all_with_observer(
+            &temp.path().join("cache"),
+            false,
+            CloneBranches::Single,
+            &observer,
+        );
+        assert_eq!(
+            events.into_inner().unwrap(),
+            [
+                format!("start {bad}"),
+                format!("error {bad}"),
+                format!("done {bad} false"),
+                format!("start {good}"),
+                format!("done {good} true"),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_all_sources() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream/Family");
+        let info = file_repo(
+            &upstream,
+            &[
+                ("sources/config.yaml", "sources:\n  - Family.glyphs\n"),
+                ("sources/Family.glyphs", "{}"),
+            ],
+        );
+        // the same repo at the same rev, for another family
+        let mut duplicate = info.clone();
+        duplicate.token_env = Some("UNUSED".into());
+        let set = SourceSet::new(vec![info.clone(), duplicate]);
+        let cache = temp.path().join("cache");
+
+        let results = set.get_all_sources(&cache, true);
+        assert_eq!(results.len(), 1);
+        let sources = results[&info].as_ref().unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].status, SourceStatus::Found);
+    }
+
+    #[test]
+    fn validate_all() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream/Family");
+        let good = file_repo(
+            &upstream,
+            &[("sources/config.yaml", "sources:\n  - Missing.glyphs\n")],
+        );
+        let mut bad_rev = RepoInfo::new(
+            good.repo_url.clone(),
+            "0000000".into(),
+            vec!["config.yaml".into()],
+        )
+        .unwrap();
+        bad_rev.subdir = Some("other".into());
+        let bad_url = RepoInfo::new(
+            "http://github.com/org/font".into(),
+            "HEAD".into(),
+            vec!["config.yaml".into()],
+        )
+        .unwrap();
+        let set = SourceSet::new(vec![good, bad_rev, bad_url]);
+
+        let kinds = |problems: Vec<Problem>| problems.iter().map(|p| p.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds(set.validate_all(None, true)),
+            [ProblemKind::InvalidUrl]
+        );
+        let cache = temp.path().join("cache");
+        let mut found = kinds(set.validate_all(Some(&cache), false));
+        found.sort();
+        let report = set.check(&cache, true);
+        assert_eq!((report.checked, report.passed), (3, 0));
+        assert!(!report.is_ok());
+        assert_eq!(
+            found,
+            [
+                ProblemKind::MissingCommit,
+                ProblemKind::InvalidUrl,
+                ProblemKind::MissingSource
+            ]
+        );
+    }
+
+    #[test]
+    fn update_all() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream/Family");
+        let info = file_repo(&upstream, &[("sources/config.yaml", "sources: []\n")]);
+        let set = SourceSet::new(vec![info.clone()]);
+        let cache = temp.path().join("cache");
+
+        let first = set
+            .update_all(&cache, false, CloneBranches::Single)
+            .remove(&info)
+            .unwrap()
+            .unwrap();
+        assert!(first.before.is_none());
+        assert!(first.is_changed());
+        let second = set
+            .update_all(&cache, false, CloneBranches::Single)
+            .remove(&info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.before.as_ref(), Some(&first.after));
+        assert!(!second.is_changed());
+
+        let fetched = update_cache_dir(&cache, true).unwrap();
+        assert_eq!(fetched.len(), 1);
+        let update = fetched[&info.repo_path(&cache)].as_ref().unwrap();
+        assert_eq!(update.after, first.after);
+    }
+}
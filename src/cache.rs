@@ -0,0 +1,236 @@
+//! managing the directory of checkouts
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{GitRev, SourceSet};
+
+/// A checkout in the cache directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkout {
+    /// The checkout's location
+    pub path: PathBuf,
+    /// The total size of its files, in bytes
+    pub size: u64,
+    /// When the checkout was last checked out or fetched, if known
+    pub last_used: Option<SystemTime>,
+}
+
+impl Checkout {
+    fn new(path: PathBuf) -> Result<Self, std::io::Error> {
+        let size = dir_size(&path)?;
+        let git_dir = path.join(".git");
+        let last_used = ["HEAD", "FETCH_HEAD"]
+            .iter()
+            .filter_map(|name| git_dir.join(name).metadata().ok()?.modified().ok())
+            .max();
+        Ok(Checkout {
+            path,
+            size,
+            last_used,
+        })
+    }
+
+    /// Delete this checkout, and its parent directory if it is now empty
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        std::fs::remove_dir_all(&self.path)?;
+        if let Some(parent) = self.path.parent() {
+            // fails if there are other checkouts, which is fine
+            let _ = std::fs::remove_dir(parent);
+        }
+        Ok(())
+    }
+}
+
+/// The state of a checkout in the cache directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckoutStatus {
+    /// The checkout, including its size
+    pub checkout: Checkout,
+    /// The commit that is currently checked out, if it could be determined
+    pub head: Option<GitRev>,
+    /// `true` if the working tree has uncommitted changes
+    pub dirty: bool,
+    /// When the checkout was last fetched, if ever
+    pub last_fetched: Option<SystemTime>,
+    /// The revs that entries in the source set pin this repository at
+    pub pinned_revs: Vec<String>,
+    /// Whether `head` is one of `pinned_revs`, or `None` if no entry uses
+    /// this checkout
+    pub at_pinned_rev: Option<bool>,
+}
+
+/// Report the state of every checkout in `cache_dir`.
+///
+/// If `set` is provided each checkout is compared to the revs it pins.
+pub fn checkout_status(
+    cache_dir: &Path,
+    set: Option<&SourceSet>,
+) -> Result<Vec<CheckoutStatus>, std::io::Error> {
+    let mut pinned = BTreeMap::<_, Vec<_>>::new();
+    for info in set.into_iter().flatten() {
+        let revs = pinned.entry(info.repo_path(cache_dir)).or_default();
+        if !revs.contains(&info.git_rev()) {
+            revs.push(info.git_rev());
+        }
+    }
+    let mut result = Vec::new();
+    for path in list_checkouts(cache_dir)? {
+        let head = crate::resolve_rev(&path, "HEAD").ok().flatten();
+        // if we can't tell, assume the worst
+        let dirty = crate::run_git(&path, ["status", "--porcelain"])
+            .map(|out| !out.trim().is_empty())
+            .unwrap_or(true);
+        let last_fetched = path
+            .join(".git/FETCH_HEAD")
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .ok();
+        let pinned_revs = pinned.remove(&path).unwrap_or_default();
+        let at_pinned_rev = (!pinned_revs.is_empty()).then(|| {
+            pinned_revs
+                .iter()
+                .any(|rev| head.is_some() && crate::resolve_rev(&path, rev).ok().flatten() == head)
+        });
+        result.push(CheckoutStatus {
+            checkout: Checkout::new(path)?,
+            head,
+            dirty,
+            last_fetched,
+            pinned_revs: pinned_revs.into_iter().map(str::to_owned).collect(),
+            at_pinned_rev,
+        });
+    }
+    Ok(result)
+}
+
+/// Find checkouts in `cache_dir` that can be deleted.
+///
+/// A checkout is unused if `keep` is provided and no entry in it refers to
+/// the checkout, or if `max_age` is provided and the checkout has not been
+/// used for at least that long. The checkout of google/fonts used for
+/// discovery is never included.
+///
+/// Nothing is deleted; see [`Checkout::remove`].
+pub fn find_unused_checkouts(
+    cache_dir: &Path,
+    keep: Option<&SourceSet>,
+    max_age: Option<Duration>,
+) -> Result<Vec<Checkout>, std::io::Error> {
+    let mut referenced = keep
+        .map(|set| {
+            set.iter()
+                .map(|info| info.repo_path(cache_dir))
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+    let google_fonts = cache_dir.join("google/fonts");
+    let now = SystemTime::now();
+    let mut unused = Vec::new();
+    for path in list_checkouts(cache_dir)? {
+        if path == google_fonts || (keep.is_none() && max_age.is_none()) {
+            continue;
+        }
+        let checkout = Checkout::new(path)?;
+        let unreferenced = keep.is_some() && !referenced.remove(&checkout.path);
+        let too_old = max_age.is_some_and(|max_age| {
+            checkout
+                .last_used
+                .and_then(|time| now.duration_since(time).ok())
+                .is_none_or(|age| age >= max_age)
+        });
+        if unreferenced || too_old {
+            unused.push(checkout);
+        }
+    }
+    Ok(unused)
+}
+
+/// Return the path of every checkout in `cache_dir`, sorted.
+///
+/// Checkouts are at `{cache_dir}/{org}/{repo}`.
+pub(crate) fn list_checkouts(cache_dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut checkouts = Vec::new();
+    for org in std::fs::read_dir(cache_dir)? {
+        let org = org?.path();
+        if !org.is_dir() {
+            continue;
+        }
+        for repo in std::fs::read_dir(&org)? {
+            let repo = repo?.path();
+            if repo.join(".git").exists() {
+                checkouts.push(repo);
+            }
+        }
+    }
+    checkouts.sort();
+    Ok(checkouts)
+}
+
+// the total size of the files in this directory, not following symlinks
+fn dir_size(path: &Path) -> Result<u64, std::io::Error> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo_info::tests::file_repo;
+
+    #[test]
+    fn unused_checkouts() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = temp.path().join("cache");
+        let mut infos = Vec::new();
+        for name in ["Kept", "Unused"] {
+            let upstream = temp.path().join("upstream").join(name);
+            let info = file_repo(&upstream, &[("sources/config.yaml", "sources: []\n")]);
+            info.instantiate(&cache).unwrap();
+            infos.push(info);
+        }
+        let keep = SourceSet::new(vec![infos[0].clone()]);
+
+        let unused = find_unused_checkouts(&cache, Some(&keep), None).unwrap();
+        assert_eq!(unused.len(), 1);
+        assert_eq!(unused[0].path, infos[1].repo_path(&cache));
+        assert!(unused[0].size > 0);
+        assert!(unused[0].last_used.is_some());
+
+        let old = find_unused_checkouts(&cache, None, Some(Duration::from_secs(3600))).unwrap();
+        assert!(old.is_empty());
+        let old = find_unused_checkouts(&cache, None, Some(Duration::ZERO)).unwrap();
+        assert_eq!(old.len(), 2);
+        assert!(find_unused_checkouts(&cache, None, None)
+            .unwrap()
+            .is_empty());
+
+        let status = checkout_status(&cache, Some(&keep)).unwrap();
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].at_pinned_rev, Some(true));
+        assert_eq!(status[0].pinned_revs, ["HEAD"]);
+        assert!(!status[0].dirty);
+        assert_eq!(status[1].at_pinned_rev, None);
+        std::fs::write(infos[1].repo_path(&cache).join("new.txt"), "hi").unwrap();
+        assert!(checkout_status(&cache, None).unwrap()[1].dirty);
+
+        unused[0].remove().unwrap();
+        assert!(!infos[1].repo_path(&cache).exists());
+        assert_eq!(
+            list_checkouts(&cache).unwrap(),
+            [infos[0].repo_path(&cache)]
+        );
+    }
+}
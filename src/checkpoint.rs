@@ -0,0 +1,145 @@
+//! saving partial discovery results, so an interrupted run can be resumed
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{source_set::replace_file, GitRev, Problem, RepoInfo};
+
+const FILE_NAME: &str = "gfsources-checkpoint.json";
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The results of a discovery run so far
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Checkpoint {
+    /// The rev of google/fonts these results were found at
+    pub(crate) google_fonts_rev: Option<GitRev>,
+    /// The families that have been probed, whatever the outcome
+    pub(crate) done: BTreeSet<String>,
+    pub(crate) sources: Vec<RepoInfo>,
+    pub(crate) problems: Vec<Problem>,
+    #[serde(skip)]
+    last_saved: Option<Instant>,
+}
+
+impl Checkpoint {
+    /// The location of the checkpoint for a given cache directory
+    pub(crate) fn path(git_cache_dir: &Path) -> PathBuf {
+        git_cache_dir.join(FILE_NAME)
+    }
+
+    pub(crate) fn new(google_fonts_rev: Option<GitRev>) -> Self {
+        Checkpoint {
+            google_fonts_rev,
+            ..Default::default()
+        }
+    }
+
+    /// Load the checkpoint at `path`, if it was made at `google_fonts_rev`.
+    ///
+    /// A checkpoint from another rev of google/fonts is stale, and is ignored.
+    pub(crate) fn load(path: &Path, google_fonts_rev: Option<&GitRev>) -> Option<Self> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let checkpoint: Checkpoint = match serde_json::from_str(&contents) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                log::warn!("ignoring unreadable checkpoint '{}': {e}", path.display());
+                return None;
+            }
+        };
+        if checkpoint.google_fonts_rev.as_ref() != google_fonts_rev {
+            log::warn!("ignoring checkpoint from another rev of google/fonts");
+            return None;
+        }
+        Some(checkpoint)
+    }
+
+    /// Record that a family has been probed, with its result
+    pub(crate) fn finished(&mut self, family: &str, info: Option<&RepoInfo>) {
+        self.done.insert(family.to_owned());
+        self.sources.extend(info.cloned());
+    }
+
+    /// Record that probing a family failed
+    pub(crate) fn problem(&mut self, problem: &Problem) {
+        if let Some(name) = &problem.family_name {
+            self.done.insert(name.clone());
+        }
+        self.problems.push(problem.clone());
+    }
+
+    /// Save to `path` if enough time has passed since we last did
+    pub(crate) fn save_periodically(&mut self, path: &Path) {
+        let now = Instant::now();
+        if self
+            .last_saved
+            .is_some_and(|last| now.duration_since(last) < SAVE_INTERVAL)
+        {
+            return;
+        }
+        // don't save right away; short runs don't need a checkpoint
+        if self.last_saved.is_some() {
+            if let Err(e) = self.save(path) {
+                log::warn!("failed to write checkpoint '{}': {e}", path.display());
+            }
+        }
+        self.last_saved = Some(now);
+    }
+
+    /// Write the checkpoint to `path`.
+    ///
+    /// The file is replaced atomically, so a run that is interrupted while
+    /// saving leaves the previous checkpoint intact.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        let json = serde_json::to_string(self).expect("checkpoint is always serializable");
+        replace_file(path, json.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ProblemKind;
+
+    use super::*;
+
+    #[test]
+    fn save_and_resume() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = Checkpoint::path(tempdir.path());
+        let rev = "abc123".to_string();
+
+        let mut checkpoint = Checkpoint::new(Some(rev.clone()));
+        let info = RepoInfo::new(
+            "https://github.com/hello/world".into(),
+            "def456".into(),
+            vec!["sources/config.yaml".into()],
+        )
+        .unwrap();
+        checkpoint.finished("World", Some(&info));
+        checkpoint.finished("Nothing", None);
+        checkpoint.problem(&Problem {
+            family_name: Some("Broken".into()),
+            path: None,
+            repo_url: None,
+            kind: ProblemKind::ProbeFailed,
+            code: None,
+            message: "oops".into(),
+        });
+        checkpoint.save(&path).unwrap();
+
+        let loaded = Checkpoint::load(&path, Some(&rev)).unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert_eq!(
+            loaded.done.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["Broken", "Nothing", "World"]
+        );
+        assert_eq!(loaded.sources, [info]);
+        assert_eq!(loaded.problems.len(), 1);
+
+        // a checkpoint from a different rev of google/fonts is stale
+        assert!(Checkpoint::load(&path, Some(&"fff000".to_string())).is_none());
+        assert!(Checkpoint::load(&tempdir.path().join("nope.json"), Some(&rev)).is_none());
+    }
+}
@@ -1,6 +1,6 @@
 //! parsing google fonts config files
 
-use std::path::Path;
+use std::{path::Path, str::FromStr};
 
 use font_types::Tag;
 
@@ -60,7 +60,52 @@ fn true_() -> bool {
 impl Config {
     /// Parse and return a config.yaml file for the provided font source
     pub fn load(config_path: &Path) -> Result<Self, BadConfig> {
-        let contents = std::fs::read_to_string(config_path)?;
-        serde_yaml::from_str(&contents).map_err(BadConfig::Yaml)
+        let contents = std::fs::read_to_string(config_path).map_err(|source| BadConfig::Read {
+            path: config_path.to_owned(),
+            source,
+        })?;
+        log::debug!("loading config '{}'", config_path.display());
+        contents
+            .parse()
+            .map_err(|e: BadConfig| e.with_path(config_path))
+    }
+}
+
+impl FromStr for Config {
+    type Err = BadConfig;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_yaml::from_str(s).map_err(BadConfig::yaml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn errors_have_locations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("config.yaml");
+        std::fs::write(&path, "sources:\n  - a.glyphs\nbuildStatic: maybe\n").unwrap();
+        let err = Config::load(&path).unwrap_err();
+        let BadConfig::Yaml { line, column, .. } = &err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!((*line, *column), (Some(3), Some(14)));
+        assert_eq!(err.path(), Some(path.as_path()));
+        assert!(err.to_string().contains(&path.display().to_string()));
+
+        let missing = tempdir.path().join("nope.yaml");
+        let err = Config::load(&missing).unwrap_err();
+        assert_eq!(err.path(), Some(missing.as_path()));
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_never_panics(s in r"(sources:|familyName:|buildStatic:|- |\[|\]|\{|\}|[a-z.:&*!]|\s)*|\PC*") {
+            let _ = s.parse::<Config>();
+        }
     }
 }
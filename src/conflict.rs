@@ -0,0 +1,129 @@
+//! repositories pinned at more than one rev
+
+use std::collections::BTreeMap;
+
+use crate::RepoInfo;
+
+/// A repository that different entries in a [`SourceSet`] pin at different
+/// revs.
+///
+/// This happens when a repository contains multiple families (or multiple
+/// projects) that were updated in google/fonts at different times. Consumers
+/// that check out each repository once need to pick a rev, which may not be
+/// the right one for every family.
+///
+/// [`SourceSet`]: crate::SourceSet
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+pub struct RevConflict {
+    /// The repository's url
+    pub repo_url: String,
+    /// The rev to use when only one can be checked out.
+    ///
+    /// This is the rev used by the most entries; ties are broken in favour of
+    /// the entry that sorts first.
+    pub default_rev: String,
+    /// Each rev, and the entries that use it.
+    ///
+    /// Entries are identified by family name if known, and otherwise by their
+    /// subdir or repository name.
+    pub revs: BTreeMap<String, Vec<String>>,
+}
+
+/// Find all repositories pinned at more than one rev.
+///
+/// `sources` should be in the order they are serialized, so that the result
+/// is deterministic.
+pub(crate) fn find_rev_conflicts<'a>(
+    sources: impl IntoIterator<Item = &'a RepoInfo>,
+) -> Vec<RevConflict> {
+    // url -> (rev -> (first position, entries))
+    let mut by_url = BTreeMap::<&str, BTreeMap<&str, (usize, Vec<String>)>>::new();
+    for (i, info) in sources.into_iter().enumerate() {
+        let label = match (info.family_name(), info.subdir.as_deref()) {
+            (Some(family), _) => family.to_owned(),
+            (None, Some(subdir)) => subdir.display().to_string(),
+            (None, None) => info.repo_name().to_owned(),
+        };
+        by_url
+            .entry(info.repo_url.trim_end_matches('/'))
+            .or_default()
+            .entry(info.git_rev())
+            .or_insert_with(|| (i, Vec::new()))
+            .1
+            .push(label);
+    }
+
+    by_url
+        .into_iter()
+        .filter(|(_, revs)| revs.len() > 1)
+        .map(|(url, revs)| {
+            let default_rev = revs
+                .iter()
+                .max_by(|a, b| {
+                    let (a_pos, a_entries) = a.1;
+                    let (b_pos, b_entries) = b.1;
+                    a_entries.len().cmp(&b_entries.len()).then(b_pos.cmp(a_pos))
+                })
+                .map(|(rev, _)| rev.to_string())
+                .unwrap_or_default();
+            RevConflict {
+                repo_url: url.to_owned(),
+                default_rev,
+                revs: revs
+                    .into_iter()
+                    .map(|(rev, (_, entries))| (rev.to_owned(), entries))
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SourceSet;
+
+    fn info(url: &str, rev: &str, subdir: &str) -> RepoInfo {
+        let mut info = RepoInfo::new(url.into(), rev.into(), Vec::new()).unwrap();
+        info.subdir = Some(subdir.into());
+        info
+    }
+
+    #[test]
+    fn conflict_groups() {
+        let mut set = SourceSet::new(vec![
+            info("https://github.com/org/multi", "bbb", "Two"),
+            info("https://github.com/org/multi", "aaa", "One"),
+            info("https://github.com/org/multi", "bbb", "Three"),
+            info("https://github.com/org/single", "ccc", "Four"),
+        ]);
+        assert_eq!(set.rev_conflicts().len(), 1);
+        let conflict = &set.rev_conflicts()[0];
+        assert_eq!(conflict.repo_url, "https://github.com/org/multi");
+        assert_eq!(conflict.default_rev, "bbb");
+        assert_eq!(conflict.revs["aaa"], ["One"]);
+        assert_eq!(conflict.revs["bbb"], ["Three", "Two"]);
+        assert!(set.has_rev_conflict(&set.sources()[0]));
+        assert!(!set.has_rev_conflict(&set.sources()[3]));
+
+        // conflicts are serialized, and kept up to date
+        let json = set.to_json().unwrap();
+        assert!(json.contains("\"rev_conflicts\""));
+        let loaded = SourceSet::from_json(&json).unwrap();
+        assert_eq!(loaded.rev_conflicts(), set.rev_conflicts());
+        set.retain(|info| info.git_rev() != "aaa");
+        assert!(set.rev_conflicts().is_empty());
+    }
+
+    #[test]
+    fn ties_prefer_first_entry() {
+        let set = SourceSet::new(vec![
+            info("https://github.com/org/multi", "zzz", "B"),
+            info("https://github.com/org/multi", "yyy", "A"),
+        ]);
+        // sorted by subdir, 'A' comes first
+        assert_eq!(set.rev_conflicts()[0].default_rev, "yyy");
+    }
+}
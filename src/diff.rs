@@ -0,0 +1,155 @@
+//! comparing two source sets
+
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use crate::{RepoInfo, SourceSet};
+
+/// The differences between two [`SourceSet`]s.
+///
+/// Entries are matched by repository url and [subdir](RepoInfo::subdir).
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SourceSetDiff {
+    /// Entries that are only in the newer set
+    pub added: Vec<RepoInfo>,
+    /// Entries that are only in the older set
+    pub removed: Vec<RepoInfo>,
+    /// Entries in both sets whose rev or config files have changed
+    pub changed: Vec<ChangedSource>,
+}
+
+/// An entry that exists in both sets, but has changed
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChangedSource {
+    /// The entry in the older set
+    pub old: RepoInfo,
+    /// The entry in the newer set
+    pub new: RepoInfo,
+}
+
+impl SourceSetDiff {
+    /// Compute the differences between `older` and `newer`.
+    pub fn new(older: &SourceSet, newer: &SourceSet) -> Self {
+        let old_entries = by_key(older);
+        let mut new_entries = by_key(newer);
+        let mut result = SourceSetDiff::default();
+        for (key, old) in old_entries {
+            match new_entries.remove(&key) {
+                None => result.removed.push(old.clone()),
+                Some(new)
+                    if new.git_rev() != old.git_rev() || new.config_files != old.config_files =>
+                {
+                    result.changed.push(ChangedSource {
+                        old: old.clone(),
+                        new: new.clone(),
+                    })
+                }
+                Some(_) => (),
+            }
+        }
+        result.added = new_entries.into_values().cloned().collect();
+        result
+    }
+
+    /// `true` if the two sets had the same entries
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl ChangedSource {
+    /// `true` if the pinned rev changed
+    pub fn rev_changed(&self) -> bool {
+        self.old.git_rev() != self.new.git_rev()
+    }
+
+    /// `true` if the list of config files changed
+    pub fn config_changed(&self) -> bool {
+        self.old.config_files != self.new.config_files
+    }
+}
+
+/// One line per entry: `+` for added, `-` for removed and `~` for changed.
+impl Display for SourceSetDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for info in &self.added {
+            writeln!(f, "+ {} {}", entry_name(info), info.git_rev())?;
+        }
+        for info in &self.removed {
+            writeln!(f, "- {} {}", entry_name(info), info.git_rev())?;
+        }
+        for change in &self.changed {
+            write!(f, "~ {}", entry_name(&change.new))?;
+            if change.rev_changed() {
+                write!(f, " {} -> {}", change.old.git_rev(), change.new.git_rev())?;
+            }
+            if change.config_changed() {
+                let configs = |info: &RepoInfo| {
+                    info.config_files
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                write!(
+                    f,
+                    " configs [{}] -> [{}]",
+                    configs(&change.old),
+                    configs(&change.new)
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// the url, and the subdir if there is one
+fn entry_name(info: &RepoInfo) -> String {
+    match &info.subdir {
+        Some(subdir) => format!("{} ({})", info.repo_url, subdir.display()),
+        None => info.repo_url.clone(),
+    }
+}
+
+fn by_key(set: &SourceSet) -> BTreeMap<(&str, Option<&Path>), &RepoInfo> {
+    set.iter().map(|info| (info.key(), info)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(url: &str, rev: &str) -> RepoInfo {
+        RepoInfo::new(url.into(), rev.into(), vec!["config.yaml".into()]).unwrap()
+    }
+
+    #[test]
+    fn simple_diff() {
+        let older = SourceSet::new(vec![
+            info("https://github.com/org/removed", "aaa"),
+            info("https://github.com/org/bumped", "bbb"),
+            info("https://github.com/org/same", "ccc"),
+        ]);
+        let newer = SourceSet::new(vec![
+            info("https://github.com/org/same", "ccc"),
+            info("https://github.com/org/bumped", "ddd"),
+            info("https://github.com/org/added", "eee"),
+        ]);
+        let diff = newer.diff(&older);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].repo_name(), "added");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].repo_name(), "removed");
+        assert_eq!(diff.changed.len(), 1);
+        assert!(diff.changed[0].rev_changed());
+        assert!(!diff.changed[0].config_changed());
+        assert_eq!(diff.changed[0].new.git_rev(), "ddd");
+        assert!(older.diff(&older).is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "+ https://github.com/org/added eee\n\
+             - https://github.com/org/removed aaa\n\
+             ~ https://github.com/org/bumped bbb -> ddd\n"
+        );
+    }
+}
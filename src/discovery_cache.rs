@@ -0,0 +1,78 @@
+//! reusing the results of a previous discovery run when google/fonts has not
+//! changed
+
+use std::path::{Path, PathBuf};
+
+use crate::{source_set::replace_file, DiscoveryReport, GitRev, SourceSet};
+
+const FILE_NAME: &str = "gfsources-discovery.json";
+
+/// The output of the most recent discovery run, and what it depended on
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct DiscoveryCache {
+    /// The rev of google/fonts the results were found at
+    google_fonts_rev: GitRev,
+    /// The [key](crate::DiscoveryOptions::cache_key) of the options used
+    options: String,
+    /// The set, as saved by [`SourceSet::to_json`]
+    set: serde_json::Value,
+    report: DiscoveryReport,
+}
+
+impl DiscoveryCache {
+    /// The location of the cached results for a given cache directory
+    pub(crate) fn path(git_cache_dir: &Path) -> PathBuf {
+        git_cache_dir.join(FILE_NAME)
+    }
+
+    /// Load the results saved at `path`, if they were found at
+    /// `google_fonts_rev` with options that have this `key`.
+    pub(crate) fn load(
+        path: &Path,
+        google_fonts_rev: &GitRev,
+        key: &str,
+    ) -> Option<(SourceSet, DiscoveryReport)> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let cache: DiscoveryCache = match serde_json::from_str(&contents) {
+            Ok(cache) => cache,
+            Err(e) => {
+                log::warn!(
+                    "ignoring unreadable discovery cache '{}': {e}",
+                    path.display()
+                );
+                return None;
+            }
+        };
+        if &cache.google_fonts_rev != google_fonts_rev || cache.options != key {
+            log::debug!("discovery cache is from another rev of google/fonts or other options");
+            return None;
+        }
+        match SourceSet::from_value(cache.set) {
+            Ok(set) => Some((set, cache.report)),
+            Err(e) => {
+                log::warn!(
+                    "ignoring unreadable discovery cache '{}': {e}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Save the results of a run to `path`.
+    pub(crate) fn save(
+        path: &Path,
+        google_fonts_rev: &GitRev,
+        key: &str,
+        set: &SourceSet,
+        report: &DiscoveryReport,
+    ) -> Result<(), std::io::Error> {
+        let cache = DiscoveryCache {
+            google_fonts_rev: google_fonts_rev.clone(),
+            options: key.to_owned(),
+            set: serde_json::to_value(set)?,
+            report: report.clone(),
+        };
+        replace_file(path, &serde_json::to_vec(&cache)?)
+    }
+}
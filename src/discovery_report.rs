@@ -0,0 +1,341 @@
+//! a record of why fonts were left out during discovery
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{GitRev, Metrics, Problem, ProblemKind, SourceSet, SourceSetDiff};
+
+/// What happened to the fonts in google/fonts that are not in a discovered
+/// [`SourceSet`], along with anything unusual about those that are.
+///
+/// This is returned by [`discover_sources_with_report`], and can be used to
+/// answer questions like "why is family X missing?" without digging through
+/// logs.
+///
+/// [`SourceSet`]: crate::SourceSet
+/// [`discover_sources_with_report`]: crate::discover_sources_with_report
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiscoveryReport {
+    /// The commit of google/fonts that discovery was run against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub google_fonts_rev: Option<GitRev>,
+    /// The number of fonts in google/fonts that were considered
+    pub families_scanned: usize,
+    /// One entry for each font that was skipped or is otherwise notable
+    pub entries: Vec<ReportEntry>,
+    /// How long each part of discovery took
+    #[serde(default)]
+    pub metrics: Metrics,
+}
+
+/// A font that was skipped during discovery, or something unusual about one
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ReportEntry {
+    /// The name of the family, if its metadata could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// The font's directory in google/fonts, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// The repository url, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo_url: Option<String>,
+    /// The point in discovery at which this happened
+    pub stage: DiscoveryStage,
+    /// Why the font was left out, or `None` if it was included and this entry
+    /// is only a note
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<SkipReason>,
+    /// A description of what happened
+    pub reason: String,
+}
+
+/// Why a font in google/fonts is not in a discovered [`SourceSet`]
+///
+/// [`SourceSet`]: crate::SourceSet
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum SkipReason {
+    /// The font's METADATA.pb has no repository url
+    NoRepoUrl,
+    /// Another font uses the same repository (and project directory)
+    DuplicateRepo,
+    /// The font was not selected by the [`DiscoveryOptions`]
+    ///
+    /// [`DiscoveryOptions`]: crate::DiscoveryOptions
+    Excluded,
+    /// The font's METADATA.pb could not be read or parsed
+    BadMetadata,
+    /// The repository url is not in a format we understand
+    UnfamiliarUrl,
+    /// The current commit of the repository could not be determined
+    NoCommit,
+    /// The repository has no config file
+    NoConfig,
+    /// Looking for config files in the repository failed
+    ProbeFailed,
+    /// The repository is not cached, and discovery was offline
+    Unreachable,
+}
+
+/// The number of fonts skipped for each [`SkipReason`], and the reason each
+/// family was skipped.
+///
+/// This is saved with a discovered [`SourceSet`], so that it can be tracked
+/// over time.
+///
+/// [`SourceSet`]: crate::SourceSet
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+pub struct SkipSummary {
+    /// The number of fonts skipped for each reason
+    pub counts: BTreeMap<SkipReason, usize>,
+    /// The reason each family was skipped, by family name
+    pub families: BTreeMap<String, SkipReason>,
+}
+
+impl SkipReason {
+    /// The name of this reason, as it is serialized
+    pub fn name(self) -> &'static str {
+        match self {
+            SkipReason::NoRepoUrl => "no_repo_url",
+            SkipReason::DuplicateRepo => "duplicate_repo",
+            SkipReason::Excluded => "excluded",
+            SkipReason::BadMetadata => "bad_metadata",
+            SkipReason::UnfamiliarUrl => "unfamiliar_url",
+            SkipReason::NoCommit => "no_commit",
+            SkipReason::NoConfig => "no_config",
+            SkipReason::ProbeFailed => "probe_failed",
+            SkipReason::Unreachable => "unreachable",
+        }
+    }
+}
+
+impl From<ProblemKind> for SkipReason {
+    fn from(kind: ProblemKind) -> Self {
+        match kind {
+            ProblemKind::UnfamiliarUrl | ProblemKind::InvalidUrl => SkipReason::UnfamiliarUrl,
+            ProblemKind::MissingCommit => SkipReason::NoCommit,
+            ProblemKind::BadMetadata => SkipReason::BadMetadata,
+            ProblemKind::NoConfig => SkipReason::NoConfig,
+            ProblemKind::Unreachable => SkipReason::Unreachable,
+            ProblemKind::ProbeFailed
+            | ProblemKind::CheckoutFailed
+            | ProblemKind::BadConfig
+            | ProblemKind::MissingSource => SkipReason::ProbeFailed,
+        }
+    }
+}
+
+/// The steps of discovery
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum DiscoveryStage {
+    /// Reading the font's METADATA.pb in google/fonts
+    Metadata,
+    /// Choosing which fonts to check, using the [`DiscoveryOptions`]
+    ///
+    /// [`DiscoveryOptions`]: crate::DiscoveryOptions
+    Selection,
+    /// Looking for config files in the font's repository
+    Probe,
+}
+
+impl DiscoveryReport {
+    /// The entries for a family, explaining why it is missing from the set
+    pub fn for_family<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a ReportEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.family.as_deref() == Some(name))
+    }
+
+    /// The number of fonts skipped for each reason, and why each family was
+    /// skipped
+    pub fn skipped(&self) -> SkipSummary {
+        let mut summary = SkipSummary::default();
+        for entry in &self.entries {
+            let Some(skip_reason) = entry.skip_reason else {
+                continue;
+            };
+            *summary.counts.entry(skip_reason).or_default() += 1;
+            if let Some(family) = &entry.family {
+                summary
+                    .families
+                    .entry(family.clone())
+                    .or_insert(skip_reason);
+            }
+        }
+        summary
+    }
+
+    /// A few lines describing the outcome of discovery, for printing at the
+    /// end of a run.
+    ///
+    /// `set` is the set that was discovered; if `diff` is provided it should
+    /// be the changes since a previous run.
+    pub fn summary(&self, set: &SourceSet, diff: Option<&SourceSetDiff>) -> String {
+        let skipped = self.skipped();
+        let mut out = format!(
+            "discovery finished in {:.1}s: {} families scanned, {} sources, {} skipped\n",
+            self.metrics.total.as_secs_f64(),
+            self.families_scanned,
+            set.len(),
+            skipped.counts.values().sum::<usize>(),
+        );
+        if let Some(rev) = &self.google_fonts_rev {
+            out.push_str(&format!("  google/fonts at {rev}\n"));
+        }
+        if !skipped.counts.is_empty() {
+            let mut counts = skipped.counts.iter().collect::<Vec<_>>();
+            counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let counts = counts
+                .into_iter()
+                .map(|(reason, n)| format!("{n} {}", reason.name()))
+                .collect::<Vec<_>>();
+            out.push_str(&format!("  skipped: {}\n", counts.join(", ")));
+        }
+        if let Some(diff) = diff {
+            out.push_str(&format!(
+                "  since the previous run: {} added, {} removed, {} changed\n",
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len()
+            ));
+        }
+        out
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        family: Option<&str>,
+        repo_url: Option<&str>,
+        stage: DiscoveryStage,
+        skip_reason: Option<SkipReason>,
+        reason: impl Into<String>,
+    ) {
+        self.entries.push(ReportEntry {
+            family: family.map(str::to_owned),
+            path: None,
+            repo_url: repo_url.map(str::to_owned),
+            stage,
+            skip_reason,
+            reason: reason.into(),
+        });
+    }
+
+    pub(crate) fn push_problem(&mut self, problem: &Problem) {
+        let stage = match problem.kind {
+            ProblemKind::BadMetadata => DiscoveryStage::Metadata,
+            _ => DiscoveryStage::Probe,
+        };
+        self.entries.push(ReportEntry {
+            family: problem.family_name.clone(),
+            path: problem.path.clone(),
+            repo_url: problem.repo_url.clone(),
+            stage,
+            skip_reason: Some(problem.kind.into()),
+            reason: problem.message.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_for_family() {
+        let mut report = DiscoveryReport::default();
+        report.push(
+            Some("Foo"),
+            None,
+            DiscoveryStage::Metadata,
+            Some(SkipReason::NoRepoUrl),
+            "no repository url",
+        );
+        report.push(
+            Some("Qux"),
+            Some("https://example.com/qux"),
+            DiscoveryStage::Probe,
+            None,
+            "the repository is on an unfamiliar host",
+        );
+        report.push_problem(&Problem {
+            family_name: Some("Bar".into()),
+            path: None,
+            repo_url: Some("https://github.com/org/bar".into()),
+            kind: ProblemKind::ProbeFailed,
+            code: None,
+            message: "clone failed".into(),
+        });
+        let bar = report.for_family("Bar").collect::<Vec<_>>();
+        assert_eq!(bar.len(), 1);
+        assert_eq!(bar[0].stage, DiscoveryStage::Probe);
+        assert_eq!(bar[0].reason, "clone failed");
+        assert_eq!(report.for_family("Foo").count(), 1);
+        assert_eq!(report.for_family("Baz").count(), 0);
+
+        let skipped = report.skipped();
+        assert_eq!(
+            skipped.counts,
+            [(SkipReason::NoRepoUrl, 1), (SkipReason::ProbeFailed, 1)].into()
+        );
+        assert_eq!(skipped.families["Bar"], SkipReason::ProbeFailed);
+        assert!(!skipped.families.contains_key("Qux"));
+        assert_eq!(
+            serde_json::to_value(&skipped).unwrap()["families"]["Foo"],
+            "no_repo_url"
+        );
+    }
+
+    #[test]
+    fn run_summary() {
+        let mut report = DiscoveryReport {
+            google_fonts_rev: Some("0123abcd".into()),
+            families_scanned: 4,
+            ..Default::default()
+        };
+        for (family, reason) in [
+            ("A", SkipReason::NoConfig),
+            ("B", SkipReason::NoConfig),
+            ("C", SkipReason::NoRepoUrl),
+        ] {
+            report.push(Some(family), None, DiscoveryStage::Probe, Some(reason), "");
+        }
+        report.metrics.total = std::time::Duration::from_millis(2500);
+        let info = crate::RepoInfo::new(
+            "https://github.com/org/d".into(),
+            "abc".into(),
+            vec!["config.yaml".into()],
+        )
+        .unwrap();
+        let set = SourceSet::new(vec![info]);
+        let diff = set.diff(&SourceSet::new(Vec::new()));
+        assert_eq!(
+            report.summary(&set, Some(&diff)),
+            "discovery finished in 2.5s: 4 families scanned, 1 sources, 3 skipped\n  \
+             google/fonts at 0123abcd\n  \
+             skipped: 2 no_config, 1 no_repo_url\n  \
+             since the previous run: 1 added, 0 removed, 0 changed\n"
+        );
+        for reason in [SkipReason::NoRepoUrl, SkipReason::ProbeFailed] {
+            assert_eq!(serde_json::to_value(reason).unwrap(), reason.name());
+        }
+    }
+}
@@ -1,25 +1,72 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-use crate::metadata::BadMetadata;
+use crate::{credentials::redact, metadata::BadMetadata, Version};
 
 //use protobuf::text_format::ParseError;
 
-/// A little helper trait for reporting results we can't recover from
-pub(crate) trait UnwrapOrDie<T, E> {
-    // print_msg should be a closure that eprints a message before termination
-    fn unwrap_or_die(self, print_msg: impl FnOnce(E)) -> T;
+/// A little helper trait for turning errors into a [`CliError`]
+pub(crate) trait OrFail<T> {
+    // 'what' describes what we were trying to do, e.g. "failed to load 'x'"
+    fn or_fail(self, what: impl FnOnce() -> String) -> Result<T, CliError>;
 }
 
-impl<T, E: Display> UnwrapOrDie<T, E> for Result<T, E> {
-    fn unwrap_or_die(self, print_msg: impl FnOnce(E)) -> T {
+impl<T, E: Display> OrFail<T> for Result<T, E> {
+    fn or_fail(self, what: impl FnOnce() -> String) -> Result<T, CliError> {
+        self.map_err(|e| CliError::Failed(format!("{}: '{e}'", what())))
+    }
+}
+
+/// Reasons a run of the command line tool did not succeed
+#[derive(Debug, thiserror::Error)]
+#[doc(hidden)] // only intended to be used from our binary
+pub enum CliError {
+    /// The tool could not do what was asked
+    #[error("{0}")]
+    Failed(String),
+    /// Some of the things we were asked to check or update failed.
+    ///
+    /// The failures have already been reported; this is a summary.
+    #[error("{0}")]
+    Incomplete(String),
+    /// Discovery completed with warnings, and '--strict' was passed
+    #[error("{}", .0.iter().map(|w| format!("warning: {w}")).collect::<Vec<_>>().join("\n"))]
+    Warnings(Vec<String>),
+}
+
+impl CliError {
+    /// The code the process should exit with
+    pub fn exit_code(&self) -> i32 {
         match self {
-            Ok(val) => val,
-            Err(e) => {
-                print_msg(e);
-                std::process::exit(1)
-            }
+            CliError::Failed(_) | CliError::Incomplete(_) => crate::EXIT_FAILED,
+            CliError::Warnings(_) => crate::EXIT_PROBLEMS,
         }
     }
+
+    /// This error as a JSON object, for tools that parse our output.
+    ///
+    /// This has the fields `error` (one of `failed`, `incomplete` or
+    /// `warnings`), `messages` and `exit_code`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let (kind, messages) = match self {
+            CliError::Failed(message) => ("failed", std::slice::from_ref(message)),
+            CliError::Incomplete(message) => ("incomplete", std::slice::from_ref(message)),
+            CliError::Warnings(warnings) => ("warnings", warnings.as_slice()),
+        };
+        serde_json::json!({
+            "error": kind,
+            "messages": messages,
+            "exit_code": self.exit_code(),
+        })
+    }
+}
+
+impl From<Error> for CliError {
+    fn from(error: Error) -> Self {
+        CliError::Failed(error.to_string())
+    }
 }
 
 /// Errors that occur while trying to find sources
@@ -31,17 +78,93 @@ pub enum Error {
     /// an error with reading the google/fonts repo
     #[error(transparent)]
     Git(#[from] GitFail),
+    /// The google/fonts repo is not checked out, and we are offline
+    #[error("no checkout of google/fonts at '{0}', and we are offline")]
+    NotCached(PathBuf),
+    /// A directory in the google/fonts checkout could not be read
+    #[error("failed to read directory '{}': '{source}'", path.display())]
+    ReadDir {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    /// A stable code identifying the kind of error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Io(_) => ErrorCode::Io,
+            Error::Git(_) => ErrorCode::Git,
+            Error::NotCached(_) => ErrorCode::NotCached,
+            Error::ReadDir { .. } => ErrorCode::ReadDir,
+        }
+    }
 }
 
 /// Errors that occur while trying to load a config file
 #[derive(Debug, thiserror::Error)]
 pub enum BadConfig {
     /// The file could not be read
-    #[error(transparent)]
-    Read(#[from] std::io::Error),
+    #[error("failed to read '{}': '{source}'", path.display())]
+    #[non_exhaustive]
+    Read {
+        /// The config file
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
     /// The yaml could not be parsed
-    #[error(transparent)]
-    Yaml(serde_yaml::Error),
+    #[error("invalid yaml{}: '{source}'", path.as_ref().map(|p| format!(" in '{}'", p.display())).unwrap_or_default())]
+    #[non_exhaustive]
+    Yaml {
+        /// The config file, if the yaml was loaded from a file
+        path: Option<PathBuf>,
+        /// The 1-based line of the error, if known
+        line: Option<usize>,
+        /// The 1-based column of the error, if known
+        column: Option<usize>,
+        #[source]
+        source: serde_yaml::Error,
+    },
+}
+
+impl BadConfig {
+    pub(crate) fn yaml(source: serde_yaml::Error) -> Self {
+        let location = source.location();
+        BadConfig::Yaml {
+            path: None,
+            line: location.as_ref().map(|loc| loc.line()),
+            column: location.as_ref().map(|loc| loc.column()),
+            source,
+        }
+    }
+
+    /// Record the file this config was loaded from
+    pub(crate) fn with_path(self, config_path: &Path) -> Self {
+        match self {
+            BadConfig::Yaml {
+                line,
+                column,
+                source,
+                ..
+            } => BadConfig::Yaml {
+                path: Some(config_path.to_owned()),
+                line,
+                column,
+                source,
+            },
+            other => other,
+        }
+    }
+
+    /// The config file involved, if known
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            BadConfig::Read { path, .. } => Some(path),
+            BadConfig::Yaml { path, .. } => path.as_deref(),
+        }
+    }
 }
 
 /// Things that go wrong when trying to clone and read a font repo
@@ -53,12 +176,15 @@ pub enum LoadRepoError {
         #[source]
         std::io::Error,
     ),
+    /// A git command failed
     #[error("git failed: '{0}'")]
-    GitFail(
-        #[source]
-        #[from]
-        GitFail,
-    ),
+    GitFail(#[source] GitFail),
+    /// A git command failed because the repository could not be reached.
+    ///
+    /// This is a network or transport problem (as opposed to a problem with
+    /// the repository itself), so trying again may succeed.
+    #[error("network error: '{0}'")]
+    Network(#[source] GitFail),
     /// The expected commit could not be found
     #[error("could not find commit '{sha}'")]
     NoCommit { sha: String },
@@ -72,6 +198,196 @@ pub enum LoadRepoError {
         #[from]
         BadConfig,
     ),
+    /// The repository's host does not support downloading single files
+    #[error("files cannot be downloaded from '{0}'")]
+    UnsupportedHost(String),
+    /// An http request failed
+    #[error("http request failed: '{0}'")]
+    Http(#[source] Box<ureq::Error>),
+    /// An http request returned something other than the expected file
+    #[error("unexpected response from '{url}': '{reason}'")]
+    UnexpectedResponse { url: String, reason: String },
+}
+
+impl From<GitFail> for LoadRepoError {
+    fn from(error: GitFail) -> Self {
+        if error.is_network_error() {
+            LoadRepoError::Network(error)
+        } else {
+            LoadRepoError::GitFail(error)
+        }
+    }
+}
+
+impl LoadRepoError {
+    /// `true` if this failure is likely to be temporary, such as a network
+    /// error or a server error, so that trying again may succeed.
+    ///
+    /// Other errors (such as a missing commit or a bad config file) are
+    /// problems with the repository, and will happen again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LoadRepoError::Network(_) => true,
+            LoadRepoError::Http(e) => match e.as_ref() {
+                ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+                ureq::Error::Transport(_) => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// A stable code identifying the kind of error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            LoadRepoError::Io(_) => ErrorCode::Io,
+            LoadRepoError::GitFail(_) => ErrorCode::Git,
+            LoadRepoError::Network(_) => ErrorCode::Network,
+            LoadRepoError::NoCommit { .. } => ErrorCode::NoCommit,
+            LoadRepoError::NoConfig => ErrorCode::NoConfig,
+            LoadRepoError::BadConfig(_) => ErrorCode::BadConfig,
+            LoadRepoError::UnsupportedHost(_) => ErrorCode::UnknownHost,
+            LoadRepoError::Http(_) => ErrorCode::Http,
+            LoadRepoError::UnexpectedResponse { .. } => ErrorCode::UnexpectedResponse,
+        }
+    }
+}
+
+/// A stable, machine-readable identifier for a kind of failure.
+///
+/// Unlike error messages, these codes will not change between releases, so
+/// automation can rely on them. They are serialized (and displayed) as
+/// strings like `E_NO_COMMIT`.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// A local file or directory could not be read or written
+    #[serde(rename = "E_IO")]
+    Io,
+    /// A git command failed
+    #[serde(rename = "E_GIT")]
+    Git,
+    /// A repository could not be reached because of a network problem
+    #[serde(rename = "E_NETWORK")]
+    Network,
+    /// A repository is not cached, and we are offline
+    #[serde(rename = "E_NOT_CACHED")]
+    NotCached,
+    /// A directory in google/fonts could not be read
+    #[serde(rename = "E_READ_DIR")]
+    ReadDir,
+    /// The expected commit could not be found
+    #[serde(rename = "E_NO_COMMIT")]
+    NoCommit,
+    /// The repository has no config file
+    #[serde(rename = "E_NO_CONFIG")]
+    NoConfig,
+    /// A config file could not be read or parsed
+    #[serde(rename = "E_BAD_CONFIG")]
+    BadConfig,
+    /// The repository's host or url format is not one we understand
+    #[serde(rename = "E_UNKNOWN_HOST")]
+    UnknownHost,
+    /// An http request failed
+    #[serde(rename = "E_HTTP")]
+    Http,
+    /// An http request returned something other than the expected file
+    #[serde(rename = "E_UNEXPECTED_RESPONSE")]
+    UnexpectedResponse,
+    /// A font's METADATA.pb could not be read or parsed
+    #[serde(rename = "E_BAD_METADATA")]
+    BadMetadata,
+    /// A repository url is malformed or unsafe to use
+    #[serde(rename = "E_INVALID_URL")]
+    InvalidUrl,
+    /// A config file lists a source that does not exist
+    #[serde(rename = "E_MISSING_SOURCE")]
+    MissingSource,
+}
+
+impl ErrorCode {
+    /// The code as a string, e.g. `E_NO_COMMIT`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Io => "E_IO",
+            ErrorCode::Git => "E_GIT",
+            ErrorCode::Network => "E_NETWORK",
+            ErrorCode::NotCached => "E_NOT_CACHED",
+            ErrorCode::ReadDir => "E_READ_DIR",
+            ErrorCode::NoCommit => "E_NO_COMMIT",
+            ErrorCode::NoConfig => "E_NO_CONFIG",
+            ErrorCode::BadConfig => "E_BAD_CONFIG",
+            ErrorCode::UnknownHost => "E_UNKNOWN_HOST",
+            ErrorCode::Http => "E_HTTP",
+            ErrorCode::UnexpectedResponse => "E_UNEXPECTED_RESPONSE",
+            ErrorCode::BadMetadata => "E_BAD_METADATA",
+            ErrorCode::InvalidUrl => "E_INVALID_URL",
+            ErrorCode::MissingSource => "E_MISSING_SOURCE",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Things that go wrong when loading or saving a [`SourceSet`](crate::SourceSet)
+#[derive(Debug, thiserror::Error)]
+pub enum SourceSetError {
+    /// The file could not be read or written
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The file is not valid json, or does not match the expected format
+    #[error("invalid source set: '{0}'")]
+    Json(#[from] serde_json::Error),
+    /// The set could not be serialized as yaml
+    #[error("failed to write yaml: '{0}'")]
+    Yaml(#[from] serde_yaml::Error),
+    /// The set could not be serialized as toml
+    #[error("failed to write toml: '{0}'")]
+    Toml(#[from] toml::ser::Error),
+    /// The file is missing the 'version' field
+    #[error("missing 'version' field; this does not look like a source set")]
+    NoVersion,
+    /// The file was written in a format this version of the crate cannot read
+    #[error("unsupported format version {found}; this crate reads version {}.x (you may need to update google-fonts-sources)", supported.major)]
+    UnsupportedVersion { found: Version, supported: Version },
+}
+
+/// A repository could not be locked
+#[derive(Debug, thiserror::Error)]
+#[error("failed to lock '{repo_url}': '{error}'")]
+pub struct LockError {
+    /// The url of the repository that failed
+    pub repo_url: String,
+    /// What went wrong
+    #[source]
+    pub error: LoadRepoError,
+}
+
+/// Two sets being merged contain different entries for the same project
+#[derive(Debug, thiserror::Error)]
+#[error("conflicting entries for '{repo_url}': rev '{left_rev}' and rev '{right_rev}'")]
+pub struct MergeConflict {
+    /// The url of the repository with conflicting entries
+    pub repo_url: String,
+    /// The rev in the left (`self`) set
+    pub left_rev: String,
+    /// The rev in the right (`other`) set
+    pub right_rev: String,
 }
 
 /// Things that go wrong when trying to run a git command
@@ -84,11 +400,114 @@ pub enum GitFail {
         #[source]
         std::io::Error,
     ),
-    /// The git command returns a non-zero status
-    #[error("command failed: in '{path}': '{stderr}'")]
-    GitError { path: PathBuf, stderr: String },
+    /// The git command returns a non-zero status, or unexpected output
+    #[error("command failed: '{}'{} in '{path}': '{stderr}'", command_line(args), exit_status(*status))]
+    #[non_exhaustive]
+    GitError {
+        /// The repository (or url) the command was run against
+        path: PathBuf,
+        /// The arguments passed to git, e.g. `["fetch", "origin", "HEAD"]`
+        args: Vec<String>,
+        /// The exit code, or `None` if git was killed by a signal or exited
+        /// successfully with output we could not use
+        status: Option<i32>,
+        /// What git wrote to stderr, or why its output could not be used
+        stderr: String,
+    },
+}
+
+impl GitFail {
+    /// A git command that exited with a non-zero status
+    pub(crate) fn command_failed(
+        path: impl Into<PathBuf>,
+        cmd: &std::process::Command,
+        output: &std::process::Output,
+    ) -> Self {
+        GitFail::GitError {
+            path: PathBuf::from(redact(&path.into().to_string_lossy())),
+            args: cmd
+                .get_args()
+                .map(|arg| redact(&arg.to_string_lossy()))
+                .collect(),
+            status: output.status.code(),
+            stderr: redact(&String::from_utf8_lossy(&output.stderr)),
+        }
+    }
+
+    /// A git command that succeeded, but whose output we could not use
+    pub(crate) fn unexpected_output<I, S>(path: &Path, args: I, message: String) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        GitFail::GitError {
+            path: PathBuf::from(redact(&path.to_string_lossy())),
+            args: args.into_iter().map(|arg| redact(&arg.into())).collect(),
+            status: None,
+            stderr: redact(&message),
+        }
+    }
+
+    /// `true` if git failed because it could not reach the remote, rather
+    /// than because of a problem with the repository.
+    ///
+    /// This is based on git's error message, so is a best guess.
+    pub fn is_network_error(&self) -> bool {
+        // messages from git (and curl, which it uses for https)
+        const NETWORK_ERRORS: &[&str] = &[
+            "could not resolve host",
+            "couldn't resolve host",
+            "connection timed out",
+            "operation timed out",
+            "connection refused",
+            "connection reset",
+            "failed to connect",
+            "network is unreachable",
+            "the remote end hung up unexpectedly",
+            "early eof",
+            "rpc failed",
+            "gnutls",
+            "ssl",
+            "tls",
+            "the requested url returned error: 5",
+            "the requested url returned error: 429",
+        ];
+        match self {
+            GitFail::ProcessFailed(_) => false,
+            GitFail::GitError { stderr, .. } => {
+                let stderr = stderr.to_lowercase();
+                NETWORK_ERRORS.iter().any(|msg| stderr.contains(msg))
+            }
+        }
+    }
+
+    /// The git operation that failed (such as 'clone', 'fetch' or 'checkout'),
+    /// if a command was run
+    pub fn operation(&self) -> Option<&str> {
+        match self {
+            GitFail::ProcessFailed(_) => None,
+            GitFail::GitError { args, .. } => args
+                .iter()
+                .find(|arg| !arg.starts_with('-'))
+                .map(String::as_str),
+        }
+    }
+}
+
+fn command_line(args: &[String]) -> String {
+    std::iter::once("git")
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn exit_status(status: Option<i32>) -> String {
+    status
+        .map(|code| format!(" (exit status {code})"))
+        .unwrap_or_default()
 }
 
+#[derive(Debug)]
 pub(crate) enum MetadataError {
     Read(std::io::Error),
     Parse(BadMetadata),
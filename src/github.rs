@@ -0,0 +1,201 @@
+//! batched queries for the state of repositories on github
+//!
+//! With a token, the github GraphQL api can tell us the current default
+//! branch (and its head), whether a repository is archived, and where it
+//! lives now if it was renamed or transferred, for many repositories in a
+//! single request. This saves a round-trip per repository when probing.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{
+    repo_info::{host_for_url, repo_name_and_org_from_url},
+    GitRev, RepoHost,
+};
+
+/// The environment variable holding the token used for api requests
+pub(crate) const TOKEN_VAR: &str = "GITHUB_TOKEN";
+
+const API_URL: &str = "https://api.github.com/graphql";
+
+// the number of repositories in each query; well under github's node limit
+pub(crate) const BATCH_SIZE: usize = 100;
+
+/// What github told us about a repository
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RepoState {
+    /// The current url; differs from the one we asked about if the
+    /// repository was renamed or moved to another owner
+    pub(crate) url: String,
+    pub(crate) archived: bool,
+    pub(crate) default_branch: Option<String>,
+    /// The head of the default branch, if the repository is not empty
+    pub(crate) head: Option<GitRev>,
+}
+
+impl RepoState {
+    /// `true` if the repository now lives somewhere other than `repo_url`
+    pub(crate) fn was_moved_from(&self, repo_url: &str) -> bool {
+        let repo_url = repo_url.trim_end_matches('/');
+        let repo_url = repo_url.strip_suffix(".git").unwrap_or(repo_url);
+        !self.url.eq_ignore_ascii_case(repo_url)
+    }
+}
+
+/// The token for api requests, if one is set
+pub(crate) fn token() -> Option<String> {
+    std::env::var(TOKEN_VAR)
+        .ok()
+        .filter(|t| !t.trim().is_empty())
+}
+
+/// Look up the state of each of `repo_urls` that is on github, keyed by url.
+///
+/// Repositories that don't exist (or that the token can't see) are missing
+/// from the result, as are those in any batch where the request failed; the
+/// caller should fall back to probing those individually.
+pub(crate) fn query_repo_states<'a>(
+    repo_urls: impl IntoIterator<Item = &'a str>,
+    token: &str,
+) -> HashMap<String, RepoState> {
+    let repos = repo_urls
+        .into_iter()
+        .filter(|url| host_for_url(url) == RepoHost::GitHub)
+        .filter_map(|url| {
+            let (org, name) = repo_name_and_org_from_url(url)?;
+            Some((url, org, name.strip_suffix(".git").unwrap_or(name)))
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = HashMap::new();
+    for batch in repos.chunks(BATCH_SIZE) {
+        let query = build_query(batch.iter().map(|(_, org, name)| (*org, *name)));
+        let body = serde_json::json!({ "query": query }).to_string();
+        let response = ureq::post(API_URL)
+            .set("Authorization", &format!("bearer {token}"))
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+            .map_err(|e| e.to_string())
+            .and_then(|resp| resp.into_string().map_err(|e| e.to_string()))
+            .and_then(|text| serde_json::from_str::<Value>(&text).map_err(|e| e.to_string()));
+        match response {
+            Ok(response) => {
+                let urls = batch.iter().map(|(url, ..)| *url);
+                result.extend(parse_response(&response, urls));
+            }
+            Err(e) => log::warn!(
+                stage = "probe";
+                "github api request for {} repositories failed: {e}",
+                batch.len()
+            ),
+        }
+    }
+    log::debug!(
+        stage = "probe";
+        "github api returned the state of {} of {} repositories",
+        result.len(),
+        repos.len()
+    );
+    result
+}
+
+// one aliased `repository` field per repo, named by its index in the batch
+fn build_query<'a>(repos: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut query = String::from("query {");
+    for (i, (org, name)) in repos.into_iter().enumerate() {
+        // json string literals are valid graphql string literals
+        let org = Value::from(org);
+        let name = Value::from(name);
+        query.push_str(&format!(
+            " r{i}: repository(owner: {org}, name: {name}) \
+             {{ url isArchived defaultBranchRef {{ name target {{ oid }} }} }}"
+        ));
+    }
+    query.push_str(" }");
+    query
+}
+
+fn parse_response<'a>(
+    response: &Value,
+    repo_urls: impl IntoIterator<Item = &'a str>,
+) -> Vec<(String, RepoState)> {
+    let Some(data) = response.get("data") else {
+        log::warn!(stage = "probe"; "unexpected github api response: {response}");
+        return Vec::new();
+    };
+    repo_urls
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, repo_url)| {
+            // null for repos that don't exist (which are also listed in 'errors')
+            let repo = data.get(format!("r{i}")).filter(|r| !r.is_null())?;
+            let branch = repo.get("defaultBranchRef").filter(|b| !b.is_null());
+            let state = RepoState {
+                url: repo.get("url")?.as_str()?.to_owned(),
+                archived: repo.get("isArchived")?.as_bool()?,
+                default_branch: branch
+                    .and_then(|b| b.get("name")?.as_str())
+                    .map(str::to_owned),
+                head: branch
+                    .and_then(|b| b.pointer("/target/oid")?.as_str())
+                    .map(str::to_owned),
+            };
+            Some((repo_url.to_owned(), state))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batched_query() {
+        let query = build_query([("org", "font"), ("other", "a\"b")]);
+        assert!(query.starts_with("query { r0: repository(owner: \"org\", name: \"font\")"));
+        assert!(query.contains("r1: repository(owner: \"other\", name: \"a\\\"b\")"));
+
+        let response = serde_json::json!({
+            "data": {
+                "r0": {
+                    "url": "https://github.com/new-org/font",
+                    "isArchived": true,
+                    "defaultBranchRef": { "name": "main", "target": { "oid": "abc123" } }
+                },
+                "r1": null,
+                "r2": { "url": "https://github.com/org/empty", "isArchived": false, "defaultBranchRef": null }
+            },
+            "errors": [{ "type": "NOT_FOUND", "path": ["r1"] }]
+        });
+        let states = parse_response(
+            &response,
+            [
+                "https://github.com/org/font",
+                "https://github.com/org/gone",
+                "https://github.com/org/empty",
+            ],
+        );
+        assert_eq!(states.len(), 2);
+        assert_eq!(
+            states[0],
+            (
+                "https://github.com/org/font".to_string(),
+                RepoState {
+                    url: "https://github.com/new-org/font".into(),
+                    archived: true,
+                    default_branch: Some("main".into()),
+                    head: Some("abc123".into()),
+                }
+            )
+        );
+        assert!(states[0].1.was_moved_from("https://github.com/org/font"));
+        assert!(!states[1]
+            .1
+            .was_moved_from("https://github.com/Org/Empty.git/"));
+        assert_eq!(states[1].1.head, None);
+        assert!(
+            parse_response(&serde_json::json!({ "message": "Bad credentials" }), ["x"]).is_empty()
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! caching the responses to http probes, so repeated runs can revalidate
+//! them cheaply
+//!
+//! Each response is saved (in a directory in the cache directory) along with
+//! its `ETag` and `Last-Modified` headers and how long the host says it stays
+//! fresh. Fresh responses are reused without a request; stale ones are
+//! revalidated with a conditional request, and reused if the host answers
+//! `304 Not Modified`. Responses marked `no-store` are never saved.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{repo_info::hex_string, source_set::replace_file};
+
+const DIR_NAME: &str = "gfsources-http-cache";
+
+/// A response, either from the host or from the cache
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct CachedResponse {
+    pub(crate) status: u16,
+    pub(crate) content_type: String,
+    /// The body, for `GET` requests
+    pub(crate) body: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since the epoch until which this can be used without
+    /// revalidating
+    fresh_until: Option<u64>,
+}
+
+/// Where responses are cached, if anywhere
+#[derive(Clone, Debug)]
+pub(crate) struct HttpCache(Option<PathBuf>);
+
+impl HttpCache {
+    /// A cache in the given cache directory
+    pub(crate) fn in_dir(cache_dir: &Path) -> Self {
+        HttpCache(Some(cache_dir.join(DIR_NAME)))
+    }
+
+    /// Don't cache anything
+    pub(crate) fn disabled() -> Self {
+        HttpCache(None)
+    }
+
+    /// Make a `HEAD` request, or reuse a cached response
+    pub(crate) fn head(&self, url: &str) -> Result<CachedResponse, Box<ureq::Error>> {
+        self.request("HEAD", url)
+    }
+
+    /// Make a `GET` request, or reuse a cached response
+    pub(crate) fn get(&self, url: &str) -> Result<CachedResponse, Box<ureq::Error>> {
+        self.request("GET", url)
+    }
+
+    /// `404` responses are returned (and cached) like successful ones; other
+    /// error statuses are returned as errors, and not cached.
+    fn request(&self, method: &str, url: &str) -> Result<CachedResponse, Box<ureq::Error>> {
+        let path = self.entry_path(method, url);
+        let cached = path.as_deref().and_then(load_entry);
+        let now = now();
+        if let Some(cached) = cached.as_ref().filter(|c| c.is_fresh(now)) {
+            log::trace!("using cached response for {method} {url}");
+            return Ok(cached.clone());
+        }
+
+        let mut req = ureq::request(method, url);
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                req = req.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                req = req.set("If-Modified-Since", last_modified);
+            }
+        }
+        let resp = match req.call() {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(404, resp)) => resp,
+            Err(e) => return Err(Box::new(e)),
+        };
+        let cache_control = CacheControl::parse(&resp, now);
+        let response = match cached {
+            Some(mut cached) if resp.status() == 304 => {
+                log::trace!("cached response for {method} {url} is still valid");
+                cached.fresh_until = cache_control.fresh_until;
+                cached
+            }
+            _ => CachedResponse {
+                status: resp.status(),
+                content_type: resp.content_type().to_owned(),
+                etag: resp.header("ETag").map(str::to_owned),
+                last_modified: resp.header("Last-Modified").map(str::to_owned),
+                fresh_until: cache_control.fresh_until,
+                body: match method {
+                    "GET" => Some(resp.into_string().map_err(|e| Box::new(e.into()))?),
+                    _ => None,
+                },
+            },
+        };
+        if let Some(path) = path.filter(|_| !cache_control.no_store) {
+            if let Err(e) = save_entry(&path, &response) {
+                log::warn!("failed to cache response for {url}: '{e}'");
+            }
+        }
+        Ok(response)
+    }
+
+    fn entry_path(&self, method: &str, url: &str) -> Option<PathBuf> {
+        let dir = self.0.as_ref()?;
+        let key = hex_string(&Sha256::digest(format!("{method} {url}").as_bytes()));
+        Some(dir.join(key).with_extension("json"))
+    }
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, now: u64) -> bool {
+        self.fresh_until.is_some_and(|until| now < until)
+    }
+}
+
+// the parts of the Cache-Control header we respect
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CacheControl {
+    no_store: bool,
+    fresh_until: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(resp: &ureq::Response, now: u64) -> Self {
+        let age = resp
+            .header("Age")
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or(0);
+        Self::from_header(resp.header("Cache-Control").unwrap_or_default(), age, now)
+    }
+
+    fn from_header(header: &str, age: u64, now: u64) -> Self {
+        let mut no_cache = false;
+        let mut max_age = None;
+        let mut result = CacheControl::default();
+        for directive in header.split(',').map(str::trim) {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => result.no_store = true,
+                "no-cache" | "must-revalidate" => no_cache = true,
+                "max-age" => max_age = value.trim_matches('"').parse::<u64>().ok(),
+                _ => (),
+            }
+        }
+        if !no_cache {
+            result.fresh_until = max_age.map(|max_age| (now + max_age).saturating_sub(age));
+        }
+        result
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn load_entry(path: &Path) -> Option<CachedResponse> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+// entries may be written by several threads at once
+fn save_entry(path: &Path, response: &CachedResponse) -> Result<(), std::io::Error> {
+    let dir = path.parent().expect("entries are always in a directory");
+    std::fs::create_dir_all(dir)?;
+    replace_file(path, &serde_json::to_vec(response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_control() {
+        assert_eq!(
+            CacheControl::from_header("max-age=300, public", 100, 1000),
+            CacheControl {
+                no_store: false,
+                fresh_until: Some(1200),
+            }
+        );
+        assert_eq!(
+            CacheControl::from_header("no-cache, max-age=300", 0, 1000).fresh_until,
+            None
+        );
+        assert!(CacheControl::from_header("private, No-Store", 0, 1000).no_store);
+        assert_eq!(CacheControl::from_header("", 0, 1000), Default::default());
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = HttpCache::in_dir(temp.path());
+        let path = cache.entry_path("HEAD", "https://example.com/a").unwrap();
+        assert_ne!(
+            Some(path.clone()),
+            cache.entry_path("GET", "https://example.com/a")
+        );
+        assert!(HttpCache::disabled().entry_path("HEAD", "x").is_none());
+
+        let response = CachedResponse {
+            status: 200,
+            content_type: "text/plain".into(),
+            body: None,
+            etag: Some("\"abc\"".into()),
+            last_modified: None,
+            fresh_until: Some(now() + 60),
+        };
+        save_entry(&path, &response).unwrap();
+        assert_eq!(load_entry(&path), Some(response.clone()));
+        // served from the cache, without a request
+        assert_eq!(cache.head("https://example.com/a").unwrap(), response);
+        assert!(!response.is_fresh(now() + 60));
+    }
+}
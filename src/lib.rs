@@ -25,59 +25,662 @@
 //! ```
 
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::channel,
-        Arc,
+        mpsc::{channel, Sender},
+        Arc, Mutex, PoisonError,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use kdam::{tqdm, BarExt};
+use clap::CommandFactory;
 
 mod args;
+#[cfg(feature = "async")]
+mod async_api;
+mod batch;
+mod cache;
+mod checkpoint;
 mod config;
+mod conflict;
+mod credentials;
+mod diff;
+mod discovery_cache;
+mod discovery_report;
 mod error;
+mod github;
+mod http_cache;
+mod lock;
+mod logging;
 mod metadata;
+mod metadata_index;
+mod metrics;
+mod observer;
+mod options;
+mod problem;
+mod progress;
+mod prometheus;
 mod repo_info;
+mod report;
+mod source_set;
+mod stats;
+mod stream;
 
-pub use args::Args;
+pub use args::{Args, Command, DiffFormat, OutputFormat, ReportFormat, EXIT_FAILED, EXIT_PROBLEMS};
+#[cfg(feature = "async")]
+pub use async_api::discover_sources_async;
+pub use batch::{update_cache_dir, CheckoutUpdate};
+pub use cache::{checkout_status, find_unused_checkouts, Checkout, CheckoutStatus};
+use checkpoint::Checkpoint;
 pub use config::Config;
-pub use error::{BadConfig, Error, GitFail, LoadRepoError};
-use error::{MetadataError, UnwrapOrDie};
+pub use conflict::RevConflict;
+use credentials::{git_command, git_command_for, Credentials};
+pub use diff::{ChangedSource, SourceSetDiff};
+use discovery_cache::DiscoveryCache;
+pub use discovery_report::{DiscoveryReport, DiscoveryStage, ReportEntry, SkipReason, SkipSummary};
+pub use error::{
+    BadConfig, CliError, Error, ErrorCode, GitFail, LoadRepoError, LockError, MergeConflict,
+    SourceSetError,
+};
+use error::{MetadataError, OrFail};
+use http_cache::HttpCache;
+pub use lock::{LockFile, LockedSource};
+pub use logging::{LogArea, LogFormat};
+pub use metadata::License;
 use metadata::Metadata;
-pub use repo_info::RepoInfo;
+use metadata_index::MetadataIndex;
+use metrics::Phase;
+pub use metrics::{Metrics, RepoPhases, RepoTiming};
+pub use observer::{Event, Observer};
+pub use options::DiscoveryOptions;
+use options::Network;
+pub use problem::{CheckReport, Problem, ProblemKind};
+use rayon::prelude::*;
+pub use repo_info::{
+    CloneBranches, Commit, RelativeSources, RepoHost, RepoInfo, SourceFile, SourceStatus,
+    Staleness, Validation,
+};
+pub use report::{render_changelog, render_html, render_markdown};
+pub use source_set::{MergeStrategy, SourceSet, Version};
+pub use stats::Stats;
+pub use stream::{read_streaming, SourceSetWriter};
 
 static GF_REPO_URL: &str = "https://github.com/google/fonts";
 static METADATA_FILE: &str = "METADATA.pb";
+// the number of repositories listed by `--timings` and `--profile`
+const N_SLOWEST_REPOS: usize = 10;
 
 type GitRev = String;
 
 /// entry point for the cli tool
 #[doc(hidden)] // only intended to be used from our binary
-pub fn run(args: &Args) {
-    let repos = discover_sources(&args.fonts_dir).unwrap_or_die(|e| eprintln!("{e}"));
-    let output = if args.list {
-        let urls = repos.into_iter().map(|r| r.repo_url).collect::<Vec<_>>();
-        urls.join("\n")
+pub fn run(args: &Args) -> Result<(), CliError> {
+    if let Some(jobs) = args.jobs {
+        if let Err(e) = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+        {
+            log::warn!("failed to set number of jobs: '{e}'");
+        }
+    }
+    match &args.command {
+        Some(Command::Schema) => {
+            let schema = SourceSet::json_schema();
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            return Ok(());
+        }
+        Some(Command::Stats { input, json }) => {
+            let stats = load_set(input)?.stats();
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+            } else {
+                print!("{stats}");
+            }
+            return Ok(());
+        }
+        Some(Command::Lock {
+            input,
+            fonts_dir,
+            out,
+        }) => {
+            let lock = load_set(input)?
+                .lock(fonts_dir)
+                .map_err(|e| CliError::Failed(e.to_string()))?;
+            match out {
+                Some(out) => lock.save(out),
+                None => serde_json::to_string_pretty(&lock)
+                    .map(|json| println!("{json}"))
+                    .map_err(Into::into),
+            }
+            .or_fail(|| "failed to write lock file".into())?;
+            return Ok(());
+        }
+        Some(Command::Update { fonts_dir, input }) => {
+            return update_checkouts(fonts_dir, input.as_deref(), args.clone_branches());
+        }
+        Some(Command::Gc {
+            fonts_dir,
+            input,
+            max_age_days,
+            dry_run,
+        }) => {
+            let keep = input.as_deref().map(load_set).transpose()?;
+            let max_age = max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let unused = find_unused_checkouts(fonts_dir, keep.as_ref(), max_age)
+                .or_fail(|| format!("failed to read '{}'", fonts_dir.display()))?;
+            let mut reclaimed = 0;
+            for checkout in &unused {
+                println!(
+                    "{} ({})",
+                    checkout.path.display(),
+                    display_size(checkout.size)
+                );
+                if !dry_run {
+                    checkout
+                        .remove()
+                        .or_fail(|| format!("failed to delete '{}'", checkout.path.display()))?;
+                }
+                reclaimed += checkout.size;
+            }
+            let verb = if *dry_run { "would remove" } else { "removed" };
+            eprintln!(
+                "{verb} {} checkouts, {}",
+                unused.len(),
+                display_size(reclaimed)
+            );
+            return Ok(());
+        }
+        Some(Command::Fetch {
+            fonts_dir,
+            target,
+            input,
+        }) => {
+            return fetch_one(
+                fonts_dir,
+                target,
+                input.as_deref(),
+                &args.discovery_options(),
+            );
+        }
+        Some(Command::Sources {
+            fonts_dir,
+            input,
+            json,
+        }) => {
+            return print_sources(fonts_dir, input.as_deref(), *json);
+        }
+        Some(Command::Validate { input, fonts_dir }) => {
+            let set = load_set(input)?;
+            let problems = set.validate_all(fonts_dir.as_deref(), true);
+            for problem in &problems {
+                println!("{problem}");
+            }
+            let summary = format!("{} sources, {} problems", set.len(), problems.len());
+            if !problems.is_empty() {
+                return Err(CliError::Incomplete(summary));
+            }
+            eprintln!("{summary}");
+            return Ok(());
+        }
+        Some(Command::Status { fonts_dir, input }) => {
+            return print_status(fonts_dir, input.as_deref());
+        }
+        Some(Command::Check {
+            fonts_dir,
+            input,
+            out,
+        }) => {
+            let set = match input {
+                Some(input) => load_set(input)?,
+                None => discover_sources(fonts_dir)?,
+            };
+            let report = set.check(fonts_dir, true);
+            let json = serde_json::to_string_pretty(&report).expect("always valid json");
+            match out {
+                Some(out) => source_set::write_file(out, format!("{json}\n").as_bytes())
+                    .or_fail(|| "failed to write report".into())?,
+                None => println!("{json}"),
+            }
+            let summary = format!(
+                "{} sources checked, {} passed, {} problems",
+                report.checked,
+                report.passed,
+                report.failures.len()
+            );
+            if !report.is_ok() {
+                return Err(CliError::Incomplete(summary));
+            }
+            eprintln!("{summary}");
+            return Ok(());
+        }
+        Some(Command::Diff { old, new, format }) => {
+            let diff = load_set(new)?.diff(&load_set(old)?);
+            match format {
+                DiffFormat::Text => print!("{diff}"),
+                DiffFormat::Json => {
+                    let json = serde_json::to_string_pretty(&diff).expect("always valid json");
+                    println!("{json}");
+                }
+                DiffFormat::Markdown => print!("{}", render_changelog(&diff)),
+            }
+            return Ok(());
+        }
+        Some(Command::Changelog { old, new }) => {
+            let diff = load_set(new)?.diff(&load_set(old)?);
+            print!("{}", render_changelog(&diff));
+            return Ok(());
+        }
+        Some(Command::Report {
+            input,
+            previous,
+            format,
+            out,
+        }) => {
+            let set = load_set(input)?;
+            let diff = match previous {
+                Some(previous) => Some(set.diff(&load_set(previous)?)),
+                None => None,
+            };
+            let report = match format {
+                ReportFormat::Markdown => render_markdown(&set, diff.as_ref()),
+                ReportFormat::Html => render_html(&set, diff.as_ref()),
+            };
+            match out {
+                Some(out) => source_set::write_file(out, report.as_bytes())
+                    .or_fail(|| "failed to write report".into())?,
+                None => print!("{report}"),
+            }
+            return Ok(());
+        }
+        Some(Command::Completions { shell }) => {
+            let mut cmd = Args::command();
+            let name = cmd.get_name().to_owned();
+            clap_complete::generate(*shell, &mut cmd, name, &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            return clap_mangen::Man::new(Args::command())
+                .render(&mut std::io::stdout())
+                .or_fail(|| "failed to write man page".into());
+        }
+        None => (),
+    }
+    let fonts_dir = args.fonts_dir.as_deref().expect("required by clap");
+    if let Some(interval) = args.watch {
+        return watch(fonts_dir, args, interval);
+    }
+    let (repos, report) = if args.stdin {
+        let urls =
+            read_repo_urls(std::io::stdin().lock()).or_fail(|| "failed to read stdin".into())?;
+        let options = args.discovery_options();
+        (SourceSet::from_repo_urls(urls, fonts_dir, &options), None)
     } else {
-        serde_json::to_string_pretty(&repos)
-            .unwrap_or_die(|e| eprintln!("failed to serialize repo info: '{e}'"))
+        let (set, report) = discover_sources_with_report(fonts_dir, &args.discovery_options())?;
+        if let Some(path) = &args.discovery_report {
+            let json = serde_json::to_string_pretty(&report).expect("always valid json");
+            source_set::write_file(path, format!("{json}\n").as_bytes())
+                .or_fail(|| "failed to write discovery report".into())?;
+        }
+        if args.timings {
+            eprint!("{}", report.metrics.summary(N_SLOWEST_REPOS));
+        }
+        if args.profile {
+            eprint!("{}", report.metrics.profile(N_SLOWEST_REPOS));
+        }
+        (set, Some(report))
     };
+    // the previous output, if it is a source set, to report what changed
+    let previous = args
+        .out
+        .as_deref()
+        .filter(|path| report.is_some() && !args.quiet && path.exists())
+        .and_then(|path| SourceSet::load(path).ok());
+    let output = render_output(&repos, args.output_format())?;
 
+    // we always write a single trailing newline
+    let output = output.trim_end_matches('\n');
     if let Some(out) = args.out.as_ref() {
-        std::fs::write(out, output).unwrap_or_die(|e| eprintln!("failed to write output: '{e}'"));
+        source_set::write_file(out, format!("{output}\n").as_bytes())
+            .or_fail(|| "failed to write output".into())?;
     } else {
         println!("{output}")
     }
+
+    if let Some(report) = report.filter(|_| !args.quiet) {
+        let diff = previous.map(|previous| repos.diff(&previous));
+        eprint!("{}", report.summary(&repos, diff.as_ref()));
+    }
+
+    // ndjson has nowhere to put problems, so they go to stderr
+    if args.output_format() == OutputFormat::Ndjson {
+        for problem in repos.problems() {
+            let json = serde_json::to_string(problem).expect("always valid json");
+            eprintln!("{json}");
+        }
+    }
+
+    if args.github_annotations {
+        for problem in repos.problems() {
+            eprintln!("{}", problem.github_annotation());
+        }
+    }
+
+    if args.strict {
+        let warnings = strict_warnings(&repos);
+        if !warnings.is_empty() {
+            return Err(CliError::Warnings(warnings));
+        }
+    }
+    Ok(())
+}
+
+// load a source set, for the cli
+fn load_set(path: &Path) -> Result<SourceSet, CliError> {
+    SourceSet::load(path).or_fail(|| format!("failed to load '{}'", path.display()))
+}
+
+// serialize the discovered sources in the requested format
+fn render_output(repos: &SourceSet, format: OutputFormat) -> Result<String, CliError> {
+    let output = match format {
+        OutputFormat::List => {
+            let urls = repos
+                .iter()
+                .map(|r| r.repo_url.as_str())
+                .collect::<Vec<_>>();
+            return Ok(urls.join("\n"));
+        }
+        OutputFormat::Json => repos.to_json().map_err(|e| e.to_string()),
+        OutputFormat::Yaml => repos.to_yaml().map_err(|e| e.to_string()),
+        OutputFormat::Toml => repos.to_toml().map_err(|e| e.to_string()),
+        OutputFormat::Ndjson | OutputFormat::Csv => {
+            let mut buf = Vec::new();
+            if format == OutputFormat::Csv {
+                repos.write_csv(&mut buf)
+            } else {
+                repos.write_ndjson(&mut buf)
+            }
+            .map(|()| String::from_utf8(buf).expect("output is always utf-8"))
+            .map_err(|e| e.to_string())
+        }
+    };
+    output.or_fail(|| "failed to serialize repo info".into())
+}
+
+// the '--watch' loop: refresh the output every `interval`, forever (only
+// returning if the metrics endpoint can't be started)
+fn watch(fonts_dir: &Path, args: &Args, interval: Duration) -> Result<(), CliError> {
+    let out = args.out.as_deref().expect("required by clap");
+    let options = args.discovery_options();
+    let metrics = Arc::new(prometheus::WatchMetrics::default());
+    if let Some(addr) = args.metrics_addr {
+        metrics
+            .serve(addr)
+            .or_fail(|| format!("failed to serve metrics at '{addr}'"))?;
+    }
+    // the first iteration starts from the output of a previous run, if any
+    let mut previous = SourceSet::load(out).ok();
+    loop {
+        let result = match &previous {
+            Some(previous) => previous.refresh_with_observer(fonts_dir, &options, &*metrics),
+            None => {
+                discover_sources_with_observer(fonts_dir, &options, &*metrics).map(|(set, _)| set)
+            }
+        };
+        metrics.run_finished(result.as_ref().ok().map(SourceSet::len));
+        match result {
+            Ok(repos) => {
+                let empty = SourceSet::new(Vec::new());
+                let diff = repos.diff(previous.as_ref().unwrap_or(&empty));
+                if diff.is_empty() {
+                    log::info!("no changes");
+                } else {
+                    log::info!(
+                        "{} added, {} removed, {} changed",
+                        diff.added.len(),
+                        diff.removed.len(),
+                        diff.changed.len()
+                    );
+                    eprint!("{diff}");
+                }
+                let written = render_output(&repos, args.output_format()).and_then(|output| {
+                    let output = format!("{}\n", output.trim_end_matches('\n'));
+                    source_set::replace_file(out, output.as_bytes())
+                        .or_fail(|| "failed to write output".into())
+                });
+                match written {
+                    Ok(()) => previous = Some(repos),
+                    Err(e) => log::error!("{e}"),
+                }
+            }
+            Err(e) => log::error!("discovery failed: {e}"),
+        }
+        log::info!("next check in {}", humantime::format_duration(interval));
+        std::thread::sleep(interval);
+    }
+}
+
+// the things that cause '--strict' to fail
+fn strict_warnings(set: &SourceSet) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if !set.problems().is_empty() {
+        warnings.push(format!(
+            "{} families were skipped because of problems",
+            set.problems().len()
+        ));
+    }
+    let unknown_hosts = set
+        .iter()
+        .filter(|info| info.repo_host() == RepoHost::Other)
+        .count();
+    if unknown_hosts > 0 {
+        warnings.push(format!("{unknown_hosts} sources are on an unknown host"));
+    }
+    if !set.rev_conflicts().is_empty() {
+        warnings.push(format!(
+            "{} repositories are pinned at more than one rev",
+            set.rev_conflicts().len()
+        ));
+    }
+    warnings
+}
+
+// a human-readable size, like '1.5 MB'
+fn display_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+// the 'fetch' subcommand
+fn fetch_one(
+    fonts_dir: &Path,
+    target: &str,
+    input: Option<&Path>,
+    options: &DiscoveryOptions,
+) -> Result<(), CliError> {
+    let is_url = target.contains("://");
+    let set = match input {
+        Some(input) => {
+            let set = load_set(input)?;
+            let found = if is_url {
+                set.by_repo_url(target).cloned().collect()
+            } else {
+                set.by_family(target).cloned().collect()
+            };
+            SourceSet::new(found)
+        }
+        None if is_url => SourceSet::from_repo_urls([target], fonts_dir, options),
+        None => {
+            return Err(CliError::Failed(
+                "fetching a family by name requires --input".into(),
+            ))
+        }
+    };
+    for problem in set.problems() {
+        eprintln!("{problem}");
+    }
+    if set.is_empty() {
+        return Err(CliError::Failed(format!("no source found for '{target}'")));
+    }
+    for info in &set {
+        let (path, sha) = info
+            .checkout(fonts_dir, options.clone_branches)
+            .or_fail(|| format!("failed to fetch '{}'", info.repo_url))?;
+        println!("{} {sha}", path.display());
+    }
+    Ok(())
+}
+
+// the 'sources' subcommand
+fn print_sources(fonts_dir: &Path, input: Option<&Path>, json: bool) -> Result<(), CliError> {
+    let set = match input {
+        Some(input) => load_set(input)?,
+        None => discover_sources(fonts_dir)?,
+    };
+    let mut entries = Vec::new();
+    for (info, result) in set.get_all_sources(fonts_dir, true) {
+        let sources = match result {
+            Ok(sources) => sources
+                .into_iter()
+                .filter(|source| source.status == SourceStatus::Found)
+                .map(|source| source.path)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                eprintln!("failed to load sources for '{}': '{e}'", info.repo_url);
+                continue;
+            }
+        };
+        if json {
+            entries.push(serde_json::json!({
+                "repo_url": info.repo_url,
+                "subdir": info.subdir,
+                "rev": info.git_rev(),
+                "sources": sources,
+            }));
+        } else {
+            for path in sources {
+                println!("{}", path.display());
+            }
+        }
+    }
+    if json {
+        let json = serde_json::to_string_pretty(&entries).expect("always valid json");
+        println!("{json}");
+    }
+    Ok(())
+}
+
+// the 'status' subcommand
+fn print_status(fonts_dir: &Path, input: Option<&Path>) -> Result<(), CliError> {
+    let set = input.map(load_set).transpose()?;
+    let statuses = checkout_status(fonts_dir, set.as_ref())
+        .or_fail(|| format!("failed to read '{}'", fonts_dir.display()))?;
+    let now = std::time::SystemTime::now();
+    let mut total = 0;
+    for status in &statuses {
+        let head = status.head.as_deref().unwrap_or("unknown");
+        let pinned = match status.at_pinned_rev {
+            None if set.is_some() => "unreferenced".to_string(),
+            None => String::new(),
+            Some(true) => "at pinned rev".to_string(),
+            Some(false) => format!("pinned at {}", status.pinned_revs.join(", ")),
+        };
+        let fetched = match status.last_fetched.map(|time| now.duration_since(time)) {
+            Some(Ok(age)) => format!("fetched {} days ago", age.as_secs() / (24 * 60 * 60)),
+            Some(Err(_)) => "fetched just now".to_string(),
+            None => "never fetched".to_string(),
+        };
+        let dirty = if status.dirty { "dirty" } else { "clean" };
+        println!(
+            "{}\t{}\t{pinned}\t{dirty}\t{}\t{fetched}",
+            status.checkout.path.display(),
+            head.get(..7).unwrap_or(head),
+            display_size(status.checkout.size),
+        );
+        total += status.checkout.size;
+    }
+    eprintln!("{} checkouts, {}", statuses.len(), display_size(total));
+    Ok(())
+}
+
+// the 'update' subcommand
+fn update_checkouts(
+    fonts_dir: &Path,
+    input: Option<&Path>,
+    branches: CloneBranches,
+) -> Result<(), CliError> {
+    let results = match input {
+        Some(input) => {
+            let set = load_set(input)?;
+            set.update_all(fonts_dir, true, branches)
+                .into_iter()
+                .map(|(info, result)| (info.repo_url.clone(), result.map_err(|e| e.to_string())))
+                .collect::<Vec<_>>()
+        }
+        None => update_cache_dir(fonts_dir, true)
+            .or_fail(|| format!("failed to read '{}'", fonts_dir.display()))?
+            .into_iter()
+            .map(|(path, result)| {
+                (
+                    path.display().to_string(),
+                    result.map_err(|e| e.to_string()),
+                )
+            })
+            .collect(),
+    };
+    let (mut changed, mut failed) = (0, 0);
+    for (name, result) in &results {
+        match result {
+            Ok(update) if update.is_changed() => {
+                changed += 1;
+                let before = update.before.as_deref().unwrap_or("(new)");
+                println!("{name}: {before} -> {}", update.after);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                failed += 1;
+                eprintln!("failed to update {name}: '{e}'");
+            }
+        }
+    }
+    let summary = format!(
+        "{} checkouts, {changed} changed, {failed} failed",
+        results.len()
+    );
+    if failed > 0 {
+        return Err(CliError::Incomplete(summary));
+    }
+    eprintln!("{summary}");
+    Ok(())
+}
+
+/// Read repository urls, one per line, skipping blank lines and comments
+fn read_repo_urls(reader: impl std::io::BufRead) -> Result<Vec<String>, std::io::Error> {
+    let mut urls = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            urls.push(line.to_owned());
+        }
+    }
+    Ok(urls)
 }
 
 /// Discover repositories containing font source files.
 ///
-/// Returns a vec of `RepoInfo` structs describing repositories containing
-/// known font sources.
+/// Returns a [`SourceSet`] describing repositories containing known font
+/// sources.
 ///
 /// This looks at every font in the [google/fonts] github repo, looks to see if
 /// we have a known upstream repository for that font, and then looks to see if
@@ -89,22 +692,214 @@ pub fn run(args: &Args) {
 /// sense to cache these in most cases.
 ///
 /// [google/fonts]: https://github.com/google/fonts
-pub fn discover_sources(git_cache_dir: &Path) -> Result<Vec<RepoInfo>, Error> {
+pub fn discover_sources(git_cache_dir: &Path) -> Result<SourceSet, Error> {
+    discover_sources_with_options(git_cache_dir, &DiscoveryOptions::default())
+}
+
+/// Discover sources, as with [`discover_sources`], using the provided options.
+///
+/// This can be used to quickly discover a subset of sources.
+pub fn discover_sources_with_options(
+    git_cache_dir: &Path,
+    options: &DiscoveryOptions,
+) -> Result<SourceSet, Error> {
+    discover_sources_with_report(git_cache_dir, options).map(|(set, _)| set)
+}
+
+/// Discover sources, as with [`discover_sources_with_options`], also returning
+/// a [`DiscoveryReport`] explaining why fonts were left out.
+pub fn discover_sources_with_report(
+    git_cache_dir: &Path,
+    options: &DiscoveryOptions,
+) -> Result<(SourceSet, DiscoveryReport), Error> {
+    discover_sources_with_observer(git_cache_dir, options, &())
+}
+
+/// Discover sources, as with [`discover_sources_with_report`], sending an
+/// [`Event`] to `observer` as each repository is checked.
+pub fn discover_sources_with_observer(
+    git_cache_dir: &Path,
+    options: &DiscoveryOptions,
+    observer: &dyn Observer,
+) -> Result<(SourceSet, DiscoveryReport), Error> {
+    let started = Instant::now();
+    let mut metrics = Metrics::default();
     let google_slash_fonts = git_cache_dir.join("google/fonts");
-    update_google_fonts_checkout(&google_slash_fonts)?;
-    let candidates = get_candidates_from_local_checkout(&google_slash_fonts);
-    let have_repo = candidates_with_known_repo(&candidates);
+    let network = options.network();
+    if network.can_fetch() || (network.can_clone() && !google_slash_fonts.exists()) {
+        update_google_fonts_checkout(&google_slash_fonts, options.clone_branches)?;
+    } else if !google_slash_fonts.exists() {
+        return Err(Error::NotCached(google_slash_fonts));
+    }
+    metrics.update_google_fonts = started.elapsed();
+    let metadata_started = Instant::now();
+    let google_fonts_rev = resolve_rev(&google_slash_fonts, "HEAD")?;
+    match &google_fonts_rev {
+        Some(rev) => log::info!(rev = rev; "discovering sources from google/fonts at {rev}"),
+        None => log::warn!("could not resolve the current rev of google/fonts"),
+    }
+    let cache_path = DiscoveryCache::path(git_cache_dir);
+    let cache_key = options.cache_key();
+    if let Some(rev) = google_fonts_rev.as_ref().filter(|_| options.reuse_cached) {
+        if let Some((set, mut report)) = DiscoveryCache::load(&cache_path, rev, &cache_key) {
+            log::info!("google/fonts has not changed since the last run, reusing its results");
+            metrics.total = started.elapsed();
+            report.metrics = metrics;
+            return Ok((set, report));
+        }
+    }
+    let changed = match options.since.as_deref() {
+        Some(since) => {
+            if network.can_clone() {
+                // the checkout is shallow, and we need history
+                unshallow(&google_slash_fonts)?;
+            }
+            let changed = metadata_changed_since(&google_slash_fonts, since)?;
+            log::info!("{} fonts have changed since {since}", changed.len());
+            Some(changed)
+        }
+        None => None,
+    };
+    let checkpoint_path = Checkpoint::path(git_cache_dir);
+    let mut checkpoint = options
+        .resume
+        .then(|| Checkpoint::load(&checkpoint_path, google_fonts_rev.as_ref()))
+        .flatten()
+        .unwrap_or_else(|| Checkpoint::new(google_fonts_rev.clone()));
+    if !checkpoint.done.is_empty() {
+        log::info!(
+            "resuming: {} fonts were checked in a previous run",
+            checkpoint.done.len()
+        );
+    }
+    let resumed_sources = checkpoint.sources.clone();
+    let resumed_problems = checkpoint.problems.clone();
 
-    log::info!(
-        "checking {} repositories for config.yaml files",
-        have_repo.len()
-    );
-    let repos_with_config_files = find_config_files(&have_repo, git_cache_dir);
+    // when all the fonts that match the options are wanted, we can start
+    // probing their repositories while the rest of google/fonts is read;
+    // otherwise we have to wait to see all the fonts before picking some
+    let pipelined = options.sample.is_none() && options.limit.is_none();
+    let index_path = MetadataIndex::path(git_cache_dir);
+    let scan = |found: &(dyn Fn(&Metadata) + Sync)| {
+        let on_found = |metadata: &Metadata| {
+            if pipelined && metadata.repo_url.is_some() && options.matches(metadata) {
+                found(metadata);
+            }
+        };
+        scan_candidates(
+            &google_slash_fonts,
+            changed.as_ref(),
+            Some(&index_path),
+            &on_found,
+        )
+        .map(|scanned| (scanned, metadata_started.elapsed()))
+    };
+    let mut report = DiscoveryReport {
+        google_fonts_rev: google_fonts_rev.clone(),
+        ..Default::default()
+    };
+    let mut selection = None;
+    let select = |((candidates, mut problems, parse_times), elapsed): (
+        (BTreeSet<Metadata>, Vec<Problem>, ParseTimes),
+        Duration,
+    )| {
+        metrics.metadata = elapsed;
+        problems.retain(|problem| {
+            problem
+                .family_name
+                .as_ref()
+                .is_none_or(|name| options.families.is_empty() || options.families.contains(name))
+        });
+        for problem in &problems {
+            observer.on_event(&Event::Error(problem));
+        }
+        report.families_scanned = candidates.len();
+        let with_repo = candidates_with_known_repo(&candidates, &mut report);
+        let have_repo = options.select(with_repo.clone());
+        for metadata in with_repo.difference(&have_repo) {
+            report.push(
+                Some(&metadata.name),
+                metadata.repo_url.as_deref(),
+                DiscoveryStage::Selection,
+                Some(SkipReason::Excluded),
+                "excluded by the discovery options",
+            );
+        }
+        selection = Some((candidates.len(), problems, parse_times, have_repo.clone()));
+        have_repo
+    };
+
+    let probe_started = Instant::now();
+    // `select` holds on to `metrics`, so the repositories' timings go here
+    let mut probe_metrics = Metrics::default();
+    let probe_context = ProbeContext {
+        git_cache_dir,
+        network,
+        branches: options.clone_branches,
+        options,
+        checkpoint_path: &checkpoint_path,
+        observer,
+    };
+    let (mut repos_with_config_files, probe_problems, no_config) = find_config_files(
+        scan,
+        select,
+        &probe_context,
+        &mut checkpoint,
+        &mut probe_metrics,
+    )?;
+    metrics.probe = probe_started.elapsed();
+    metrics.repos = probe_metrics.repos;
+    let (n_candidates, mut problems, parse_times, have_repo) =
+        selection.expect("set when the scan succeeds");
+    problems.extend(resumed_problems);
+    for timing in &mut metrics.repos {
+        timing.phases.metadata = parse_times.get(&timing.family).copied().unwrap_or_default();
+    }
+    problems.extend(probe_problems);
+    repos_with_config_files.extend(resumed_sources);
+    let repo_urls = have_repo
+        .iter()
+        .map(|metadata| (metadata.name.as_str(), metadata.repo_url.as_deref()))
+        .collect::<HashMap<_, _>>();
+    for family in &no_config {
+        let url = repo_urls.get(family.as_str()).copied().flatten();
+        report.push(
+            Some(family),
+            url,
+            DiscoveryStage::Probe,
+            Some(SkipReason::NoConfig),
+            "the repository has no config file",
+        );
+    }
+    for problem in &problems {
+        report.push_problem(problem);
+    }
+    for info in &repos_with_config_files {
+        if info.repo_host() == RepoHost::Other {
+            observer.on_event(&Event::Warning {
+                repo_url: Some(&info.repo_url),
+                message: "the repository is on an unfamiliar host".into(),
+            });
+            report.push(
+                info.family_name(),
+                Some(&info.repo_url),
+                DiscoveryStage::Probe,
+                None,
+                "the repository is on an unfamiliar host",
+            );
+        }
+    }
+    // we finished, so there's nothing to resume
+    if let Err(e) = std::fs::remove_file(&checkpoint_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("failed to remove checkpoint: '{e}'");
+        }
+    }
 
     log::info!(
         "{} of {} candidates have known repo url",
         have_repo.len(),
-        candidates.len()
+        n_candidates
     );
 
     log::info!(
@@ -113,25 +908,388 @@ pub fn discover_sources(git_cache_dir: &Path) -> Result<Vec<RepoInfo>, Error> {
         have_repo.len()
     );
 
-    Ok(repos_with_config_files)
+    if !problems.is_empty() {
+        log::warn!("{} fonts were skipped because of errors", problems.len());
+    }
+
+    metrics.total = started.elapsed();
+    metrics.finish();
+    report.metrics = metrics;
+    let set = SourceSet::discovered(repos_with_config_files, google_fonts_rev, problems)
+        .with_skipped(report.skipped());
+    if let Some(rev) = report
+        .google_fonts_rev
+        .as_ref()
+        .filter(|_| options.reuse_cached)
+    {
+        if let Err(e) = DiscoveryCache::save(&cache_path, rev, &cache_key, &set, &report) {
+            log::warn!(
+                "failed to save discovery cache '{}': {e}",
+                cache_path.display()
+            );
+        }
+    }
+    Ok((set, report))
+}
+
+impl SourceSet {
+    /// Build a set from a list of repository urls, without consulting
+    /// google/fonts.
+    ///
+    /// Each repository is probed for config files (as during discovery) and
+    /// pinned at the current rev of its default branch. This is useful for
+    /// testing repositories that have not yet been onboarded to google/fonts.
+    ///
+    /// Repositories that cannot be used (including those without a config
+    /// file) are recorded in [`problems`](Self::problems).
+    ///
+    /// Of the `options`, only those controlling the network, the branches
+    /// that are cloned, and tokens are used; the filters apply to the fonts
+    /// in google/fonts, and so are ignored.
+    pub fn from_repo_urls<S: AsRef<str>>(
+        urls: impl IntoIterator<Item = S>,
+        git_cache_dir: &Path,
+        options: &DiscoveryOptions,
+    ) -> SourceSet {
+        let mut sources = Vec::new();
+        let mut problems = Vec::new();
+        for url in urls {
+            let url = url.as_ref().trim().trim_end_matches('/');
+            match probe_repo_url(url, git_cache_dir, options) {
+                Ok(info) => sources.push(info),
+                Err((kind, message)) => {
+                    log::warn!(repo = url, stage = "probe"; "skipping '{url}': {message}");
+                    problems.push(Problem {
+                        family_name: None,
+                        path: None,
+                        repo_url: Some(url.to_owned()),
+                        kind,
+                        code: Some(kind.code()),
+                        message,
+                    });
+                }
+            }
+        }
+        SourceSet::discovered(sources, None, problems)
+    }
+
+    /// Update a previously discovered set, only checking the fonts whose
+    /// metadata in google/fonts has changed since it was discovered.
+    ///
+    /// Entries for other fonts are kept as they are, and entries for fonts
+    /// that have been removed from google/fonts are dropped. If this set does
+    /// not record the rev of google/fonts it was discovered at, everything is
+    /// discovered again.
+    pub fn refresh(
+        &self,
+        git_cache_dir: &Path,
+        options: &DiscoveryOptions,
+    ) -> Result<SourceSet, Error> {
+        self.refresh_with_observer(git_cache_dir, options, &())
+    }
+
+    /// Update a previously discovered set, as with [`refresh`](Self::refresh),
+    /// sending an [`Event`] to `observer` as each repository is checked.
+    pub fn refresh_with_observer(
+        &self,
+        git_cache_dir: &Path,
+        options: &DiscoveryOptions,
+        observer: &dyn Observer,
+    ) -> Result<SourceSet, Error> {
+        let Some(previous_rev) = self.google_fonts_rev() else {
+            return discover_sources_with_observer(git_cache_dir, options, observer)
+                .map(|(set, _)| set);
+        };
+        let mut options = options.clone();
+        options.since = Some(previous_rev.to_owned());
+        let (update, _) = discover_sources_with_observer(git_cache_dir, &options, observer)?;
+
+        // discovery has updated the checkout, so this is the current state
+        let google_slash_fonts = git_cache_dir.join("google/fonts");
+        let changed = metadata_changed_since(&google_slash_fonts, previous_rev)?;
+        let index_path = MetadataIndex::path(git_cache_dir);
+        let family_names = |only| {
+            get_candidates_from_local_checkout(&google_slash_fonts, only, Some(&index_path)).map(
+                |(candidates, ..)| {
+                    candidates
+                        .into_iter()
+                        .map(|metadata| metadata.name)
+                        .collect::<HashSet<_>>()
+                },
+            )
+        };
+        let current = family_names(None)?;
+        let changed = family_names(Some(&changed))?;
+        let keep = |family: Option<&str>| {
+            family.is_none_or(|name| current.contains(name) && !changed.contains(name))
+        };
+
+        let mut sources = self
+            .iter()
+            .filter(|info| keep(info.family_name()))
+            .cloned()
+            .collect::<Vec<_>>();
+        sources.extend(update.iter().cloned());
+        let mut problems = self
+            .problems()
+            .iter()
+            .filter(|problem| keep(problem.family_name.as_deref()))
+            .cloned()
+            .collect::<Vec<_>>();
+        problems.extend(update.problems().iter().cloned());
+        Ok(SourceSet::discovered(
+            sources,
+            update.google_fonts_rev().map(str::to_owned),
+            problems,
+        ))
+    }
+}
+
+/// Look for config files in a single repository, waiting if rate limited.
+fn probe_repo_url(
+    url: &str,
+    git_cache_dir: &Path,
+    options: &DiscoveryOptions,
+) -> Result<RepoInfo, (ProblemKind, String)> {
+    let token_env = options.token_env(url);
+    let credentials =
+        token_env.and_then(|var| Credentials::from_env(url, var, |var| std::env::var(var).ok()));
+    let result = loop {
+        match config_files_and_rev_for_repo(
+            url,
+            None,
+            git_cache_dir,
+            options.network(),
+            options.clone_branches,
+            None,
+            credentials.as_ref(),
+        ) {
+            Err(ConfigFetchIssue::RateLimit(backoff)) => {
+                log::warn!("rate limited, waiting {backoff} seconds");
+                std::thread::sleep(Duration::from_secs(backoff as _));
+            }
+            result => break result,
+        }
+    };
+    match result {
+        Ok((config_files, rev)) if !config_files.is_empty() => {
+            let mut info = RepoInfo::new(url.to_owned(), rev, config_files).ok_or_else(|| {
+                let msg = format!("unexpected repo url '{url}'");
+                (ProblemKind::UnfamiliarUrl, msg)
+            })?;
+            info.token_env = token_env.map(str::to_owned);
+            Ok(info)
+        }
+        Err(ConfigFetchIssue::NoConfigFound) | Ok(_) => {
+            Err((ProblemKind::NoConfig, "no config file found".into()))
+        }
+        Err(ConfigFetchIssue::BadRepoUrl(_)) => Err((
+            ProblemKind::UnfamiliarUrl,
+            format!("unexpected repo url '{url}'"),
+        )),
+        Err(ConfigFetchIssue::NoCommit(e)) => Err((ProblemKind::MissingCommit, e.to_string())),
+        Err(ConfigFetchIssue::GitFail(e)) => Err((ProblemKind::ProbeFailed, e.to_string())),
+        Err(ConfigFetchIssue::Http(e)) => Err((ProblemKind::ProbeFailed, e.to_string())),
+        Err(ConfigFetchIssue::NotCached) => Err((
+            ProblemKind::Unreachable,
+            format!("'{url}' is not cached, and we are offline"),
+        )),
+        Err(ConfigFetchIssue::RateLimit(_)) => unreachable!("retried above"),
+    }
 }
 
-/// Returns the set of candidates that have a unique repository URL
-fn candidates_with_known_repo(candidates: &BTreeSet<Metadata>) -> BTreeSet<Metadata> {
-    let mut seen_repos = HashSet::new();
+/// Returns the set of candidates that have a unique repository URL and
+/// project directory.
+fn candidates_with_known_repo(
+    candidates: &BTreeSet<Metadata>,
+    report: &mut DiscoveryReport,
+) -> BTreeSet<Metadata> {
+    let mut seen_repos = HashMap::new();
     let mut result = BTreeSet::new();
     for metadata in candidates {
         let Some(url) = metadata.repo_url.as_ref() else {
+            report.push(
+                Some(&metadata.name),
+                None,
+                DiscoveryStage::Metadata,
+                Some(SkipReason::NoRepoUrl),
+                "no repository url in METADATA.pb",
+            );
             continue;
         };
 
-        if seen_repos.insert(url) {
-            result.insert(metadata.clone());
+        match seen_repos.entry((url, metadata.subdir())) {
+            Entry::Vacant(entry) => {
+                entry.insert(&metadata.name);
+                result.insert(metadata.clone());
+            }
+            Entry::Occupied(entry) => report.push(
+                Some(&metadata.name),
+                Some(url),
+                DiscoveryStage::Metadata,
+                Some(SkipReason::DuplicateRepo),
+                format!("the repository is already used by '{}'", entry.get()),
+            ),
         }
     }
     result
 }
 
+/// A font's repository, and the project directory within it.
+///
+/// Fonts with the same key would have the same sources, so each key is only
+/// probed once.
+type ProbeKey = (String, Option<PathBuf>);
+
+fn probe_key(metadata: &Metadata) -> Option<ProbeKey> {
+    Some((metadata.repo_url.clone()?, metadata.subdir()))
+}
+
+/// The sources found, the problems, and the fonts without a config file
+type ProbeResults = (Vec<RepoInfo>, Vec<Problem>, Vec<String>);
+
+/// What probing a font's repository found
+enum ProbeOutcome {
+    Found(RepoInfo),
+    NoConfig,
+    Problem(Problem),
+}
+
+// messages sent to the thread running `find_config_files`
+enum ProbeMessage<T> {
+    // from the scan of google/fonts
+    Candidate(Metadata),
+    Scanned(Result<T, Error>),
+    // from a worker thread
+    Started {
+        repo_url: String,
+        family: String,
+    },
+    Cloned,
+    Done(Box<(Metadata, ProbeOutcome, RepoTiming)>),
+    RateLimit(usize),
+    Retry {
+        repo_url: String,
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+}
+
+/// Where [`find_config_files`] looks for repositories, and who it tells
+/// about them.
+struct ProbeContext<'a> {
+    git_cache_dir: &'a Path,
+    /// whether repositories may be cloned, fetched or probed
+    network: Network,
+    /// which branches are fetched when a repository is cloned
+    branches: CloneBranches,
+    /// the options, for the tokens of private repositories
+    options: &'a DiscoveryOptions,
+    checkpoint_path: &'a Path,
+    observer: &'a dyn Observer,
+}
+
+/// A finished probe, and how long it took
+type ProbeDone = (Metadata, ProbeOutcome, RepoTiming);
+
+/// The scheduling state of [`find_config_files`].
+///
+/// Fonts are queued as the scan of google/fonts finds them, before we know
+/// which of them are wanted, so results can arrive early; these are held
+/// until the scan is finished and the wanted fonts have been selected.
+struct ProbeQueue {
+    /// the fonts wanted, by key, once the scan is finished
+    selected: Option<HashMap<ProbeKey, Metadata>>,
+    /// the keys of every font that was queued, so each is probed once
+    probed: HashSet<ProbeKey>,
+    /// fonts waiting for a probe to be started
+    pending: Vec<Metadata>,
+    /// results that arrived before we knew which fonts were wanted
+    held: Vec<ProbeDone>,
+    /// fonts being probed, or waiting to be
+    outstanding: usize,
+    scanning: bool,
+}
+
+impl ProbeQueue {
+    fn new() -> Self {
+        ProbeQueue {
+            selected: None,
+            probed: HashSet::new(),
+            pending: Vec::new(),
+            held: Vec::new(),
+            outstanding: 0,
+            scanning: true,
+        }
+    }
+
+    /// `true` once the scan is finished and every probe has completed
+    fn is_finished(&self) -> bool {
+        !self.scanning && self.outstanding == 0
+    }
+
+    /// Queue a font, unless one with the same [`ProbeKey`] already was.
+    ///
+    /// Returns `true` if the font was queued.
+    fn push(&mut self, metadata: Metadata) -> bool {
+        let Some(key) = probe_key(&metadata) else {
+            return false;
+        };
+        if !self.probed.insert(key) {
+            return false;
+        }
+        self.outstanding += 1;
+        self.pending.push(metadata);
+        true
+    }
+
+    /// The scan is finished, and these are the fonts that are wanted.
+    ///
+    /// Returns the results that were held until now.
+    fn select(&mut self, wanted: &BTreeSet<Metadata>) -> Vec<ProbeDone> {
+        self.scanning = false;
+        self.selected = Some(
+            wanted
+                .iter()
+                .filter_map(|metadata| Some((probe_key(metadata)?, metadata.clone())))
+                .collect(),
+        );
+        std::mem::take(&mut self.held)
+    }
+
+    /// The scan failed: probes that were started are finished, but nothing
+    /// else is probed and no results are wanted.
+    fn abandon(&mut self) {
+        self.scanning = false;
+        self.outstanding -= self.pending.len();
+        self.pending.clear();
+        self.selected = Some(HashMap::new());
+        self.held.clear();
+    }
+
+    /// A probe is finished.
+    ///
+    /// The result is returned if we know which fonts are wanted, and held
+    /// otherwise.
+    fn finish(&mut self, done: ProbeDone) -> Option<ProbeDone> {
+        self.outstanding -= 1;
+        if self.selected.is_none() {
+            self.held.push(done);
+            return None;
+        }
+        Some(done)
+    }
+
+    /// The selected font that a finished probe is for.
+    ///
+    /// Fonts in the same project share a probe, which may have been started
+    /// for one that was not selected.
+    fn wanted(&self, probed: &Metadata) -> Option<&Metadata> {
+        self.selected.as_ref()?.get(&probe_key(probed)?)
+    }
+}
+
 /// for each font for which we have metadata, check remote repository for a config file.
 ///
 /// By convention repositories containing sources we use should have a config file
@@ -144,129 +1302,445 @@ fn candidates_with_known_repo(candidates: &BTreeSet<Metadata>) -> BTreeSet<Metad
 /// We naively look for the most common file names using a simple http request,
 /// and if we don't find anything then we clone the repo locally and inspect
 /// its contents.
-fn find_config_files(fonts: &BTreeSet<Metadata>, git_cache_dir: &Path) -> Vec<RepoInfo> {
-    let n_has_repo = fonts.iter().filter(|md| md.repo_url.is_some()).count();
-
-    // messages sent from a worker thread
-    enum Message {
-        Finished(Option<RepoInfo>),
-        ErrorMsg(String),
-        RateLimit(usize),
-    }
-
-    rayon::scope(|s| {
-        let mut result = Vec::new();
-        let mut seen = 0;
-        let mut sent = 0;
-        let mut progressbar = kdam::tqdm!(total = n_has_repo);
-        let rate_limited = Arc::new(AtomicBool::new(false));
-
-        let (tx, rx) = channel();
-        for repo_url in fonts.iter().filter_map(|meta| meta.repo_url.clone()) {
-            let tx = tx.clone();
-            let rate_limited = rate_limited.clone();
-            s.spawn(move |_| {
-                loop {
-                    // first, if we're currently rate-limited we spin:
-                    while rate_limited.load(Ordering::Acquire) {
-                        std::thread::sleep(Duration::from_secs(1));
+///
+/// The fonts are found by `scan`, which runs on its own thread while
+/// repositories are probed: each font it passes to its argument is probed
+/// right away, so probing doesn't wait for all of google/fonts to be read.
+/// When the scan is finished, `select` is given its result and returns the
+/// fonts that are actually wanted. Any of those that were not already probed
+/// are probed then, and the results for fonts that were probed but not
+/// selected are dropped. Fonts with the same [`ProbeKey`] share one probe.
+///
+/// Fonts that are skipped because of an error are returned as [`Problem`]s,
+/// and the names of those whose repository has no config file are returned
+/// last.
+fn find_config_files<T: Send>(
+    scan: impl FnOnce(&(dyn Fn(&Metadata) + Sync)) -> Result<T, Error> + Send,
+    select: impl FnOnce(T) -> BTreeSet<Metadata>,
+    cx: &ProbeContext,
+    checkpoint: &mut Checkpoint,
+    metrics: &mut Metrics,
+) -> Result<ProbeResults, Error> {
+    // with a token, we can ask about many github repos in one request,
+    // instead of asking each for its current rev
+    let github_token = github::token().filter(|_| cx.network.can_clone());
+    let (tx, rx) = channel::<ProbeMessage<T>>();
+
+    std::thread::scope(|threads| {
+        let scan_tx = tx.clone();
+        threads.spawn(move || {
+            let found = |metadata: &Metadata| {
+                let _ = scan_tx.send(ProbeMessage::Candidate(metadata.clone()));
+            };
+            let result = scan(&found);
+            let _ = scan_tx.send(ProbeMessage::Scanned(result));
+        });
+
+        // this loop runs on the calling thread, not in the rayon pool, so the
+        // workers can make progress even if the pool only has one thread
+        rayon::in_place_scope(|s| {
+            let mut results = (Vec::new(), Vec::new(), Vec::new());
+            let mut progress = progress::Progress::new(0, "probing repositories");
+            let rate_limited = Arc::new(AtomicBool::new(false));
+            // projects in the same repo share a checkout, so are probed one at a time
+            let mut repo_locks = HashMap::<String, Arc<Mutex<()>>>::new();
+            let mut spawn_probe = |metadata: Metadata, head: Option<GitRev>| {
+                let repo_url = metadata.repo_url.clone().unwrap_or_default();
+                let lock = repo_locks.entry(repo_url).or_default().clone();
+                let tx = tx.clone();
+                let rate_limited = rate_limited.clone();
+                s.spawn(move |_| {
+                    let _checkout = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                    probe_font(metadata, head, cx, &rate_limited, &tx);
+                });
+            };
+
+            let mut select = Some(select);
+            let mut queue = ProbeQueue::new();
+            let mut scan_error = None;
+            while !queue.is_finished() {
+                let mut new_fonts = Vec::new();
+                let finished = match rx.recv() {
+                    Ok(ProbeMessage::Candidate(metadata)) => {
+                        new_fonts.push(metadata);
+                        Vec::new()
                     }
-                    // then try to get configs (which may trigger rate limiting)
-                    match config_files_and_rev_for_repo(&repo_url, git_cache_dir) {
-                        Ok((config_files, rev)) if !config_files.is_empty() => {
-                            let info = RepoInfo::new(repo_url, rev, config_files);
-                            tx.send(Message::Finished(info)).unwrap();
-                            break;
-                        }
-                        // no configs found or looking for configs failed:
-                        Err(ConfigFetchIssue::NoConfigFound) | Ok(_) => {
-                            tx.send(Message::Finished(None)).unwrap();
-                            break;
-                        }
-                        // if we're rate limited, set the flag telling other threads
-                        // to spin, sleep, and then unset the flag
-                        Err(ConfigFetchIssue::RateLimit(backoff)) => {
-                            if !rate_limited.swap(true, Ordering::Acquire) {
-                                tx.send(Message::RateLimit(backoff)).unwrap();
-                                std::thread::sleep(Duration::from_secs(backoff as _));
-                                rate_limited.store(false, Ordering::Release);
-                            }
-                        }
-                        Err(e) => {
-                            let msg = match e {
-                                ConfigFetchIssue::BadRepoUrl(s) => s,
-                                ConfigFetchIssue::GitFail(e) => e.to_string(),
-                                ConfigFetchIssue::Http(e) => e.to_string(),
-                                _ => unreachable!(), // handled above
-                            };
-                            tx.send(Message::ErrorMsg(msg)).unwrap();
-                            break;
-                        }
+                    Ok(ProbeMessage::Scanned(Ok(scanned))) => {
+                        let mut wanted = (select.take().unwrap())(scanned);
+                        wanted.retain(|metadata| !checkpoint.done.contains(&metadata.name));
+                        log::info!(
+                            "checking {} repositories for config.yaml files",
+                            wanted.len()
+                        );
+                        let held = queue.select(&wanted);
+                        new_fonts.extend(wanted);
+                        held
                     }
-                }
-            });
-            sent += 1;
-        }
+                    Ok(ProbeMessage::Scanned(Err(e))) => {
+                        // finish the probes that were started, but nothing else
+                        scan_error = Some(e);
+                        queue.abandon();
+                        Vec::new()
+                    }
+                    Ok(ProbeMessage::Done(done)) => {
+                        let (metadata, outcome, timing) = *done;
+                        progress.finish_one();
+                        cx.observer.on_event(&Event::RepoCompleted {
+                            repo_url: metadata.repo_url.as_deref().unwrap_or_default(),
+                            family: Some(&metadata.name),
+                            ok: !matches!(outcome, ProbeOutcome::Problem(_)),
+                        });
+                        queue
+                            .finish((metadata, outcome, timing))
+                            .into_iter()
+                            .collect()
+                    }
+                    Ok(message) => {
+                        report_probe_progress(message, cx.observer, &mut progress);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("channel error: '{e}'");
+                        break;
+                    }
+                };
 
-        while seen < sent {
-            match rx.recv() {
-                Ok(Message::Finished(info)) => {
-                    if let Some(info) = info {
-                        result.push(info);
+                for metadata in new_fonts {
+                    if !checkpoint.done.contains(&metadata.name) && queue.push(metadata) {
+                        progress.add_to_total(1);
                     }
-                    seen += 1;
                 }
-                Ok(Message::RateLimit(seconds)) => {
-                    progressbar
-                        .write(format!(
-                            "rate limit hit, cooling down for {seconds} seconds"
-                        ))
-                        .unwrap();
-                    let mut limit_progress = tqdm!(
-                        total = seconds,
-                        desc = "cooldown",
-                        position = 1,
-                        leave = false,
-                        bar_format = "{desc}|{animation}| {count}/{total}"
+                start_probes(
+                    &mut queue.pending,
+                    github_token.as_deref(),
+                    !queue.scanning,
+                    &mut spawn_probe,
+                );
+
+                for (probed, outcome, mut timing) in finished {
+                    let Some(metadata) = queue.wanted(&probed) else {
+                        continue;
+                    };
+                    timing.family = metadata.name.clone();
+                    metrics.repos.push(timing);
+                    record_probe(
+                        metadata,
+                        outcome,
+                        &mut results,
+                        cx,
+                        checkpoint,
+                        &mut progress,
                     );
-                    for _ in 0..seconds {
-                        std::thread::sleep(Duration::from_secs(1));
-                        limit_progress.update(1).unwrap();
-                    }
-                }
-                Ok(Message::ErrorMsg(msg)) => {
-                    progressbar.write(msg).unwrap();
-                    seen += 1;
-                }
-                Err(e) => {
-                    log::error!("channel error: '{e}'");
-                    break;
                 }
             }
-            progressbar.update(1).unwrap();
-        }
-        result
+            match scan_error {
+                Some(e) => Err(e),
+                None => Ok(results),
+            }
+        })
     })
 }
 
-/// Conditions under which we fail to find a config.
-///
-/// different conditions are handled differently; NoConfigFound is fine,
-/// RateLimit means we need to wait and retry, other things are errors we report
-#[derive(Debug)]
-enum ConfigFetchIssue {
-    NoConfigFound,
-    RateLimit(usize),
+/// Pass on a message about a probe that is still running.
+fn report_probe_progress<T>(
+    message: ProbeMessage<T>,
+    observer: &dyn Observer,
+    progress: &mut progress::Progress,
+) {
+    match message {
+        ProbeMessage::Started { repo_url, family } => {
+            progress.set_current(&repo_url);
+            observer.on_event(&Event::RepoStarted {
+                repo_url: &repo_url,
+                family: Some(&family),
+            });
+        }
+        ProbeMessage::Cloned => progress.cloned(),
+        ProbeMessage::Retry {
+            repo_url,
+            attempt,
+            delay,
+            error,
+        } => {
+            log::debug!(
+                repo = repo_url, stage = "probe";
+                "attempt {attempt} for {repo_url} failed, retrying in {}s: {error}",
+                delay.as_secs()
+            );
+            observer.on_event(&Event::Retry {
+                repo_url: &repo_url,
+                attempt,
+                delay,
+                error,
+            });
+        }
+        ProbeMessage::RateLimit(seconds) => {
+            observer.on_event(&Event::Warning {
+                repo_url: None,
+                message: format!("rate limited, waiting {seconds} seconds"),
+            });
+            progress.cool_down(seconds);
+        }
+        ProbeMessage::Candidate(_) | ProbeMessage::Scanned(_) | ProbeMessage::Done(_) => {
+            unreachable!("not about a running probe")
+        }
+    }
+}
+
+/// Add the outcome of probing the repository of a wanted font to `results`,
+/// and to the checkpoint.
+fn record_probe(
+    metadata: &Metadata,
+    outcome: ProbeOutcome,
+    (found, problems, no_config): &mut ProbeResults,
+    cx: &ProbeContext,
+    checkpoint: &mut Checkpoint,
+    progress: &mut progress::Progress,
+) {
+    match outcome {
+        ProbeOutcome::Found(info) => {
+            let info = info.with_metadata(metadata);
+            checkpoint.finished(&metadata.name, Some(&info));
+            found.push(info);
+        }
+        ProbeOutcome::NoConfig => {
+            checkpoint.finished(&metadata.name, None);
+            no_config.push(metadata.name.clone());
+        }
+        ProbeOutcome::Problem(mut problem) => {
+            problem.family_name = Some(metadata.name.clone());
+            progress.write(&problem.message);
+            log::warn!(
+                repo = problem.repo_url.as_deref(), stage = "probe";
+                "{}", problem
+            );
+            cx.observer.on_event(&Event::Error(&problem));
+            checkpoint.problem(&problem);
+            problems.push(problem);
+        }
+    }
+    checkpoint.save_periodically(cx.checkpoint_path);
+}
+
+/// Look for config files in the repository of a single font, on a worker
+/// thread, reporting progress and the outcome through `tx`.
+///
+/// If any probe is rate limited, every worker waits until the limit is over.
+fn probe_font<T>(
+    metadata: Metadata,
+    head: Option<GitRev>,
+    cx: &ProbeContext,
+    rate_limited: &AtomicBool,
+    tx: &Sender<ProbeMessage<T>>,
+) {
+    let repo_url = metadata.repo_url.clone().unwrap_or_default();
+    let subdir = metadata.subdir();
+    let token_env = cx.options.token_env(&repo_url);
+    let credentials = token_env
+        .and_then(|var| Credentials::from_env(&repo_url, var, |var| std::env::var(var).ok()));
+    tx.send(ProbeMessage::Started {
+        repo_url: repo_url.clone(),
+        family: metadata.name.clone(),
+    })
+    .unwrap();
+    let is_cached = || {
+        repo_info::repo_path_for_url(&repo_url, cx.git_cache_dir)
+            .is_some_and(|path| path.join(".git").exists())
+    };
+    let was_cached = is_cached();
+    let started = Instant::now();
+    metrics::take_phases();
+    let mut attempt = 0;
+    let outcome = loop {
+        attempt += 1;
+        // first, if we're currently rate-limited we spin:
+        while rate_limited.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        // then try to get configs (which may trigger rate limiting)
+        let result = config_files_and_rev_for_repo(
+            &repo_url,
+            subdir.as_deref(),
+            cx.git_cache_dir,
+            cx.network,
+            cx.branches,
+            head.as_deref(),
+            credentials.as_ref(),
+        );
+        if !was_cached && is_cached() {
+            tx.send(ProbeMessage::Cloned).unwrap();
+        }
+        match result {
+            Ok((config_files, rev)) if !config_files.is_empty() => {
+                break match RepoInfo::new(repo_url.clone(), rev, config_files) {
+                    Some(mut info) => {
+                        info.token_env = token_env.map(str::to_owned);
+                        ProbeOutcome::Found(info)
+                    }
+                    None => ProbeOutcome::Problem(problem(
+                        &metadata,
+                        ProblemKind::UnfamiliarUrl,
+                        format!("unexpected repo url '{repo_url}'"),
+                    )),
+                };
+            }
+            // no configs found or looking for configs failed:
+            Err(ConfigFetchIssue::NoConfigFound) | Ok(_) => {
+                break ProbeOutcome::NoConfig;
+            }
+            // if we're rate limited, set the flag telling other threads
+            // to spin, sleep, and then unset the flag
+            Err(ConfigFetchIssue::RateLimit(backoff)) => {
+                tx.send(ProbeMessage::Retry {
+                    repo_url: repo_url.clone(),
+                    attempt,
+                    delay: Duration::from_secs(backoff as _),
+                    error: "rate limited (http status 429)".into(),
+                })
+                .unwrap();
+                if !rate_limited.swap(true, Ordering::Acquire) {
+                    tx.send(ProbeMessage::RateLimit(backoff)).unwrap();
+                    std::thread::sleep(Duration::from_secs(backoff as _));
+                    rate_limited.store(false, Ordering::Release);
+                }
+            }
+            Err(e) => {
+                let code = e.code();
+                let (kind, msg) = match e {
+                    ConfigFetchIssue::BadRepoUrl(s) => (
+                        ProblemKind::UnfamiliarUrl,
+                        format!("unexpected repo url '{s}'"),
+                    ),
+                    ConfigFetchIssue::NoCommit(e) => (ProblemKind::MissingCommit, e.to_string()),
+                    ConfigFetchIssue::GitFail(e) => (ProblemKind::ProbeFailed, e.to_string()),
+                    ConfigFetchIssue::Http(e) => (ProblemKind::ProbeFailed, e.to_string()),
+                    ConfigFetchIssue::NotCached => (
+                        ProblemKind::Unreachable,
+                        format!("'{repo_url}' is not cached, and we are offline"),
+                    ),
+                    _ => unreachable!(), // handled above
+                };
+                let problem = problem(&metadata, kind, msg).with_code(code);
+                break ProbeOutcome::Problem(problem);
+            }
+        }
+    };
+    let timing = RepoTiming {
+        repo_url,
+        family: metadata.name.clone(),
+        elapsed: started.elapsed(),
+        phases: metrics::take_phases(),
+    };
+    let done = (metadata, outcome, timing);
+    tx.send(ProbeMessage::Done(Box::new(done))).unwrap();
+}
+
+/// Start probing the `pending` fonts.
+///
+/// With a github token, fonts in github repositories wait until there are
+/// enough of them to fill a query of their state (or until `all`), since
+/// the state lets us skip asking each repository for its current rev.
+fn start_probes(
+    pending: &mut Vec<Metadata>,
+    github_token: Option<&str>,
+    all: bool,
+    spawn: &mut dyn FnMut(Metadata, Option<GitRev>),
+) {
+    let Some(token) = github_token else {
+        pending.drain(..).for_each(|metadata| spawn(metadata, None));
+        return;
+    };
+    let is_github = |metadata: &Metadata| {
+        metadata
+            .repo_url
+            .as_deref()
+            .is_some_and(|url| repo_info::host_for_url(url) == RepoHost::GitHub)
+    };
+    let (github, other): (Vec<_>, Vec<_>) = pending.drain(..).partition(is_github);
+    other.into_iter().for_each(|metadata| spawn(metadata, None));
+    if !all && github.len() < github::BATCH_SIZE {
+        *pending = github;
+        return;
+    }
+    let states =
+        github::query_repo_states(github.iter().filter_map(|md| md.repo_url.as_deref()), token);
+    for metadata in github {
+        let repo_url = metadata.repo_url.as_deref().unwrap_or_default();
+        let state = states.get(repo_url);
+        if let Some(state) = state.filter(|state| state.was_moved_from(repo_url)) {
+            log::warn!(
+                repo = repo_url, stage = "probe";
+                "{repo_url} has moved to {}; its METADATA.pb should be updated",
+                state.url
+            );
+        }
+        if state.is_some_and(|state| state.archived) {
+            log::info!(repo = repo_url, stage = "probe"; "{repo_url} is archived");
+        }
+        // only useful when we don't have a checkout to look at
+        let head = state.and_then(|state| state.head.clone());
+        spawn(metadata, head);
+    }
+}
+
+fn problem(metadata: &Metadata, kind: ProblemKind, message: String) -> Problem {
+    Problem {
+        family_name: Some(metadata.name.clone()),
+        path: None,
+        repo_url: metadata.repo_url.clone(),
+        kind,
+        code: Some(kind.code()),
+        message: credentials::redact(&message),
+    }
+}
+
+/// Conditions under which we fail to find a config.
+///
+/// different conditions are handled differently; NoConfigFound is fine,
+/// RateLimit means we need to wait and retry, other things are errors we report
+#[derive(Debug)]
+enum ConfigFetchIssue {
+    NoConfigFound,
+    RateLimit(usize),
     BadRepoUrl(String),
+    // the current rev of the repo could not be determined
+    NoCommit(GitFail),
     // contains stderr
     GitFail(GitFail),
     Http(Box<ureq::Error>),
+    // the repo is not checked out, and we can't clone it
+    NotCached,
+}
+
+impl ConfigFetchIssue {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ConfigFetchIssue::NoConfigFound => ErrorCode::NoConfig,
+            ConfigFetchIssue::RateLimit(_) | ConfigFetchIssue::Http(_) => ErrorCode::Http,
+            ConfigFetchIssue::BadRepoUrl(_) => ErrorCode::UnknownHost,
+            ConfigFetchIssue::NoCommit(_) => ErrorCode::NoCommit,
+            ConfigFetchIssue::GitFail(_) => ErrorCode::Git,
+            ConfigFetchIssue::NotCached => ErrorCode::NotCached,
+        }
+    }
 }
 
 /// Checks for a config file in a given repo; also returns git rev
+///
+/// If `subdir` is provided, the config is expected in `{subdir}/sources`.
+///
+/// `network` controls whether the repository may be cloned, fetched or
+/// probed over http. If the current `head` of the repository is already
+/// known, it is used instead of asking the remote when probing over http.
+/// A repository with `credentials` is always cloned.
 fn config_files_and_rev_for_repo(
     repo_url: &str,
+    subdir: Option<&Path>,
     checkout_font_dir: &Path,
+    network: Network,
+    branches: CloneBranches,
+    head: Option<&str>,
+    credentials: Option<&Credentials>,
 ) -> Result<(Vec<PathBuf>, GitRev), ConfigFetchIssue> {
     let local_repo_dir = repo_info::repo_path_for_url(repo_url, checkout_font_dir)
         .ok_or_else(|| ConfigFetchIssue::BadRepoUrl(repo_url.to_owned()))?;
@@ -274,50 +1748,98 @@ fn config_files_and_rev_for_repo(
     // - otherwise try naive http requests first,
     // - and then finally clone the repo and look
     let local_git_dir = local_repo_dir.join(".git");
-    let skip_http = local_git_dir.exists();
+    // http probing only works for hosts where we know how to request a single
+    // file, and doesn't send tokens
+    let skip_http = !network.can_clone()
+        || local_git_dir.exists()
+        || credentials.is_some()
+        || repo_info::host_for_url(repo_url) == RepoHost::Other;
 
     if !skip_http {
-        let config_from_http =
-            config_file_and_rev_from_remote_http(repo_url).map(|(p, rev)| (vec![p], rev));
+        let cache = HttpCache::in_dir(checkout_font_dir);
+        let config_from_http = metrics::timed(Phase::Probe, || {
+            config_file_and_rev_from_remote_http(repo_url, subdir, head, &cache)
+        })
+        .map(|(p, rev)| (vec![p], rev));
         // if not found, try checking out and looking; otherwise return the result
         if !matches!(config_from_http, Err(ConfigFetchIssue::NoConfigFound)) {
             return config_from_http;
         }
     }
-    let configs = config_files_from_local_checkout(repo_url, &local_repo_dir)?;
-    let rev = get_git_rev(&local_repo_dir).map_err(ConfigFetchIssue::GitFail)?;
+    let configs = config_files_from_local_checkout(
+        repo_url,
+        &local_repo_dir,
+        subdir,
+        network,
+        branches,
+        credentials,
+    )?;
+    let rev = metrics::timed(Phase::Checkout, || get_git_rev(&local_repo_dir))
+        .map_err(ConfigFetchIssue::NoCommit)?;
     Ok((configs, rev))
 }
 
 fn config_file_and_rev_from_remote_http(
     repo_url: &str,
+    subdir: Option<&Path>,
+    head: Option<&str>,
+    cache: &HttpCache,
 ) -> Result<(PathBuf, GitRev), ConfigFetchIssue> {
-    config_file_from_remote_http(repo_url)
-        .and_then(|config| get_git_rev_remote(repo_url).map(|rev| (config, rev)))
+    // find the rev first, so that we look for the config in that commit
+    let rev = match head {
+        Some(head) => head.to_owned(),
+        None => get_git_rev_remote(repo_url).map_err(ConfigFetchIssue::NoCommit)?,
+    };
+    config_file_from_remote_http(repo_url, &rev, subdir, cache).map(|config| (config, rev))
 }
 
-// just check for the presence of the most common file names
-fn config_file_from_remote_http(repo_url: &str) -> Result<PathBuf, ConfigFetchIssue> {
+// just check for the presence of the most common file names, by requesting
+// the raw file at `rev` (without downloading it). Since the file can't change
+// at a given rev, the responses are cached.
+fn config_file_from_remote_http(
+    repo_url: &str,
+    rev: &str,
+    subdir: Option<&Path>,
+    cache: &HttpCache,
+) -> Result<PathBuf, ConfigFetchIssue> {
+    let sources_dir = subdir.unwrap_or(Path::new("")).join("sources");
     for filename in ["config.yaml", "config.yml"] {
-        let config_url = format!("{repo_url}/tree/HEAD/sources/{filename}");
-        let req = ureq::head(&config_url);
-
-        match req.call() {
-            Ok(resp) if resp.status() == 200 => return Ok(filename.into()),
+        let Some(config_url) = repo_info::raw_file_url(repo_url, rev, &sources_dir.join(filename))
+        else {
+            // we can't request single files from this host; clone instead
+            return Err(ConfigFetchIssue::NoConfigFound);
+        };
+        match cache.head(&config_url) {
+            Ok(resp) if resp.status == 404 => (),
+            // gitlab redirects to a sign-in page (instead of returning 404)
+            // for projects that are private or don't exist; so the file may
+            // still be there, and we need to clone to find out
+            Ok(resp) if repo_info::looks_like_html(&resp.content_type, "") => {
+                log::debug!(
+                    repo = repo_url, stage = "probe";
+                    "received html for {config_url}, will clone {repo_url}"
+                );
+                return Err(ConfigFetchIssue::NoConfigFound);
+            }
+            Ok(resp) if resp.status == 200 => return Ok(filename.into()),
             Ok(resp) => {
                 // seems very unlikely but it feels bad to just skip this branch?
-                log::warn!("unexpected response code for {repo_url}: {}", resp.status());
-            }
-            Err(ureq::Error::Status(404, _)) => (),
-            Err(ureq::Error::Status(429, resp)) => {
-                let backoff = resp
-                    .header("Retry-After")
-                    .and_then(|s| s.parse::<usize>().ok())
-                    .unwrap_or(60);
-                return Err(ConfigFetchIssue::RateLimit(backoff));
+                log::warn!(
+                    repo = repo_url, stage = "probe";
+                    "unexpected response code for {repo_url}: {}", resp.status
+                );
             }
             Err(e) => {
-                return Err(ConfigFetchIssue::Http(Box::new(e)));
+                return Err(match *e {
+                    ureq::Error::Status(429, resp) => {
+                        let backoff = resp
+                            .header("Retry-After")
+                            .and_then(|s| s.parse::<usize>().ok())
+                            .unwrap_or(60);
+                        ConfigFetchIssue::RateLimit(backoff)
+                    }
+                    e => ConfigFetchIssue::Http(Box::new(e)),
+                });
             }
         }
     }
@@ -327,16 +1849,34 @@ fn config_file_from_remote_http(repo_url: &str) -> Result<PathBuf, ConfigFetchIs
 fn config_files_from_local_checkout(
     repo_url: &str,
     local_repo_dir: &Path,
+    subdir: Option<&Path>,
+    network: Network,
+    branches: CloneBranches,
+    credentials: Option<&Credentials>,
 ) -> Result<Vec<PathBuf>, ConfigFetchIssue> {
     if local_repo_dir.exists() {
-        // try fetch; but failure is okay
-        let _ = fetch_latest(local_repo_dir);
-        // should we always fetch? idk
+        if network.can_fetch() {
+            // try fetch; but failure is okay
+            let _ = metrics::timed(Phase::Checkout, || {
+                fetch_latest(local_repo_dir, credentials)
+            });
+            // should we always fetch? idk
+        }
+    } else if !network.can_clone() {
+        return Err(ConfigFetchIssue::NotCached);
     } else {
         std::fs::create_dir_all(local_repo_dir).unwrap();
-        clone_repo(repo_url, local_repo_dir).map_err(ConfigFetchIssue::GitFail)?;
+        metrics::timed(Phase::Clone, || {
+            clone_repo(repo_url, local_repo_dir, branches, None, credentials)
+        })
+        .map_err(ConfigFetchIssue::GitFail)?;
     }
-    let configs: Vec<_> = iter_config_paths(local_repo_dir)?.collect();
+    let project_dir = match subdir {
+        Some(subdir) => local_repo_dir.join(subdir),
+        None => local_repo_dir.to_owned(),
+    };
+    let configs: Vec<_> =
+        metrics::timed(Phase::Checkout, || iter_config_paths(&project_dir))?.collect();
     if configs.is_empty() {
         Err(ConfigFetchIssue::NoConfigFound)
     } else {
@@ -387,48 +1927,182 @@ fn find_sources_dir(font_dir: &Path) -> Option<PathBuf> {
     None
 }
 
-fn update_google_fonts_checkout(path: &Path) -> Result<(), Error> {
+fn update_google_fonts_checkout(path: &Path, branches: CloneBranches) -> Result<(), Error> {
     if !path.exists() {
         log::info!("cloning {GF_REPO_URL} to {}", path.display());
         std::fs::create_dir_all(path)?;
-        clone_repo(GF_REPO_URL, path)?;
+        clone_repo(GF_REPO_URL, path, branches, None, None)?;
     } else {
-        fetch_latest(path)?;
+        fetch_latest(path, None)?;
     }
     Ok(())
 }
 
-fn get_candidates_from_local_checkout(path: &Path) -> BTreeSet<Metadata> {
-    let ofl_dir = path.join("ofl");
-    log::debug!("searching for candidates in {}", ofl_dir.display());
-    let mut result = BTreeSet::new();
-    for font_dir in iter_ofl_subdirectories(&ofl_dir) {
-        let metadata = match load_metadata(&font_dir) {
-            Ok(metadata) => metadata,
-            Err(e) => {
-                log::debug!("no metadata for font {}: '{}'", font_dir.display(), e);
+// the time spent reading and parsing each family's metadata
+type ParseTimes = HashMap<String, Duration>;
+
+/// Load the metadata of every font in google/fonts.
+///
+/// If `only` is provided, only the font directories it contains (relative to
+/// the root of google/fonts) are considered.
+///
+/// The metadata files are read and parsed in parallel, using the global rayon
+/// thread pool. If an `index_path` is provided, the [`MetadataIndex`] there is
+/// used to skip parsing files that have not changed since the last run, and is
+/// then updated.
+///
+/// Also returns the time spent reading and parsing each family's metadata.
+fn get_candidates_from_local_checkout(
+    path: &Path,
+    only: Option<&HashSet<PathBuf>>,
+    index_path: Option<&Path>,
+) -> Result<(BTreeSet<Metadata>, Vec<Problem>, ParseTimes), Error> {
+    scan_candidates(path, only, index_path, &|_| ())
+}
+
+/// Load the metadata of every font, as with
+/// [`get_candidates_from_local_checkout`], calling `on_found` with each font
+/// as soon as its metadata has been parsed.
+///
+/// `on_found` is called from whichever thread parsed the metadata, in no
+/// particular order.
+fn scan_candidates(
+    path: &Path,
+    only: Option<&HashSet<PathBuf>>,
+    index_path: Option<&Path>,
+    on_found: &(dyn Fn(&Metadata) + Sync),
+) -> Result<(BTreeSet<Metadata>, Vec<Problem>, ParseTimes), Error> {
+    let mut font_dirs = Vec::new();
+    for license in License::ALL {
+        let license_dir = path.join(license.dir_name());
+        log::debug!(
+            target: logging::METADATA,
+            "searching for candidates in {}",
+            license_dir.display()
+        );
+        for font_dir in iter_license_subdirectories(&license_dir)? {
+            let relative = font_dir.strip_prefix(path).unwrap_or(&font_dir);
+            if only.is_some_and(|only| !only.contains(relative)) {
                 continue;
             }
-        };
-        result.insert(metadata);
+            font_dirs.push((license, relative.to_owned()));
+        }
     }
-    result
+
+    let mut index = index_path.map(MetadataIndex::load).unwrap_or_default();
+    let loaded = font_dirs
+        .par_iter()
+        .map(|(license, relative)| {
+            let font_dir = path.join(relative);
+            let started = Instant::now();
+            let result = std::fs::read(font_dir.join(METADATA_FILE))
+                .map_err(MetadataError::Read)
+                .map(|bytes| index.parse(relative, bytes));
+            match result {
+                Ok((sha256, Ok(mut metadata))) => {
+                    metadata.license = Some(*license);
+                    let elapsed = started.elapsed();
+                    on_found(&metadata);
+                    Some(Ok((relative, sha256, metadata, elapsed)))
+                }
+                // not every directory is a font
+                Err(MetadataError::Read(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::debug!(
+                        target: logging::METADATA,
+                        "no metadata for font {}: '{}'",
+                        font_dir.display(),
+                        e
+                    );
+                    None
+                }
+                Err(e) | Ok((_, Err(e))) => {
+                    log::warn!(
+                        target: logging::METADATA,
+                        "bad metadata for font {}: '{}'",
+                        font_dir.display(),
+                        e
+                    );
+                    Some(Err(Problem {
+                        family_name: None,
+                        path: Some(relative.clone()),
+                        repo_url: None,
+                        kind: ProblemKind::BadMetadata,
+                        code: Some(ErrorCode::BadMetadata),
+                        message: e.to_string(),
+                    }))
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = BTreeSet::new();
+    let mut problems = Vec::new();
+    let mut parse_times = HashMap::new();
+    for item in loaded.into_iter().flatten() {
+        match item {
+            Ok((relative, sha256, metadata, elapsed)) => {
+                index.insert(relative.clone(), sha256, &metadata);
+                parse_times.insert(metadata.name.clone(), elapsed);
+                result.insert(metadata);
+            }
+            Err(problem) => problems.push(problem),
+        }
+    }
+    if let Some(index_path) = index_path {
+        if only.is_none() {
+            let mut dirs = font_dirs
+                .into_iter()
+                .map(|(_, dir)| dir)
+                .collect::<Vec<_>>();
+            dirs.sort();
+            index.retain(&dirs);
+        }
+        if let Err(e) = index.save(index_path) {
+            log::warn!(
+                "failed to save metadata index '{}': {e}",
+                index_path.display()
+            );
+        }
+    }
+    Ok((result, problems, parse_times))
+}
+
+/// Return the font directories in google/fonts whose metadata has changed
+/// since `since`, which is either a rev or a date.
+///
+/// Paths are relative to the root of the repository, e.g. `ofl/abeezee`.
+fn metadata_changed_since(repo_dir: &Path, since: &str) -> Result<HashSet<PathBuf>, GitFail> {
+    let range = match resolve_rev(repo_dir, since)? {
+        Some(rev) => vec![format!("{rev}..HEAD")],
+        None => vec![format!("--since={since}"), "HEAD".to_owned()],
+    };
+    let args = ["log", "--name-only", "--format="]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(range)
+        .chain(["--".to_owned(), format!("*/{METADATA_FILE}")]);
+    let stdout = run_git(repo_dir, args)?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| Path::new(line.trim()).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(Path::to_owned)
+        .collect())
 }
 
-fn get_git_rev_remote(repo_url: &str) -> Result<GitRev, ConfigFetchIssue> {
-    let output = std::process::Command::new("git")
-        .arg("ls-remote")
-        .arg(repo_url)
-        .arg("HEAD")
-        .output()
-        .expect("should not fail if we found configs at this path");
+/// Get the sha of the tip of the default branch of a remote repository.
+///
+/// This uses `git ls-remote`, and so does not require a local checkout.
+fn get_git_rev_remote(repo_url: &str) -> Result<GitRev, GitFail> {
+    let mut cmd = git_command();
+    cmd.arg("ls-remote").arg(repo_url).arg("HEAD");
+    let output = cmd.output()?;
+
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let sha = stdout
-        .split_whitespace()
-        .next()
-        .map(String::from)
-        .unwrap_or_else(|| stdout.into_owned());
-    Ok(sha)
+    match stdout.split_whitespace().next() {
+        Some(sha) if output.status.success() => Ok(sha.to_owned()),
+        _ => Err(GitFail::command_failed(repo_url, &cmd, &output)),
+    }
 }
 
 /// Get the short sha of the current commit in the provided repository.
@@ -438,17 +2112,13 @@ fn get_git_rev_remote(repo_url: &str) -> Result<GitRev, ConfigFetchIssue> {
 /// returns `None` if the `git` command fails (for instance if the path is not
 /// a git repository)
 fn get_git_rev(repo_path: &Path) -> Result<String, GitFail> {
-    let mut cmd = std::process::Command::new("git");
+    let mut cmd = git_command();
     cmd.args(["rev-parse", "--short", "HEAD"])
         .current_dir(repo_path);
     let output = cmd.output()?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(GitFail::GitError {
-            path: repo_path.to_owned(),
-            stderr: stderr.into_owned(),
-        });
+        return Err(GitFail::command_failed(repo_path, &cmd, &output));
     }
 
     Ok(std::str::from_utf8(&output.stdout)
@@ -457,10 +2127,36 @@ fn get_git_rev(repo_path: &Path) -> Result<String, GitFail> {
         .to_owned())
 }
 
+/// Resolve a rev (a sha, tag, branch name, etc) to the full sha of a commit.
+///
+/// Returns `None` if the rev does not name a commit in the local repository.
+fn resolve_rev(repo_dir: &Path, rev: &str) -> Result<Option<GitRev>, GitFail> {
+    let output = git_command()
+        .current_dir(repo_dir)
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{rev}^{{commit}}"))
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+    ))
+}
+
 // try to checkout this rev.
 //
-// returns `true` if successful, `false` otherwise (indicating a git error)
-fn checkout_rev(repo_dir: &Path, rev: &str) -> Result<bool, GitFail> {
+// The rev may be a (possibly abbreviated) sha, or the name of a tag or branch.
+//
+// returns the full sha of the checked out commit if successful, or `None`
+// if the rev could not be found.
+//
+// `credentials` are used when fetching.
+fn checkout_rev(
+    repo_dir: &Path,
+    rev: &str,
+    credentials: Option<&Credentials>,
+) -> Result<Option<GitRev>, GitFail> {
     let sha = get_git_rev(repo_dir)?;
     // the longer str is on the left, so we check if shorter str is a prefix
     let (left, right) = if sha.len() > rev.len() {
@@ -468,79 +2164,272 @@ fn checkout_rev(repo_dir: &Path, rev: &str) -> Result<bool, GitFail> {
     } else {
         (rev, sha.as_str())
     };
-    if left.starts_with(right) {
-        return Ok(true);
+    // only compare shas; a tag or branch name is never a prefix of a sha
+    let is_sha = rev.chars().all(|c| c.is_ascii_hexdigit());
+    if is_sha && left.starts_with(right) {
+        return resolve_rev(repo_dir, "HEAD");
     }
-    log::info!(
-        "repo {} needs fetch for {rev} (at {sha})",
-        repo_dir.display()
-    );
-    // checkouts might be shallow, so unshallow before looking for a rev:
-    let _ = std::process::Command::new("git")
-        .current_dir(repo_dir)
-        .args(["fetch", "--unshallow"])
-        .output();
 
-    let result = std::process::Command::new("git")
+    // if the commit is already in the checkout (as it usually is when the
+    // cache is warm) we can check it out without going to the network; a
+    // branch (or tag) may have moved upstream, so names are always fetched
+    let local = match is_sha && has_commit(repo_dir, rev)? {
+        true => resolve_rev(repo_dir, rev)?,
+        false => None,
+    };
+    let target = match local {
+        Some(target) => target,
+        None => {
+            log::info!(
+                target: logging::GIT,
+                "repo {} needs fetch for {rev} (at {sha})",
+                repo_dir.display()
+            );
+            // checkouts might be shallow, so unshallow before looking for a rev:
+            let _ = git_command_for(credentials)
+                .current_dir(repo_dir)
+                .args(["fetch", "--unshallow"])
+                .output();
+            // and tags are not fetched by default in shallow clones
+            let _ = git_command_for(credentials)
+                .current_dir(repo_dir)
+                .args(["fetch", "--tags", "origin"])
+                .output();
+
+            // single branch clones only fetch the branch (or tag) they were
+            // cloned at, so a sha may be on the default branch or elsewhere,
+            // and a branch name may only exist on the remote
+            let remote_branch = format!("origin/{rev}");
+            let mut wanted = vec!["HEAD".to_owned()];
+            if pinned_branch(rev).is_some() {
+                wanted.push(format!("+refs/heads/{rev}:refs/remotes/{remote_branch}"));
+            } else if is_sha && rev.len() == 40 {
+                wanted.push(rev.to_owned());
+            }
+            for refspec in wanted {
+                let _ = git_command_for(credentials)
+                    .current_dir(repo_dir)
+                    .args(["fetch", "origin", &refspec])
+                    .output();
+            }
+            // the local branch made when cloning isn't updated by fetching,
+            // so the remote branch is preferred
+            match resolve_rev(repo_dir, &remote_branch)?.or(resolve_rev(repo_dir, rev)?) {
+                Some(target) => target,
+                None => {
+                    log::warn!(
+                        target: logging::GIT,
+                        "failed to find rev {rev} for {}",
+                        repo_dir.display()
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    let result = git_command()
         .current_dir(repo_dir)
         .arg("checkout")
-        .arg(rev)
+        .arg(&target)
         .output()?;
 
     if result.status.success() {
-        Ok(true)
+        if !target.starts_with(rev) {
+            log::debug!(
+                target: logging::GIT,
+                "resolved rev {rev} to {target} in {}",
+                repo_dir.display()
+            );
+        }
+        Ok(Some(target))
     } else {
-        log::warn!("failed to find rev {rev} for {}", repo_dir.display());
-        Ok(false)
+        log::warn!(
+            target: logging::GIT,
+            "failed to checkout rev {rev} for {}",
+            repo_dir.display()
+        );
+        Ok(None)
     }
 }
 
-fn load_metadata(path: &Path) -> Result<Metadata, MetadataError> {
-    let meta_path = path.join(METADATA_FILE);
-    Metadata::load(&meta_path)
+/// Run a git command in `repo_dir`, returning stdout on success.
+fn run_git<I, S>(repo_dir: &Path, args: I) -> Result<String, GitFail>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let mut cmd = git_command();
+    cmd.current_dir(repo_dir).args(args);
+    log::debug!(target: logging::GIT, "running {cmd:?} in {}", repo_dir.display());
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(GitFail::command_failed(repo_dir, &cmd, &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
-fn iter_ofl_subdirectories(path: &Path) -> impl Iterator<Item = PathBuf> {
-    let contents =
-        std::fs::read_dir(path).unwrap_or_die(|e| eprintln!("failed to read ofl directory: '{e}'"));
-    contents.filter_map(|entry| entry.ok().map(|d| d.path()).filter(|p| p.is_dir()))
+/// If this repository is a shallow clone, fetch the rest of its history.
+fn unshallow(repo_dir: &Path) -> Result<(), GitFail> {
+    if run_git(repo_dir, ["rev-parse", "--is-shallow-repository"])?.trim() == "true" {
+        run_git(repo_dir, ["fetch", "--unshallow"])?;
+    }
+    Ok(())
 }
 
-fn clone_repo(url: &str, to_dir: &Path) -> Result<(), GitFail> {
-    assert!(to_dir.exists());
-    let output = std::process::Command::new("git")
-        // if a repo requires credentials fail instead of waiting
-        .env("GIT_TERMINAL_PROMPT", "0")
-        .arg("clone")
-        .args(["--depth", "1"])
-        .arg(url)
-        .arg(to_dir)
+/// Fetch the remote's default branch, returning the sha of its tip.
+///
+/// This does not change the current checkout.
+fn fetch_upstream_head(repo_dir: &Path) -> Result<GitRev, GitFail> {
+    run_git(repo_dir, ["fetch", "origin", "HEAD"])?;
+    resolve_rev(repo_dir, "FETCH_HEAD")?.ok_or_else(|| {
+        GitFail::unexpected_output(
+            repo_dir,
+            ["fetch", "origin", "HEAD"],
+            "FETCH_HEAD is not a commit".into(),
+        )
+    })
+}
+
+/// Return the commits in `range` that touch any of `paths`, newest first.
+fn git_log(repo_dir: &Path, range: &str, paths: &[PathBuf]) -> Result<Vec<Commit>, GitFail> {
+    let args = ["log", "--format=%H%x00%cI%x00%s", range, "--"]
+        .into_iter()
+        .map(std::ffi::OsStr::new)
+        .chain(paths.iter().map(|p| p.as_os_str()));
+    let stdout = run_git(repo_dir, args)?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\0');
+            Some(Commit {
+                sha: fields.next()?.to_owned(),
+                date: fields.next()?.to_owned(),
+                summary: fields.next()?.to_owned(),
+            })
+        })
+        .collect())
+}
+
+/// Read the contents of the file at `path` in the tree of commit `rev`
+fn read_file_at_rev(repo_dir: &Path, rev: &str, path: &Path) -> Result<String, GitFail> {
+    run_git(repo_dir, ["show", &format!("{rev}:{}", git_path(path))])
+}
+
+/// Returns `true` if the commit `sha` (which may be abbreviated) is in the
+/// local repository
+fn has_commit(repo_dir: &Path, sha: &str) -> Result<bool, GitFail> {
+    let output = git_command()
+        .current_dir(repo_dir)
+        .args(["cat-file", "-e", &format!("{sha}^{{commit}}")])
         .output()?;
+    Ok(output.status.success())
+}
+
+/// Returns `true` if a file or directory exists at `path` in commit `rev`
+fn exists_at_rev(repo_dir: &Path, rev: &str, path: &Path) -> Result<bool, GitFail> {
+    let object = format!("{rev}:{}", git_path(path));
+    let output = git_command()
+        .current_dir(repo_dir)
+        .args(["cat-file", "-e", &object])
+        .output()?;
+    Ok(output.status.success())
+}
+
+/// Convert a relative path to the '/'-separated form git uses in tree paths
+fn git_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Count the commits in `range`
+fn count_commits(repo_dir: &Path, range: &str) -> Result<usize, GitFail> {
+    let stdout = run_git(repo_dir, ["rev-list", "--count", range])?;
+    stdout.trim().parse().map_err(|_| {
+        GitFail::unexpected_output(
+            repo_dir,
+            ["rev-list", "--count", range],
+            format!("unexpected rev-list output '{stdout}'"),
+        )
+    })
+}
+
+/// The commit time of `rev`, in seconds since the unix epoch
+fn commit_time(repo_dir: &Path, rev: &str) -> Result<u64, GitFail> {
+    let stdout = run_git(repo_dir, ["show", "-s", "--format=%ct", rev])?;
+    stdout.trim().parse().map_err(|_| {
+        GitFail::unexpected_output(
+            repo_dir,
+            ["show", "-s", "--format=%ct", rev],
+            format!("unexpected commit time '{stdout}'"),
+        )
+    })
+}
+
+fn iter_license_subdirectories(path: &Path) -> Result<impl Iterator<Item = PathBuf>, Error> {
+    let contents = std::fs::read_dir(path).map_err(|source| Error::ReadDir {
+        path: path.to_owned(),
+        source,
+    })?;
+    Ok(contents.filter_map(|entry| entry.ok().map(|d| d.path()).filter(|p| p.is_dir())))
+}
+
+// the branch (or tag) to clone for a source pinned at `rev`, if it names one
+fn pinned_branch(rev: &str) -> Option<&str> {
+    let is_sha = rev.chars().all(|c| c.is_ascii_hexdigit());
+    (!is_sha && rev != "HEAD").then_some(rev)
+}
+
+/// Clone `url` into the existing, empty, `to_dir`.
+///
+/// With [`CloneBranches::Single`] and a `branch` (which may also be a tag)
+/// only that branch is cloned; if the remote has no such branch, its default
+/// branch is cloned instead.
+fn clone_repo(
+    url: &str,
+    to_dir: &Path,
+    branches: CloneBranches,
+    branch: Option<&str>,
+    credentials: Option<&Credentials>,
+) -> Result<(), GitFail> {
+    assert!(to_dir.exists());
+    let branch = branch.filter(|_| branches == CloneBranches::Single);
+    let mut cmd = git_command_for(credentials);
+    cmd.arg("clone").args(["--depth", "1"]);
+    match branches {
+        CloneBranches::Single => cmd.arg("--single-branch"),
+        CloneBranches::All => cmd.arg("--no-single-branch"),
+    };
+    if let Some(branch) = branch {
+        cmd.args(["--branch", branch]);
+    }
+    cmd.arg(url).arg(to_dir);
+    log::debug!(target: logging::GIT, "cloning {url} to {}", to_dir.display());
+    let output = cmd.output()?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(GitFail::GitError {
-            path: to_dir.to_owned(),
-            stderr: stderr.into_owned(),
-        });
+        if let Some(branch) = branch {
+            log::debug!(
+                target: logging::GIT,
+                "failed to clone {branch} from {url}, cloning the default branch"
+            );
+            return clone_repo(url, to_dir, branches, None, credentials);
+        }
+        return Err(GitFail::command_failed(to_dir, &cmd, &output));
     }
     Ok(())
 }
 
 /// On success returns whether there were any changes
-fn fetch_latest(path: &Path) -> Result<(), GitFail> {
-    let output = std::process::Command::new("git")
-        // if a repo requires credentials fail instead of waiting
-        .env("GIT_TERMINAL_PROMPT", "0")
-        .arg("pull")
-        .current_dir(path)
-        .output()?;
+fn fetch_latest(path: &Path, credentials: Option<&Credentials>) -> Result<(), GitFail> {
+    let mut cmd = git_command_for(credentials);
+    cmd.arg("pull").current_dir(path);
+    log::debug!(target: logging::GIT, "pulling in {}", path.display());
+    let output = cmd.output()?;
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(GitFail::GitError {
-            path: path.to_owned(),
-            stderr: stderr.into_owned(),
-        });
+        return Err(GitFail::command_failed(path, &cmd, &output));
     }
     Ok(())
 }
@@ -548,14 +2437,24 @@ fn fetch_latest(path: &Path) -> Result<(), GitFail> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::repo_info::tests::{commit, file_url, make_repo};
 
     #[test]
     fn http_config() {
-        assert!(
-            config_file_and_rev_from_remote_http("https://github.com/PaoloBiagini/Joan").is_ok()
-        );
+        assert!(config_file_and_rev_from_remote_http(
+            "https://github.com/PaoloBiagini/Joan",
+            None,
+            None,
+            &HttpCache::disabled()
+        )
+        .is_ok());
         assert!(matches!(
-            config_file_and_rev_from_remote_http("https://github.com/googlefonts/bangers"),
+            config_file_and_rev_from_remote_http(
+                "https://github.com/googlefonts/bangers",
+                None,
+                None,
+                &HttpCache::disabled()
+            ),
             Err(ConfigFetchIssue::NoConfigFound)
         ));
     }
@@ -568,6 +2467,205 @@ mod tests {
         assert!(rev.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    // create a repo with two commits, tagging the first
+    fn make_repo_with_tag(dir: &Path, tag: &str) {
+        make_repo(dir, &[]);
+        run_git(dir, ["tag", tag]).unwrap();
+        commit(dir, "two");
+    }
+
+    #[test]
+    fn checkout_tag() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        let checkout = temp.path().join("checkout");
+        std::fs::create_dir_all(&upstream).unwrap();
+        std::fs::create_dir_all(&checkout).unwrap();
+        make_repo_with_tag(&upstream, "v1.000");
+        let tagged = resolve_rev(&upstream, "v1.000").unwrap().unwrap();
+
+        clone_repo(
+            &file_url(&upstream),
+            &checkout,
+            CloneBranches::Single,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            checkout_rev(&checkout, "v1.000", None).unwrap(),
+            Some(tagged)
+        );
+        assert_eq!(checkout_rev(&checkout, "v2.000", None).unwrap(), None);
+    }
+
+    #[test]
+    fn checkout_moved_branch() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        let checkout = temp.path().join("checkout");
+        std::fs::create_dir_all(&checkout).unwrap();
+        make_repo_with_tag(&upstream, "v1.000");
+        run_git(&upstream, ["branch", "release"]).unwrap();
+        let url = file_url(&upstream);
+        clone_repo(
+            &url,
+            &checkout,
+            CloneBranches::Single,
+            pinned_branch("release"),
+            None,
+        )
+        .unwrap();
+        let first = resolve_rev(&upstream, "release").unwrap();
+        assert_eq!(checkout_rev(&checkout, "release", None).unwrap(), first);
+
+        run_git(&upstream, ["checkout", "--quiet", "release"]).unwrap();
+        commit(&upstream, "three");
+        let moved = resolve_rev(&upstream, "release").unwrap();
+        assert_ne!(moved, first);
+        assert_eq!(checkout_rev(&checkout, "release", None).unwrap(), moved);
+    }
+
+    #[test]
+    fn checkout_default_branch_after_tag() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        let checkout = temp.path().join("checkout");
+        std::fs::create_dir_all(&checkout).unwrap();
+        make_repo_with_tag(&upstream, "v1.000");
+        let url = file_url(&upstream);
+        clone_repo(
+            &url,
+            &checkout,
+            CloneBranches::Single,
+            pinned_branch("v1.000"),
+            None,
+        )
+        .unwrap();
+        let tagged = resolve_rev(&upstream, "v1.000").unwrap();
+        assert_eq!(checkout_rev(&checkout, "v1.000", None).unwrap(), tagged);
+
+        // the pin moves to a later commit on the default branch, which the
+        // clone of the tag didn't fetch
+        commit(&upstream, "three");
+        let head = resolve_rev(&upstream, "HEAD").unwrap().unwrap();
+        assert_eq!(checkout_rev(&checkout, &head, None).unwrap(), Some(head));
+    }
+
+    #[test]
+    fn checkout_local_rev_without_fetching() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        let checkout = temp.path().join("checkout");
+        std::fs::create_dir_all(&upstream).unwrap();
+        std::fs::create_dir_all(&checkout).unwrap();
+        make_repo_with_tag(&upstream, "v1.000");
+        clone_repo(
+            &file_url(&upstream),
+            &checkout,
+            CloneBranches::Single,
+            None,
+            None,
+        )
+        .unwrap();
+        unshallow(&checkout).unwrap();
+        let first = resolve_rev(&checkout, "HEAD~1").unwrap().unwrap();
+
+        commit(&upstream, "three");
+        let third = resolve_rev(&upstream, "HEAD").unwrap().unwrap();
+
+        assert!(has_commit(&checkout, &first[..10]).unwrap());
+        assert!(!has_commit(&checkout, &third).unwrap());
+        assert_eq!(checkout_rev(&checkout, &first, None).unwrap(), Some(first));
+        // the commit was already here, so nothing new was fetched
+        assert_eq!(resolve_rev(&checkout, &third).unwrap(), None);
+    }
+
+    #[test]
+    fn single_branch_clones() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream");
+        std::fs::create_dir_all(&upstream).unwrap();
+        make_repo_with_tag(&upstream, "v1.000");
+        run_git(&upstream, ["branch", "release", "v1.000"]).unwrap();
+        let release = resolve_rev(&upstream, "release").unwrap().unwrap();
+        let url = file_url(&upstream);
+        let remote_branches = |checkout: &Path| {
+            run_git(
+                checkout,
+                ["branch", "--remotes", "--format=%(refname:short)"],
+            )
+            .unwrap()
+        };
+
+        let pinned = temp.path().join("pinned");
+        std::fs::create_dir_all(&pinned).unwrap();
+        clone_repo(
+            &url,
+            &pinned,
+            CloneBranches::Single,
+            pinned_branch("release"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(remote_branches(&pinned).trim(), "origin/release");
+        assert_eq!(resolve_rev(&pinned, "HEAD").unwrap(), Some(release.clone()));
+
+        // only the default branch was cloned, so the other one is fetched
+        let default = temp.path().join("default");
+        std::fs::create_dir_all(&default).unwrap();
+        clone_repo(
+            &url,
+            &default,
+            CloneBranches::Single,
+            pinned_branch(&release),
+            None,
+        )
+        .unwrap();
+        assert!(!remote_branches(&default).contains("release"));
+        assert_eq!(
+            checkout_rev(&default, "release", None).unwrap(),
+            Some(release)
+        );
+        assert_eq!(pinned_branch("HEAD"), None);
+
+        // every branch, even though one is pinned
+        let all = temp.path().join("all");
+        std::fs::create_dir_all(&all).unwrap();
+        clone_repo(
+            &url,
+            &all,
+            CloneBranches::All,
+            pinned_branch("release"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            remote_branches(&all).lines().count(),
+            3,
+            "{}",
+            remote_branches(&all)
+        );
+    }
+
+    #[test]
+    fn log_since_tag() {
+        let temp = tempfile::tempdir().unwrap();
+        make_repo_with_tag(temp.path(), "v1.000");
+        let log = git_log(temp.path(), "v1.000..HEAD", &[]).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].summary, "two");
+        assert_eq!(log[0].sha.len(), 40);
+        // the file doesn't exist, so no commits touched it
+        let log = git_log(temp.path(), "HEAD", &["sources/config.yaml".into()]).unwrap();
+        assert!(log.is_empty());
+        assert_eq!(count_commits(temp.path(), "v1.000..HEAD").unwrap(), 1);
+        assert!(
+            commit_time(temp.path(), "HEAD").unwrap()
+                >= commit_time(temp.path(), "v1.000").unwrap()
+        );
+    }
+
     #[test]
     fn source_dir_case() {
         assert_eq!(
@@ -575,4 +2673,714 @@ mod tests {
             Some(PathBuf::from("./source_dir_test/Sources"))
         )
     }
+
+    #[test]
+    fn set_from_repo_urls() {
+        let temp = tempfile::tempdir().unwrap();
+        let with_config = temp.path().join("upstream/HasConfig");
+        let without_config = temp.path().join("upstream/NoConfig");
+        make_repo(&with_config, &[("sources/config.yaml", "sources: []\n")]);
+        make_repo(&without_config, &[("README.md", "hi")]);
+        let urls = [
+            format!("file://{}/", with_config.display()),
+            file_url(&without_config),
+            "not a url".to_owned(),
+        ];
+        let cache = temp.path().join("cache");
+        let offline = DiscoveryOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let set = SourceSet::from_repo_urls(&urls, &cache, &offline);
+        assert!(set.is_empty());
+        assert!(set
+            .problems()
+            .iter()
+            .any(|problem| problem.kind == ProblemKind::Unreachable));
+        assert!(!cache.join("upstream").exists());
+
+        let set = SourceSet::from_repo_urls(&urls, &cache, &DiscoveryOptions::default());
+        assert_eq!(set.len(), 1);
+        let info = &set.sources()[0];
+        assert_eq!(info.repo_name(), "HasConfig");
+        assert_eq!(info.config_files, [PathBuf::from("config.yaml")]);
+        let kinds = set.problems().iter().map(|p| p.kind).collect::<Vec<_>>();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&ProblemKind::NoConfig));
+        assert!(kinds.contains(&ProblemKind::UnfamiliarUrl));
+    }
+
+    #[test]
+    fn bad_metadata_is_a_problem() {
+        let temp = tempfile::tempdir().unwrap();
+        let files = [
+            ("ofl/good/METADATA.pb", "name: \"Good\"\n"),
+            ("ofl/bad/METADATA.pb", "nom: \"Bad\"\n"),
+            ("apache/notafont/README", ""),
+            ("ufl/.keep", ""),
+        ];
+        for (path, contents) in files {
+            let path = temp.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let (candidates, problems, _) =
+            get_candidates_from_local_checkout(temp.path(), None, None).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates.first().unwrap().name, "Good");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::BadMetadata);
+        assert_eq!(problems[0].path.as_deref(), Some(Path::new("ofl/bad")));
+    }
+
+    #[test]
+    fn report_families_without_usable_repo() {
+        let metadata = |name: &str, url: Option<&str>| Metadata {
+            name: name.into(),
+            repo_url: url.map(Into::into),
+            config_yaml: None,
+            files: Default::default(),
+            license: None,
+        };
+        let candidates = BTreeSet::from([
+            metadata("A Sans", Some("https://github.com/org/a")),
+            metadata("A Serif", Some("https://github.com/org/a")),
+            metadata("B", None),
+        ]);
+        let mut report = DiscoveryReport::default();
+        let with_repo = candidates_with_known_repo(&candidates, &mut report);
+        assert_eq!(with_repo.len(), 1);
+        let serif = report.for_family("A Serif").next().unwrap();
+        assert_eq!(serif.stage, DiscoveryStage::Metadata);
+        assert_eq!(serif.reason, "the repository is already used by 'A Sans'");
+        let b = report.for_family("B").next().unwrap();
+        assert_eq!(b.reason, "no repository url in METADATA.pb");
+        assert_eq!(report.entries.len(), 2);
+
+        let set = SourceSet::new(Vec::new()).with_skipped(report.skipped());
+        let json: serde_json::Value = serde_json::from_str(&set.to_json().unwrap()).unwrap();
+        assert_eq!(json["skipped"]["counts"]["duplicate_repo"], 1);
+        assert_eq!(json["skipped"]["families"]["B"], "no_repo_url");
+        let loaded = SourceSet::from_json(&set.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.skipped(), set.skipped());
+    }
+
+    #[test]
+    fn probe_queue_holds_early_results() {
+        let metadata = |name: &str, url: &str| Metadata {
+            name: name.into(),
+            repo_url: Some(url.into()),
+            config_yaml: None,
+            files: Default::default(),
+            license: None,
+        };
+        let done = |metadata: &Metadata| {
+            let timing = RepoTiming {
+                repo_url: metadata.repo_url.clone().unwrap(),
+                family: metadata.name.clone(),
+                elapsed: Duration::ZERO,
+                phases: Default::default(),
+            };
+            (metadata.clone(), ProbeOutcome::NoConfig, timing)
+        };
+        let a = metadata("A", "https://github.com/org/a");
+        let a_italic = metadata("A Italic", "https://github.com/org/a");
+        let b = metadata("B", "https://github.com/org/b");
+
+        let mut queue = ProbeQueue::new();
+        assert!(queue.push(a.clone()));
+        // same project, so it shares the probe
+        assert!(!queue.push(a_italic.clone()));
+        assert!(queue.push(b.clone()));
+        assert_eq!(std::mem::take(&mut queue.pending).len(), 2);
+        // finished before the scan, so held
+        assert!(queue.finish(done(&a)).is_none());
+
+        // only the italic is wanted, but it gets the shared result
+        let held = queue.select(&BTreeSet::from([a_italic.clone()]));
+        assert_eq!(held.len(), 1);
+        assert_eq!(queue.wanted(&held[0].0), Some(&a_italic));
+        assert!(!queue.is_finished());
+        let late = queue.finish(done(&b)).unwrap();
+        assert_eq!(queue.wanted(&late.0), None);
+        assert!(queue.is_finished());
+
+        // after a failed scan, queued fonts are not waited for
+        let mut queue = ProbeQueue::new();
+        queue.push(a.clone());
+        queue.abandon();
+        assert!(queue.is_finished());
+        assert!(queue.pending.is_empty());
+    }
+
+    #[test]
+    fn git_failures_include_the_command() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let err = run_git(tempdir.path(), ["fetch", "origin", "HEAD"]).unwrap_err();
+        let GitFail::GitError { args, status, .. } = &err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(args, &["fetch", "origin", "HEAD"]);
+        assert!(status.is_some_and(|code| code != 0));
+        assert_eq!(err.operation(), Some("fetch"));
+        assert!(err
+            .to_string()
+            .contains("'git fetch origin HEAD' (exit status"));
+    }
+
+    #[test]
+    fn network_failures_are_retryable() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let unreachable = GitFail::unexpected_output(
+            tempdir.path(),
+            ["clone"],
+            "fatal: unable to access 'https://example.invalid/': Could not resolve host".into(),
+        );
+        let err = LoadRepoError::from(unreachable);
+        assert!(matches!(err, LoadRepoError::Network(_)));
+        assert!(err.is_retryable());
+        assert_eq!(err.code(), ErrorCode::Network);
+
+        // a repository that doesn't exist will never work
+        let missing = file_url(&tempdir.path().join("nope"));
+        let err = LoadRepoError::from(
+            clone_repo(&missing, tempdir.path(), CloneBranches::Single, None, None).unwrap_err(),
+        );
+        assert!(matches!(err, LoadRepoError::GitFail(_)), "{err:?}");
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn missing_license_dir_is_an_error() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("ofl")).unwrap();
+        let result = get_candidates_from_local_checkout(temp.path(), None, None);
+        assert!(
+            matches!(result, Err(Error::ReadDir { path, .. }) if path == temp.path().join("apache"))
+        );
+    }
+
+    #[test]
+    fn repo_urls_from_list() {
+        let input = "# from a previous run\nhttps://github.com/a/b\n\n  https://github.com/c/d  \n";
+        let urls = read_repo_urls(input.as_bytes()).unwrap();
+        assert_eq!(urls, ["https://github.com/a/b", "https://github.com/c/d"]);
+    }
+
+    #[test]
+    fn candidates_changed_since() {
+        let temp = tempfile::tempdir().unwrap();
+        make_repo(
+            temp.path(),
+            &[
+                ("ofl/old/METADATA.pb", "name: \"Old\"\n"),
+                ("apache/.keep", ""),
+                ("ufl/.keep", ""),
+            ],
+        );
+        let first = resolve_rev(temp.path(), "HEAD").unwrap().unwrap();
+        std::fs::create_dir_all(temp.path().join("ofl/new")).unwrap();
+        std::fs::write(temp.path().join("ofl/new/METADATA.pb"), "name: \"New\"\n").unwrap();
+        commit(temp.path(), "new");
+
+        let changed = metadata_changed_since(temp.path(), &first).unwrap();
+        assert_eq!(changed, HashSet::from([PathBuf::from("ofl/new")]));
+        let (candidates, ..) =
+            get_candidates_from_local_checkout(temp.path(), Some(&changed), None).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates.first().unwrap().name, "New");
+
+        // everything has changed since a date before either commit
+        let changed = metadata_changed_since(temp.path(), "2000-01-01").unwrap();
+        assert_eq!(changed.len(), 2);
+    }
+
+    #[test]
+    fn cli_exit_codes() {
+        let temp = tempfile::tempdir().unwrap();
+        let run_command = |command| {
+            run(&Args {
+                command: Some(command),
+                ..Default::default()
+            })
+        };
+        let missing = temp.path().join("missing.json");
+        let err = run_command(Command::Stats {
+            input: missing,
+            json: false,
+        })
+        .unwrap_err();
+        assert!(matches!(err, CliError::Failed(_)));
+        assert_eq!(err.exit_code(), EXIT_FAILED);
+
+        let input = temp.path().join("sources.json");
+        let set = SourceSet::new(vec![RepoInfo::new(
+            "https://github.com/org/repo?query".into(),
+            "a".into(),
+            Vec::new(),
+        )
+        .unwrap()]);
+        std::fs::write(&input, set.to_json().unwrap()).unwrap();
+        let validate = |input| Command::Validate {
+            input,
+            fonts_dir: None,
+        };
+        let err = run_command(validate(input.clone())).unwrap_err();
+        assert!(matches!(err, CliError::Incomplete(_)));
+        assert_eq!(err.exit_code(), EXIT_FAILED);
+
+        std::fs::write(&input, SourceSet::new(Vec::new()).to_json().unwrap()).unwrap();
+        assert!(run_command(validate(input)).is_ok());
+
+        let err = CliError::Warnings(vec!["one".into(), "two".into()]);
+        assert_eq!(err.to_string(), "warning: one\nwarning: two");
+        assert_eq!(err.exit_code(), EXIT_PROBLEMS);
+        assert_eq!(
+            err.to_json(),
+            serde_json::json!({
+                "error": "warnings",
+                "messages": ["one", "two"],
+                "exit_code": EXIT_PROBLEMS,
+            })
+        );
+        assert_eq!(
+            CliError::Failed("oops".into()).to_json()["messages"],
+            serde_json::json!(["oops"])
+        );
+    }
+
+    #[test]
+    fn strict_mode_warnings() {
+        let info =
+            |url: &str, rev: &str| RepoInfo::new(url.into(), rev.into(), Vec::new()).unwrap();
+        let clean = SourceSet::new(vec![info("https://github.com/org/font", "a")]);
+        assert!(strict_warnings(&clean).is_empty());
+
+        let messy = SourceSet::new(vec![
+            info("https://github.com/org/font", "a"),
+            info("https://github.com/org/font/", "b"),
+            info("https://example.com/org/font", "c"),
+        ]);
+        assert_eq!(strict_warnings(&messy).len(), 2);
+    }
+
+    #[test]
+    fn no_fetch_uses_cached_checkout() {
+        let temp = tempfile::tempdir().unwrap();
+        let upstream = temp.path().join("upstream/Family");
+        make_repo(&upstream, &[("sources/config.yaml", "sources: []\n")]);
+        let url = file_url(&upstream);
+        let cache = temp.path().join("cache");
+        let probe = |network| {
+            config_files_and_rev_for_repo(
+                &url,
+                None,
+                &cache,
+                network,
+                CloneBranches::Single,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+
+        let (_, first) = probe(Network::NoFetch);
+        std::fs::write(upstream.join("README"), "hi").unwrap();
+        commit(&upstream, "more");
+        assert_eq!(probe(Network::NoFetch).1, first);
+        assert_ne!(probe(Network::Full).1, first);
+    }
+
+    #[test]
+    fn refresh_keeps_unchanged_fonts() {
+        let temp = tempfile::tempdir().unwrap();
+        let google_fonts = temp.path().join("google/fonts");
+        make_repo(
+            &google_fonts,
+            &[
+                ("ofl/foo/METADATA.pb", "name: \"Foo\"\n"),
+                ("apache/.keep", ""),
+                ("ufl/.keep", ""),
+            ],
+        );
+        let rev = resolve_rev(&google_fonts, "HEAD").unwrap().unwrap();
+        let previous = SourceSet::from_json(&format!(
+            r#"{{
+                "version": "1.2",
+                "google_fonts_rev": "{rev}",
+                "sources": [
+                    {{"repo_url": "https://github.com/org/foo", "rev": "a",
+                      "config_files": [], "family_name": "Foo"}},
+                    {{"repo_url": "https://github.com/org/gone", "rev": "b",
+                      "config_files": [], "family_name": "Gone"}},
+                    {{"repo_url": "https://github.com/org/manual", "rev": "c",
+                      "config_files": []}}
+                ]
+            }}"#
+        ))
+        .unwrap();
+
+        let options = DiscoveryOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let refreshed = previous.refresh(temp.path(), &options).unwrap();
+        let urls = refreshed
+            .iter()
+            .map(|info| info.repo_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://github.com/org/foo",
+                "https://github.com/org/manual"
+            ]
+        );
+        assert_eq!(refreshed.google_fonts_rev(), Some(rev.as_str()));
+    }
+
+    #[test]
+    fn report_google_fonts_rev() {
+        let temp = tempfile::tempdir().unwrap();
+        let google_fonts = temp.path().join("google/fonts");
+        make_repo(
+            &google_fonts,
+            &[("ofl/.keep", ""), ("apache/.keep", ""), ("ufl/.keep", "")],
+        );
+        let rev = resolve_rev(&google_fonts, "HEAD").unwrap();
+        let options = DiscoveryOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let (set, report) = discover_sources_with_report(temp.path(), &options).unwrap();
+        assert!(rev.is_some());
+        assert_eq!(report.google_fonts_rev, rev);
+        assert_eq!(set.google_fonts_rev(), rev.as_deref());
+    }
+
+    #[test]
+    fn reuse_cached_discovery() {
+        let temp = tempfile::tempdir().unwrap();
+        let google_fonts = temp.path().join("google/fonts");
+        make_repo(
+            &google_fonts,
+            &[("ofl/.keep", ""), ("apache/.keep", ""), ("ufl/.keep", "")],
+        );
+        let options = DiscoveryOptions {
+            offline: true,
+            reuse_cached: true,
+            ..Default::default()
+        };
+        let (set, report) = discover_sources_with_report(temp.path(), &options).unwrap();
+        assert!(set.is_empty());
+        let cache_path = DiscoveryCache::path(temp.path());
+        assert!(cache_path.exists());
+
+        // pretend the previous run found something, so we can tell it was reused
+        let rev = report.google_fonts_rev.clone().unwrap();
+        let info = RepoInfo::new(
+            "https://github.com/org/font".into(),
+            "abc".into(),
+            vec!["config.yaml".into()],
+        )
+        .unwrap();
+        let cached = SourceSet::new(vec![info]);
+        DiscoveryCache::save(&cache_path, &rev, &options.cache_key(), &cached, &report).unwrap();
+        let (set, _) = discover_sources_with_report(temp.path(), &options).unwrap();
+        assert_eq!(set.len(), 1);
+
+        // different options, or a new rev of google/fonts, means starting over
+        let filtered = DiscoveryOptions {
+            families: vec!["Font".into()],
+            ..options.clone()
+        };
+        assert!(discover_sources_with_options(temp.path(), &filtered)
+            .unwrap()
+            .is_empty());
+        DiscoveryCache::save(&cache_path, &rev, &options.cache_key(), &cached, &report).unwrap();
+        commit(&google_fonts, "more");
+        assert!(discover_sources_with_options(temp.path(), &options)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn offline_discovery() {
+        let temp = tempfile::tempdir().unwrap();
+        make_repo(
+            &temp.path().join("org/Cached"),
+            &[("sources/config.yaml", "sources: []\n")],
+        );
+        let (configs, _) = config_files_and_rev_for_repo(
+            "https://github.com/org/Cached",
+            None,
+            temp.path(),
+            Network::Offline,
+            CloneBranches::Single,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(configs, [PathBuf::from("config.yaml")]);
+        assert!(matches!(
+            config_files_and_rev_for_repo(
+                "https://github.com/org/Missing",
+                None,
+                temp.path(),
+                Network::Offline,
+                CloneBranches::Single,
+                None,
+                None,
+            ),
+            Err(ConfigFetchIssue::NotCached)
+        ));
+        assert!(!temp.path().join("org/Missing").exists());
+
+        let options = DiscoveryOptions {
+            offline: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            discover_sources_with_options(temp.path(), &options),
+            Err(Error::NotCached(_))
+        ));
+    }
+
+    // serve the repositories in `root` over http, as a host of private
+    // repositories would: requests without the token are refused
+    fn serve_private_repos(root: &Path, token: &str) -> String {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::process::{Command, Stdio};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let root = root.to_owned();
+        let expected = format!("Basic {}", base64(format!("oauth2:{token}").as_bytes()));
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut lines = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end() {
+                        "" => break,
+                        line => lines.push(line.to_owned()),
+                    }
+                }
+                let header = |name: &str| {
+                    lines.iter().find_map(|line| {
+                        let (key, value) = line.split_once(':')?;
+                        key.eq_ignore_ascii_case(name)
+                            .then(|| value.trim().to_owned())
+                    })
+                };
+                let length = header("content-length").map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                if header("authorization").as_ref() != Some(&expected) {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"git\"\r\n\
+                          Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                    continue;
+                }
+                let mut request = lines[0].split_whitespace();
+                let (method, target) = (request.next().unwrap(), request.next().unwrap());
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let mut backend = Command::new("git")
+                    .arg("http-backend")
+                    .env("GIT_PROJECT_ROOT", &root)
+                    .env("GIT_HTTP_EXPORT_ALL", "1")
+                    .env("REQUEST_METHOD", method)
+                    .env("PATH_INFO", path)
+                    .env("QUERY_STRING", query)
+                    .env("CONTENT_TYPE", header("content-type").unwrap_or_default())
+                    .env("CONTENT_LENGTH", length.to_string())
+                    .env(
+                        "HTTP_CONTENT_ENCODING",
+                        header("content-encoding").unwrap_or_default(),
+                    )
+                    .env("GIT_PROTOCOL", header("git-protocol").unwrap_or_default())
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                backend.stdin.take().unwrap().write_all(&body).unwrap();
+                let output = backend.wait_with_output().unwrap().stdout;
+                // cgi output is headers, a blank line, and the body
+                let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                let cgi_headers = String::from_utf8_lossy(&output[..end]);
+                let status = cgi_headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Status: "))
+                    .unwrap_or("200 OK");
+                let body = &output[end + 4..];
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\n{cgi_headers}\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        url
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().fold(0u32, |n, b| n << 8 | *b as u32) << (8 * (3 - chunk.len()));
+            for i in 0..4 {
+                match i <= chunk.len() {
+                    true => out.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char),
+                    false => out.push('='),
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn probe_clones_with_token() {
+        let token = "hunter2";
+        let temp = tempfile::tempdir().unwrap();
+        let hosted = temp.path().join("hosted");
+        make_repo(
+            &hosted.join("private/Font"),
+            &[("sources/config.yaml", "sources: []\n")],
+        );
+        let url = format!("{}/private/Font", serve_private_repos(&hosted, token));
+        let cache = temp.path().join("cache");
+        let probe = |credentials| {
+            config_files_and_rev_for_repo(
+                &url,
+                None,
+                &cache,
+                Network::Full,
+                CloneBranches::Single,
+                None,
+                credentials,
+            )
+        };
+
+        assert!(matches!(probe(None), Err(ConfigFetchIssue::GitFail(_))));
+        std::fs::remove_dir_all(cache.join("private")).unwrap();
+        let credentials = Credentials::from_env(&url, "TOKEN", |_| Some(token.into()));
+        let (configs, _) = probe(credentials.as_ref()).unwrap();
+        assert_eq!(configs, [PathBuf::from("config.yaml")]);
+        let config = std::fs::read_to_string(cache.join("private/Font/.git/config")).unwrap();
+        assert!(!config.contains(token));
+    }
+
+    #[test]
+    fn discovery_clones_with_token() {
+        // cargo always sets this for tests, so we don't have to set a
+        // variable in the environment shared with the other tests
+        let (token_env, token) = ("CARGO_PKG_NAME", env!("CARGO_PKG_NAME"));
+        let temp = tempfile::tempdir().unwrap();
+        let hosted = temp.path().join("hosted");
+        make_repo(
+            &hosted.join("private/Font"),
+            &[("sources/config.yaml", "sources: []\n")],
+        );
+        let host = serve_private_repos(&hosted, token);
+        let url = format!("{host}/private/Font");
+        let cache = temp.path().join("cache");
+        let metadata = format!("name: \"Font\"\nsource {{\n  repository_url: \"{url}\"\n}}\n");
+        make_repo(
+            &cache.join("google/fonts"),
+            &[
+                ("ofl/font/METADATA.pb", &metadata),
+                ("apache/.keep", ""),
+                ("ufl/.keep", ""),
+            ],
+        );
+
+        let mut options = DiscoveryOptions {
+            no_fetch: true,
+            ..Default::default()
+        };
+        let set = discover_sources_with_options(&cache, &options).unwrap();
+        assert!(set.is_empty());
+        assert_eq!(set.problems()[0].kind, ProblemKind::ProbeFailed);
+        std::fs::remove_dir_all(cache.join("private")).unwrap();
+
+        // prefixes only match whole path segments
+        options
+            .tokens
+            .insert(format!("{host}/private-other"), token_env.into());
+        options
+            .tokens
+            .insert(format!("{host}/private"), token_env.into());
+        let set = discover_sources_with_options(&cache, &options).unwrap();
+        assert_eq!(set.problems(), []);
+        let [info] = set.sources() else {
+            panic!("expected one source, found {:?}", set.sources());
+        };
+        assert_eq!(info.family_name(), Some("Font"));
+        assert_eq!(info.token_env.as_deref(), Some(token_env));
+        let config = std::fs::read_to_string(cache.join("private/Font/.git/config")).unwrap();
+        assert!(!config.contains(token));
+    }
+
+    #[test]
+    fn discovery_probes_while_scanning() {
+        let temp = tempfile::tempdir().unwrap();
+        let metadata = |name: &str, repo: &str| {
+            format!("name: \"{name}\"\nsource {{\n  repository_url: \"https://github.com/org/{repo}\"\n}}\n")
+        };
+        let (one, one_bold, two, three) = (
+            metadata("One", "One"),
+            metadata("One Bold", "One"),
+            metadata("Two", "Two"),
+            metadata("Three", "Missing"),
+        );
+        make_repo(
+            &temp.path().join("google/fonts"),
+            &[
+                ("ofl/one/METADATA.pb", &one),
+                ("ofl/onebold/METADATA.pb", &one_bold),
+                ("ofl/two/METADATA.pb", &two),
+                ("ofl/three/METADATA.pb", &three),
+                ("apache/.keep", ""),
+                ("ufl/.keep", ""),
+            ],
+        );
+        make_repo(
+            &temp.path().join("org/One"),
+            &[("sources/config.yaml", "sources: []\n")],
+        );
+        make_repo(&temp.path().join("org/Two"), &[("README.md", "")]);
+
+        let options = DiscoveryOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let (set, report) = discover_sources_with_report(temp.path(), &options).unwrap();
+        let families = set
+            .iter()
+            .map(|info| info.family_name().unwrap())
+            .collect::<Vec<_>>();
+        // 'One Bold' shares a probe with 'One', and isn't reported separately
+        assert_eq!(families, ["One"]);
+        assert_eq!(report.families_scanned, 4);
+        assert_eq!(report.metrics.repos.len(), 3);
+        let problems = set
+            .problems()
+            .iter()
+            .map(|problem| (problem.family_name.as_deref().unwrap(), problem.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(problems, [("Three", ProblemKind::Unreachable)]);
+
+        // with a limit, nothing is probed until all the fonts are known
+        let options = DiscoveryOptions {
+            limit: Some(1),
+            ..options
+        };
+        let (_, report) = discover_sources_with_report(temp.path(), &options).unwrap();
+        assert_eq!(report.metrics.repos.len(), 1);
+    }
 }
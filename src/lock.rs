@@ -0,0 +1,166 @@
+//! recording the exact state of each source after checkout
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{LockError, SourceSetError},
+    source_set, SourceSet,
+};
+
+/// The fully resolved state of every entry in a [`SourceSet`].
+///
+/// Where a `SourceSet` records the rev that each repository is pinned at, a
+/// lock file records what was actually found there: the full sha that was
+/// checked out, and digests of the config files and sources. This can be
+/// used to check that a later run sees exactly the same inputs, or to find
+/// which entries really changed between two runs.
+///
+/// Create one with [`SourceSet::lock`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LockFile {
+    /// The commit of google/fonts that was used for discovery, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub google_fonts_rev: Option<String>,
+    /// The resolved state of each entry
+    pub sources: Vec<LockedSource>,
+}
+
+/// The resolved state of a single [`RepoInfo`](crate::RepoInfo).
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LockedSource {
+    /// The repository's url
+    pub repo_url: String,
+    /// The project's directory, for repositories with multiple projects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<PathBuf>,
+    /// The rev the entry was pinned at
+    pub rev: String,
+    /// The full sha of the commit that was checked out
+    pub sha: String,
+    /// The sha256 of each config file's contents, keyed by path relative to
+    /// the root of the repository
+    pub config_hashes: BTreeMap<PathBuf, String>,
+    /// A sha256 digest of each source file (or directory), keyed by path
+    /// relative to the root of the repository
+    pub source_digests: BTreeMap<PathBuf, String>,
+}
+
+impl LockFile {
+    /// Load a lock file written by [`save`](Self::save).
+    pub fn load(path: &Path) -> Result<Self, SourceSetError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Write this lock file as json.
+    pub fn save(&self, path: &Path) -> Result<(), SourceSetError> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        source_set::write_file(path, json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Return the entries whose configs or sources differ from those in
+    /// `older`, or which are not in `older` at all.
+    ///
+    /// Entries whose sha changed but whose inputs are identical (for instance
+    /// because only unrelated files were modified) are not included.
+    pub fn changed_since<'a>(&'a self, older: &LockFile) -> Vec<&'a LockedSource> {
+        let older = older
+            .sources
+            .iter()
+            .map(|locked| (locked.key(), locked))
+            .collect::<BTreeMap<_, _>>();
+        self.sources
+            .iter()
+            .filter(|locked| match older.get(&locked.key()) {
+                Some(old) => !locked.same_inputs(old),
+                None => true,
+            })
+            .collect()
+    }
+}
+
+impl LockedSource {
+    fn key(&self) -> (&str, Option<&Path>) {
+        (&self.repo_url, self.subdir.as_deref())
+    }
+
+    /// `true` if the configs and sources are the same in both entries.
+    pub fn same_inputs(&self, other: &LockedSource) -> bool {
+        self.config_hashes == other.config_hashes && self.source_digests == other.source_digests
+    }
+}
+
+impl SourceSet {
+    /// Check out every entry in this set and record its resolved state.
+    ///
+    /// This stops at the first repository that cannot be checked out.
+    pub fn lock(&self, cache_dir: &Path) -> Result<LockFile, LockError> {
+        let sources = self
+            .iter()
+            .map(|info| {
+                info.lock(cache_dir).map_err(|error| LockError {
+                    repo_url: info.repo_url.clone(),
+                    error,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LockFile {
+            google_fonts_rev: self.google_fonts_rev().map(str::to_owned),
+            sources,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(url: &str, sha: &str, digest: &str) -> LockedSource {
+        LockedSource {
+            repo_url: url.into(),
+            subdir: None,
+            rev: sha.into(),
+            sha: sha.into(),
+            config_hashes: BTreeMap::from([("sources/config.yaml".into(), "aaaa".into())]),
+            source_digests: BTreeMap::from([("sources/Font.glyphs".into(), digest.into())]),
+        }
+    }
+
+    #[test]
+    fn changed_since() {
+        let older = LockFile {
+            google_fonts_rev: None,
+            sources: vec![
+                locked("https://github.com/org/same", "111", "abc"),
+                locked("https://github.com/org/bumped", "222", "def"),
+                locked("https://github.com/org/edited", "333", "123"),
+            ],
+        };
+        let newer = LockFile {
+            google_fonts_rev: None,
+            sources: vec![
+                locked("https://github.com/org/same", "111", "abc"),
+                locked("https://github.com/org/bumped", "999", "def"),
+                locked("https://github.com/org/edited", "444", "456"),
+                locked("https://github.com/org/new", "555", "789"),
+            ],
+        };
+        let changed = newer
+            .changed_since(&older)
+            .into_iter()
+            .map(|locked| locked.repo_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changed,
+            [
+                "https://github.com/org/edited",
+                "https://github.com/org/new"
+            ]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! configuring log output for the binary
+
+use std::{io::Write, path::Path};
+
+use clap::ValueEnum;
+use env_logger::fmt::Formatter;
+use log::{kv::Key, LevelFilter, Log, Metadata, Record};
+
+/// The target of log messages about running git
+pub(crate) const GIT: &str = "google_fonts_sources::git";
+/// The target of log messages about reading METADATA.pb files
+pub(crate) const METADATA: &str = "google_fonts_sources::metadata";
+
+/// How log messages are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with 'ts', 'level', 'stage', 'repo' and
+    /// 'message' fields
+    Json,
+}
+
+/// A part of the program whose log messages can be made more verbose on
+/// their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogArea {
+    /// Running git: cloning, fetching and checking out repositories
+    Git,
+    /// Reading METADATA.pb files in google/fonts
+    Metadata,
+    /// Loading config files
+    Config,
+    /// Everything else, including probing repositories for config files
+    Discovery,
+}
+
+impl LogArea {
+    // messages from the crate root are 'discovery'; more specific targets win
+    fn target(self) -> &'static str {
+        match self {
+            LogArea::Git => GIT,
+            LogArea::Metadata => METADATA,
+            LogArea::Config => "google_fonts_sources::config",
+            LogArea::Discovery => "google_fonts_sources",
+        }
+    }
+}
+
+/// Install a logger writing to stderr, and optionally also to `log_file`.
+///
+/// Messages on stderr are filtered by `RUST_LOG` as usual (showing only errors
+/// by default), and then by `verbosity` and `areas` (see [`filters`]); the log
+/// file also includes info messages unless `RUST_LOG` is set.
+pub(crate) fn init(
+    log_file: Option<&Path>,
+    format: LogFormat,
+    verbosity: u8,
+    areas: &[LogArea],
+) -> Result<(), std::io::Error> {
+    let mut stderr = env_logger::Builder::from_default_env();
+    for (target, level) in filters(verbosity, areas) {
+        stderr.filter_module(target, level);
+    }
+    if format == LogFormat::Json {
+        stderr.format(write_json);
+    }
+    let mut loggers = vec![stderr.build()];
+    if let Some(path) = log_file {
+        let file = std::fs::File::create(path)?;
+        let mut builder =
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+        builder
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never);
+        if format == LogFormat::Json {
+            builder.format(write_json);
+        }
+        loggers.push(builder.build());
+    }
+    let max_level = loggers.iter().map(|logger| logger.filter()).max();
+    log::set_boxed_logger(Box::new(Tee(loggers))).expect("logger is only initialized once");
+    log::set_max_level(max_level.unwrap_or(log::LevelFilter::Off));
+    Ok(())
+}
+
+/// The log level for each of our log targets, given the number of '-v' flags
+/// and the areas they apply to.
+///
+/// One '-v' shows info messages, two show debug messages and three show
+/// everything. If `areas` is empty this applies to all of our messages
+/// (but not to those from our dependencies); otherwise the areas that
+/// were not selected are left at the default level.
+fn filters(verbosity: u8, areas: &[LogArea]) -> Vec<(&'static str, LevelFilter)> {
+    let level = match verbosity {
+        0 => return Vec::new(),
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    if areas.is_empty() {
+        return vec![(LogArea::Discovery.target(), level)];
+    }
+    LogArea::value_variants()
+        .iter()
+        .map(|area| {
+            let level = if areas.contains(area) {
+                level
+            } else {
+                LevelFilter::Error
+            };
+            (area.target(), level)
+        })
+        .collect()
+}
+
+/// Sends each message to every logger that accepts it
+struct Tee(Vec<env_logger::Logger>);
+
+impl Log for Tee {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.iter().any(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for logger in &self.0 {
+            if logger.matches(record) {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.0.iter().for_each(Log::flush)
+    }
+}
+
+fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let ts = buf.timestamp().to_string();
+    writeln!(buf, "{}", json_line(&ts, record))
+}
+
+fn json_line(ts: &str, record: &Record) -> serde_json::Value {
+    let kv = record.key_values();
+    let field = |key: &str| kv.get(Key::from(key)).map(|value| value.to_string());
+    serde_json::json!({
+        "ts": ts,
+        "level": record.level().as_str(),
+        "stage": field("stage").unwrap_or_else(|| stage_for_target(record.target()).to_owned()),
+        "repo": field("repo"),
+        "message": record.args().to_string(),
+    })
+}
+
+// messages without an explicit stage use the module they come from
+fn stage_for_target(target: &str) -> &str {
+    match target.split_once("::") {
+        Some((_, module)) => module,
+        None => "discovery",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines() {
+        let kvs = [("repo", "https://github.com/a/b"), ("stage", "probe")];
+        let line = json_line(
+            "2024-01-01T00:00:00Z",
+            &Record::builder()
+                .level(log::Level::Warn)
+                .target("google_fonts_sources")
+                .args(format_args!("no config"))
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "ts": "2024-01-01T00:00:00Z",
+                "level": "WARN",
+                "stage": "probe",
+                "repo": "https://github.com/a/b",
+                "message": "no config",
+            })
+        );
+
+        let line = json_line(
+            "2024-01-01T00:00:00Z",
+            &Record::builder()
+                .level(log::Level::Info)
+                .target("google_fonts_sources::batch")
+                .args(format_args!("hi"))
+                .build(),
+        );
+        assert_eq!(line["stage"], "batch");
+        assert!(line["repo"].is_null());
+    }
+
+    #[test]
+    fn verbosity_filters() {
+        assert!(filters(0, &[LogArea::Git]).is_empty());
+        assert_eq!(
+            filters(2, &[]),
+            [("google_fonts_sources", LevelFilter::Debug)]
+        );
+        assert_eq!(
+            filters(1, &[LogArea::Git, LogArea::Config]),
+            [
+                (GIT, LevelFilter::Info),
+                (METADATA, LevelFilter::Error),
+                ("google_fonts_sources::config", LevelFilter::Info),
+                ("google_fonts_sources", LevelFilter::Error),
+            ]
+        );
+    }
+}
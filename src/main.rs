@@ -1,9 +1,16 @@
-use clap::Parser;
-
 use google_fonts_sources::Args;
 
 fn main() {
-    env_logger::init();
-    let args = Args::parse();
-    google_fonts_sources::run(&args);
+    let args = Args::parse_with_defaults();
+    if let Err(e) = args
+        .init_logging()
+        .and_then(|()| google_fonts_sources::run(&args))
+    {
+        if args.json_errors() {
+            eprintln!("{}", e.to_json());
+        } else {
+            eprintln!("{e}");
+        }
+        std::process::exit(e.exit_code());
+    }
 }
@@ -3,30 +3,105 @@
 //! this format is defined at
 //! <https://github.com/googlefonts/gftools/blob/main/Lib/gftools/fonts_public.proto>
 
-use std::{fmt::Display, path::Path, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
 use crate::error::MetadataError;
 
 // in the future we would like to generate a type for this from the protobuf definition
 // but there's no official rust protobuf impl, and no informal impl correctly
 // handles the protobuf text format
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Metadata {
     pub(crate) name: String,
     pub(crate) repo_url: Option<String>,
+    /// the path to the config file in the source repo, if specified
+    pub(crate) config_yaml: Option<String>,
+    /// map of files in the source repo to the name they have in google/fonts
+    pub(crate) files: BTreeMap<String, String>,
+    /// the license directory in google/fonts containing this font
+    pub(crate) license: Option<License>,
+}
+
+/// The license of a font, which determines where it lives in google/fonts
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum License {
+    /// The SIL Open Font License, in the 'ofl' directory
+    Ofl,
+    /// The Apache License, in the 'apache' directory
+    Apache,
+    /// The Ubuntu Font License, in the 'ufl' directory
+    Ufl,
+}
+
+impl License {
+    pub(crate) const ALL: [License; 3] = [License::Ofl, License::Apache, License::Ufl];
+
+    /// The name of the directory in google/fonts containing fonts with this license
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            License::Ofl => "ofl",
+            License::Apache => "apache",
+            License::Ufl => "ufl",
+        }
+    }
 }
 
 /// Ways parsing metadata can fail
+#[derive(Debug)]
 pub(crate) enum BadMetadata {
     /// The required 'name' field was missing
     NoName,
 }
 
 impl Metadata {
-    pub fn load(path: &Path) -> Result<Self, MetadataError> {
-        let string = std::fs::read_to_string(path).map_err(MetadataError::Read)?;
+    /// Parse the contents of a metadata file, which must be utf-8
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Result<Self, MetadataError> {
+        let string = String::from_utf8(bytes).map_err(|e| {
+            MetadataError::Read(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })?;
         string.parse().map_err(MetadataError::Parse)
     }
+
+    /// The directory in the source repo containing this font's project, if
+    /// it is not the root.
+    ///
+    /// This is derived from the `config_yaml` field, for repositories that
+    /// contain many projects, e.g. `MyFont/sources/config.yaml`. Paths that
+    /// could point outside of the repository (such as `../sources/config.yaml`)
+    /// are ignored.
+    pub(crate) fn subdir(&self) -> Option<PathBuf> {
+        let sources_dir = Path::new(self.config_yaml.as_deref()?).parent()?;
+        if !sources_dir.ends_with("sources") && !sources_dir.ends_with("Sources") {
+            return None;
+        }
+        sources_dir
+            .parent()
+            .filter(|dir| is_contained_subdir(dir))
+            .map(Path::to_owned)
+    }
+}
+
+/// `true` if `dir` is a non-empty relative path that stays inside its parent.
+pub(crate) fn is_contained_subdir(dir: &Path) -> bool {
+    !dir.as_os_str().is_empty() && dir.components().all(|c| matches!(c, Component::Normal(_)))
 }
 
 impl FromStr for Metadata {
@@ -34,6 +109,7 @@ impl FromStr for Metadata {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         static NAME_KEY: &str = "name: ";
         static REPO_KEY: &str = "repository_url: ";
+        static CONFIG_KEY: &str = "config_yaml: ";
         let Some(pos) = s.find(NAME_KEY) else {
             return Err(BadMetadata::NoName);
         };
@@ -48,8 +124,42 @@ impl FromStr for Metadata {
             .map(|s| s.trim_end_matches('/')) // trailing / is not meaningful for a url
             .filter(|s| !s.is_empty())
             .map(str::to_owned);
-        Ok(Metadata { name, repo_url })
+        let config_yaml = s
+            .find(CONFIG_KEY)
+            .and_then(|pos| extract_litstr(&s[pos + CONFIG_KEY.len()..]))
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned);
+        let files = extract_files(s);
+        Ok(Metadata {
+            name,
+            repo_url,
+            config_yaml,
+            files,
+            license: None,
+        })
+    }
+}
+
+/// extract the `source_file`/`dest_file` pairs from the `files` fields
+fn extract_files(s: &str) -> BTreeMap<String, String> {
+    static SOURCE_KEY: &str = "source_file: ";
+    static DEST_KEY: &str = "dest_file: ";
+    let mut result = BTreeMap::new();
+    let mut rest = s;
+    while let Some(pos) = rest.find(SOURCE_KEY) {
+        rest = &rest[pos + SOURCE_KEY.len()..];
+        // the dest should be before the next source
+        let block_end = rest.find(SOURCE_KEY).unwrap_or(rest.len());
+        let block = &rest[..block_end];
+        let source = extract_litstr(block);
+        let dest = block
+            .find(DEST_KEY)
+            .and_then(|pos| extract_litstr(&block[pos + DEST_KEY.len()..]));
+        if let (Some(source), Some(dest)) = (source, dest) {
+            result.insert(source.to_owned(), dest.to_owned());
+        }
     }
+    result
 }
 
 /// extract the contents of a string literal, e.g. the stuff between the quotation marks
@@ -98,6 +208,7 @@ impl Display for BadMetadata {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn extract_str() {
@@ -110,4 +221,69 @@ mod tests {
         // ignore escaped " (but we don't actually handle the escaping)
         assert_eq!(extract_litstr(r#" "foo\"bar" "#), Some("foo\\\"bar"));
     }
+
+    #[test]
+    fn files() {
+        let meta: Metadata = r#"
+name: "Joan"
+source {
+  repository_url: "https://github.com/PaoloBiagini/Joan"
+  files {
+    source_file: "OFL.txt"
+    dest_file: "OFL.txt"
+  }
+  files {
+    source_file: "fonts/ttf/Joan-Regular.ttf"
+    dest_file: "Joan-Regular.ttf"
+  }
+}
+"#
+        .parse()
+        .unwrap();
+        assert_eq!(meta.files.len(), 2);
+        assert_eq!(meta.subdir(), None);
+        assert_eq!(
+            meta.files
+                .get("fonts/ttf/Joan-Regular.ttf")
+                .map(String::as_str),
+            Some("Joan-Regular.ttf")
+        );
+    }
+
+    #[test]
+    fn subdir_from_config_yaml() {
+        let meta = |config: &str| Metadata {
+            name: "Test".into(),
+            repo_url: None,
+            config_yaml: Some(config.into()),
+            files: Default::default(),
+            license: None,
+        };
+        assert_eq!(meta("sources/config.yaml").subdir(), None);
+        assert_eq!(
+            meta("fonts/MyFont/sources/config.yaml").subdir(),
+            Some(PathBuf::from("fonts/MyFont"))
+        );
+        assert_eq!(meta("MyFont/config.yaml").subdir(), None);
+        assert_eq!(meta("../MyFont/sources/config.yaml").subdir(), None);
+        assert_eq!(meta("/tmp/sources/config.yaml").subdir(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn parsing_never_panics(
+            s in r#"(name: |repository_url: |config_yaml: |files \{|source_file: |dest_file: |"|\\|[a-zâ/.]|\s)*|\PC*"#
+        ) {
+            if let Ok(metadata) = s.parse::<Metadata>() {
+                if let Some(subdir) = metadata.subdir() {
+                    prop_assert!(subdir.is_relative());
+                }
+            }
+        }
+
+        #[test]
+        fn parsing_arbitrary_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..500)) {
+            let _ = String::from_utf8_lossy(&bytes).parse::<Metadata>();
+        }
+    }
 }
@@ -0,0 +1,128 @@
+//! remembering parsed METADATA.pb files, so that unchanged ones are not parsed
+//! again
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::MetadataError, metadata::Metadata, repo_info::hex_string, source_set::replace_file,
+};
+
+const FILE_NAME: &str = "gfsources-metadata-index.json";
+
+/// The parsed contents of each metadata file, by the digest of its bytes
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct MetadataIndex {
+    /// Keyed by the font's directory, relative to the root of google/fonts
+    entries: BTreeMap<PathBuf, IndexEntry>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct IndexEntry {
+    sha256: String,
+    metadata: Metadata,
+}
+
+impl MetadataIndex {
+    /// The location of the index for a given cache directory
+    pub(crate) fn path(git_cache_dir: &Path) -> PathBuf {
+        git_cache_dir.join(FILE_NAME)
+    }
+
+    /// Load the index at `path`, or return an empty one if there isn't one
+    /// (or it can't be read.)
+    pub(crate) fn load(path: &Path) -> Self {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Default::default();
+        };
+        serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!(
+                "ignoring unreadable metadata index '{}': {e}",
+                path.display()
+            );
+            Default::default()
+        })
+    }
+
+    /// Parse the metadata file for the font in `font_dir` (relative to the
+    /// root of google/fonts), reusing the previous result if its contents have
+    /// not changed.
+    ///
+    /// Returns the digest of the file, to be [recorded](Self::insert) if it
+    /// was parsed successfully.
+    pub(crate) fn parse(
+        &self,
+        font_dir: &Path,
+        bytes: Vec<u8>,
+    ) -> (String, Result<Metadata, MetadataError>) {
+        let sha256 = hex_string(&Sha256::digest(&bytes));
+        if let Some(entry) = self
+            .entries
+            .get(font_dir)
+            .filter(|entry| entry.sha256 == sha256)
+        {
+            return (sha256, Ok(entry.metadata.clone()));
+        }
+        (sha256, Metadata::from_bytes(bytes))
+    }
+
+    /// Record the parsed metadata for a font
+    pub(crate) fn insert(&mut self, font_dir: PathBuf, sha256: String, metadata: &Metadata) {
+        let entry = IndexEntry {
+            sha256,
+            metadata: metadata.clone(),
+        };
+        if self.entries.get(&font_dir) != Some(&entry) {
+            self.entries.insert(font_dir, entry);
+            self.changed = true;
+        }
+    }
+
+    /// Forget every font whose directory is not in `font_dirs`
+    pub(crate) fn retain(&mut self, font_dirs: &[PathBuf]) {
+        let before = self.entries.len();
+        self.entries
+            .retain(|dir, _| font_dirs.binary_search(dir).is_ok());
+        self.changed |= self.entries.len() != before;
+    }
+
+    /// Write the index to `path`, if anything has changed since it was loaded.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+        replace_file(path, &serde_json::to_vec(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_unchanged_metadata() {
+        let mut index = MetadataIndex::default();
+        let dir = Path::new("ofl/foo");
+        let (sha256, metadata) = index.parse(dir, b"name: \"Foo\"\n".to_vec());
+        let mut metadata = metadata.unwrap();
+        index.insert(dir.to_owned(), sha256, &metadata);
+        assert!(index.changed);
+
+        // a result that we could only have gotten from the index
+        metadata.name = "Cached".into();
+        index.insert(dir.to_owned(), index.entries[dir].sha256.clone(), &metadata);
+        let (_, reused) = index.parse(dir, b"name: \"Foo\"\n".to_vec());
+        assert_eq!(reused.unwrap().name, "Cached");
+        let (_, parsed) = index.parse(dir, b"name: \"Bar\"\n".to_vec());
+        assert_eq!(parsed.unwrap().name, "Bar");
+
+        index.retain(&[PathBuf::from("ofl/other")]);
+        assert!(index.entries.is_empty());
+    }
+}
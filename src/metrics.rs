@@ -0,0 +1,269 @@
+//! how long discovery took, and where the time went
+
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// Wall-clock timings for a discovery run.
+///
+/// This is included in the [`DiscoveryReport`], and can be used to find the
+/// repositories that dominate the time discovery takes.
+///
+/// [`DiscoveryReport`]: crate::DiscoveryReport
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Metrics {
+    /// Time spent cloning or updating the google/fonts checkout
+    pub update_google_fonts: Duration,
+    /// Time spent reading and selecting fonts in google/fonts
+    pub metadata: Duration,
+    /// Time spent looking for config files in font repositories.
+    ///
+    /// This starts while google/fonts is still being read, so it overlaps
+    /// [`metadata`](Self::metadata).
+    pub probe: Duration,
+    /// The time the whole run took
+    pub total: Duration,
+    /// The time spent on each font's repository, slowest first
+    pub repos: Vec<RepoTiming>,
+}
+
+/// The time spent looking for config files for one font
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RepoTiming {
+    /// The repository url
+    pub repo_url: String,
+    /// The name of the family
+    pub family: String,
+    /// How long it took, including any time spent waiting out rate limits
+    pub elapsed: Duration,
+    /// Where that time went
+    #[serde(default)]
+    pub phases: RepoPhases,
+}
+
+/// The time spent on one font's repository, by what we were doing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RepoPhases {
+    /// Reading and parsing the font's METADATA.pb file
+    pub metadata: Duration,
+    /// Asking the repository's host for its current commit and config files
+    pub probe: Duration,
+    /// Cloning the repository
+    pub clone: Duration,
+    /// Updating an existing checkout and looking for config files in it
+    pub checkout: Duration,
+}
+
+impl std::ops::AddAssign for RepoPhases {
+    fn add_assign(&mut self, other: Self) {
+        self.metadata += other.metadata;
+        self.probe += other.probe;
+        self.clone += other.clone;
+        self.checkout += other.checkout;
+    }
+}
+
+/// The phases recorded with [`timed`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Probe,
+    Clone,
+    Checkout,
+}
+
+thread_local! {
+    // the phases of the repository being checked on this thread
+    static PHASES: Cell<RepoPhases> = Cell::new(RepoPhases::default());
+}
+
+/// Run `f`, adding the time it takes to `phase` for the repository being
+/// checked on this thread (see [`take_phases`])
+pub(crate) fn timed<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    PHASES.with(|phases| {
+        let mut current = phases.get();
+        match phase {
+            Phase::Probe => current.probe += elapsed,
+            Phase::Clone => current.clone += elapsed,
+            Phase::Checkout => current.checkout += elapsed,
+        }
+        phases.set(current);
+    });
+    result
+}
+
+/// The time recorded on this thread since the last call, which is then reset
+pub(crate) fn take_phases() -> RepoPhases {
+    PHASES.with(Cell::take)
+}
+
+impl Metrics {
+    /// The `n` repositories that took the longest
+    pub fn slowest(&self, n: usize) -> &[RepoTiming] {
+        &self.repos[..n.min(self.repos.len())]
+    }
+
+    /// A human readable summary, listing the time spent in each stage and the
+    /// `n_slowest` slowest repositories
+    pub fn summary(&self, n_slowest: usize) -> String {
+        let mut out = format!(
+            "total {}\n  update google/fonts  {}\n  read metadata        {}\n  probe repositories   {}\n",
+            fmt_duration(self.total),
+            fmt_duration(self.update_google_fonts),
+            fmt_duration(self.metadata),
+            fmt_duration(self.probe),
+        );
+        let slowest = self.slowest(n_slowest);
+        if !slowest.is_empty() {
+            out.push_str(&format!("slowest {} repositories:\n", slowest.len()));
+            for timing in slowest {
+                out.push_str(&format!(
+                    "  {:>8}  {} ({})\n",
+                    fmt_duration(timing.elapsed),
+                    timing.repo_url,
+                    timing.family
+                ));
+            }
+        }
+        out
+    }
+
+    /// A human readable profile: the total time spent in each phase across all
+    /// repositories, and the `n_slowest` repositories (adding up the time
+    /// spent on every font in the same repository) with their phases.
+    pub fn profile(&self, n_slowest: usize) -> String {
+        let mut by_repo = BTreeMap::<&str, (Duration, RepoPhases)>::new();
+        let mut totals = RepoPhases::default();
+        for timing in &self.repos {
+            let (elapsed, phases) = by_repo.entry(&timing.repo_url).or_default();
+            *elapsed += timing.elapsed;
+            *phases += timing.phases;
+            totals += timing.phases;
+        }
+        let mut out = format!(
+            "time in each phase, over {} repositories:\n  metadata  {}\n  probe     {}\n  clone     {}\n  checkout  {}\n",
+            by_repo.len(),
+            fmt_duration(totals.metadata),
+            fmt_duration(totals.probe),
+            fmt_duration(totals.clone),
+            fmt_duration(totals.checkout),
+        );
+        let mut by_repo = by_repo.into_iter().collect::<Vec<_>>();
+        by_repo.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(b.0)));
+        by_repo.truncate(n_slowest);
+        if !by_repo.is_empty() {
+            out.push_str(&format!(
+                "slowest {} repositories:\n  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}\n",
+                by_repo.len(),
+                "total",
+                "metadata",
+                "probe",
+                "clone",
+                "checkout"
+            ));
+            for (repo_url, (elapsed, phases)) in by_repo {
+                out.push_str(&format!(
+                    "  {:>8}  {:>8}  {:>8}  {:>8}  {:>8}  {repo_url}\n",
+                    fmt_duration(elapsed),
+                    fmt_duration(phases.metadata),
+                    fmt_duration(phases.probe),
+                    fmt_duration(phases.clone),
+                    fmt_duration(phases.checkout),
+                ));
+            }
+        }
+        out
+    }
+
+    // sort the repos, slowest first
+    pub(crate) fn finish(&mut self) {
+        self.repos
+            .sort_by(|a, b| b.elapsed.cmp(&a.elapsed).then(a.family.cmp(&b.family)));
+    }
+}
+
+fn fmt_duration(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_repos() {
+        let mut metrics = Metrics {
+            total: Duration::from_secs(10),
+            probe: Duration::from_secs(9),
+            ..Default::default()
+        };
+        for (family, secs) in [("Fast", 1), ("Slow", 5), ("Medium", 3)] {
+            metrics.repos.push(RepoTiming {
+                repo_url: format!("https://github.com/fonts/{family}"),
+                family: family.into(),
+                elapsed: Duration::from_secs(secs),
+                phases: Default::default(),
+            });
+        }
+        metrics.finish();
+        let slowest = metrics.slowest(2);
+        assert_eq!(
+            slowest
+                .iter()
+                .map(|t| t.family.as_str())
+                .collect::<Vec<_>>(),
+            ["Slow", "Medium"]
+        );
+        assert_eq!(metrics.slowest(10).len(), 3);
+
+        let summary = metrics.summary(1);
+        assert!(summary.starts_with("total 10.00s\n"), "{summary}");
+        assert!(summary.contains("slowest 1 repositories:\n"));
+        assert!(summary.contains("5.00s  https://github.com/fonts/Slow (Slow)"));
+        assert!(!summary.contains("Medium"));
+    }
+
+    #[test]
+    fn profile_by_repo() {
+        let mut metrics = Metrics::default();
+        let phases = |clone, checkout| RepoPhases {
+            clone: Duration::from_secs(clone),
+            checkout: Duration::from_secs(checkout),
+            ..Default::default()
+        };
+        // two families in the same repository
+        for (repo, family, clone, checkout) in [
+            ("fonts/Shared", "Sans", 4, 1),
+            ("fonts/Shared", "Serif", 0, 1),
+            ("fonts/Other", "Other", 3, 0),
+        ] {
+            let phases = phases(clone, checkout);
+            metrics.repos.push(RepoTiming {
+                repo_url: format!("https://github.com/{repo}"),
+                family: family.into(),
+                elapsed: phases.clone + phases.checkout,
+                phases,
+            });
+        }
+        let profile = metrics.profile(1);
+        assert!(profile.starts_with("time in each phase, over 2 repositories:\n"));
+        assert!(profile.contains("  clone     7.00s\n"), "{profile}");
+        assert!(profile.contains(
+            "     6.00s     0.00s     0.00s     4.00s     2.00s  https://github.com/fonts/Shared\n"
+        ), "{profile}");
+        assert!(!profile.contains("fonts/Other\n"));
+
+        take_phases();
+        timed(Phase::Clone, || {
+            std::thread::sleep(Duration::from_millis(2))
+        });
+        let taken = take_phases();
+        assert!(taken.clone >= Duration::from_millis(2));
+        assert_eq!(taken.probe, Duration::ZERO);
+        assert_eq!(take_phases(), RepoPhases::default());
+    }
+}
@@ -0,0 +1,70 @@
+//! structured events for applications that embed discovery
+
+use std::time::Duration;
+
+use crate::Problem;
+
+/// Something that happened during discovery, or while checking out
+/// repositories.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// Work on a repository has started
+    RepoStarted {
+        /// The repository's url
+        repo_url: &'a str,
+        /// The family being handled, if known
+        family: Option<&'a str>,
+    },
+    /// Something unusual happened that did not stop any work
+    Warning {
+        /// The repository involved, if any
+        repo_url: Option<&'a str>,
+        /// A description of what happened
+        message: String,
+    },
+    /// A font or repository was skipped because of an error
+    Error(&'a Problem),
+    /// An attempt to reach a repository failed, and will be retried
+    Retry {
+        /// The repository's url
+        repo_url: &'a str,
+        /// The attempt that failed, starting from 1
+        attempt: u32,
+        /// How long we wait before trying again
+        delay: Duration,
+        /// What went wrong
+        error: String,
+    },
+    /// Work on a repository has finished, successfully or not
+    RepoCompleted {
+        /// The repository's url
+        repo_url: &'a str,
+        /// The family being handled, if known
+        family: Option<&'a str>,
+        /// `false` if there was an error (which was reported with an
+        /// [`Error`](Event::Error) event)
+        ok: bool,
+    },
+}
+
+/// Receives [`Event`]s as they happen.
+///
+/// This lets an application collect diagnostics without scraping log output.
+/// Events may be sent from several threads at once, so implementations must
+/// be [`Sync`]; closures taking an `&Event` can be used directly.
+pub trait Observer: Sync {
+    /// Called for each event
+    fn on_event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Sync> Observer for F {
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// An observer that ignores every event
+impl Observer for () {
+    fn on_event(&self, _event: &Event) {}
+}
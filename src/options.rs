@@ -0,0 +1,258 @@
+//! options controlling discovery
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    metadata::Metadata,
+    repo_info::{hex_string, host_for_url, repo_name_and_org_from_url, url_has_prefix},
+    CloneBranches, RepoHost,
+};
+
+/// Options for [`discover_sources_with_options`].
+///
+/// Filters are applied to the fonts in google/fonts before their repositories
+/// are probed, so a filtered run is much faster than filtering the output of
+/// a full one. Each filter that is not empty must match; the default options
+/// discover everything.
+///
+/// [`discover_sources_with_options`]: crate::discover_sources_with_options
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DiscoveryOptions {
+    /// Only include repositories owned by one of these orgs (compared
+    /// case-insensitively)
+    pub orgs: Vec<String>,
+    /// Only include these families
+    pub families: Vec<String>,
+    /// Only include repositories on one of these hosts
+    pub hosts: Vec<RepoHost>,
+    /// Only check a pseudo-random sample of this many fonts, after applying
+    /// the other filters.
+    ///
+    /// The sample depends only on `seed` and the names of the fonts, so it
+    /// is stable between runs.
+    pub sample: Option<usize>,
+    /// The seed used to choose a [`sample`](Self::sample)
+    pub seed: u64,
+    /// Only check at most this many fonts, after applying the other filters
+    pub limit: Option<usize>,
+    /// Only use what is already in the cache.
+    ///
+    /// Nothing is cloned or fetched, and no http requests are made; the
+    /// existing checkout of google/fonts must be present. Fonts whose
+    /// repositories are not cached are reported as
+    /// [`Unreachable`](crate::ProblemKind::Unreachable).
+    pub offline: bool,
+    /// Don't update repositories that are already in the cache.
+    ///
+    /// The existing checkout of google/fonts is used as-is, and repositories
+    /// that are already checked out are inspected without fetching; anything
+    /// missing from the cache is still cloned or probed over http.
+    pub no_fetch: bool,
+    /// Only include fonts whose metadata in google/fonts has changed since
+    /// this rev of google/fonts, or this date (in any format understood by
+    /// `git log --since`.)
+    pub since: Option<String>,
+    /// Resume an interrupted run.
+    ///
+    /// During discovery, partial results are periodically saved in the cache
+    /// directory; with this set, fonts that were already checked by a previous
+    /// run (with the same rev of google/fonts) are not checked again, and their
+    /// results are reused. The saved results are removed when discovery
+    /// finishes.
+    pub resume: bool,
+    /// Reuse the results of the previous run if google/fonts has not changed.
+    ///
+    /// With this set, the results of each run are saved in the cache
+    /// directory, along with the rev of google/fonts they were found at. If
+    /// the next run (with the same options) finds google/fonts at the same
+    /// rev, those results are returned without checking any fonts. Note that
+    /// this means new commits in the fonts' own repositories are not noticed
+    /// until google/fonts changes.
+    pub reuse_cached: bool,
+    /// Which branches are fetched when a repository (including google/fonts)
+    /// has to be cloned
+    pub clone_branches: CloneBranches,
+    /// Environment variables holding tokens for private repositories, keyed
+    /// by url prefix (ignoring the scheme), e.g. `github.com/my-org`.
+    ///
+    /// Repositories under a prefix are probed and cloned with its token, and
+    /// the variable is recorded in their [`token_env`]. If more than one
+    /// prefix matches, the longest is used.
+    ///
+    /// [`token_env`]: crate::RepoInfo::token_env
+    pub tokens: BTreeMap<String, String>,
+}
+
+/// How discovery may use the network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Network {
+    /// Clone, fetch and probe as needed
+    Full,
+    /// Clone and probe repositories that aren't cached, but don't update
+    /// those that are
+    NoFetch,
+    /// Only use what is already cached
+    Offline,
+}
+
+impl Network {
+    /// `true` if existing checkouts can be updated
+    pub(crate) fn can_fetch(self) -> bool {
+        self == Network::Full
+    }
+
+    /// `true` if repositories that aren't cached can be cloned or probed
+    pub(crate) fn can_clone(self) -> bool {
+        self != Network::Offline
+    }
+}
+
+impl DiscoveryOptions {
+    pub(crate) fn network(&self) -> Network {
+        if self.offline {
+            Network::Offline
+        } else if self.no_fetch {
+            Network::NoFetch
+        } else {
+            Network::Full
+        }
+    }
+
+    /// The variable holding the token for the repository at `repo_url`, if
+    /// there is one in [`tokens`](Self::tokens)
+    pub(crate) fn token_env(&self, repo_url: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .filter(|(prefix, _)| url_has_prefix(repo_url, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, var)| var.as_str())
+    }
+
+    /// Apply the filters, sample and limit to the fonts to be checked
+    pub(crate) fn select(&self, mut candidates: BTreeSet<Metadata>) -> BTreeSet<Metadata> {
+        candidates.retain(|metadata| self.matches(metadata));
+        if let Some(sample) = self.sample {
+            let mut keyed = candidates
+                .into_iter()
+                .map(|metadata| (self.sample_key(&metadata), metadata))
+                .collect::<Vec<_>>();
+            keyed.sort();
+            candidates = keyed
+                .into_iter()
+                .take(sample)
+                .map(|(_, metadata)| metadata)
+                .collect();
+        }
+        if let Some(limit) = self.limit {
+            candidates = candidates.into_iter().take(limit).collect();
+        }
+        candidates
+    }
+
+    /// A digest of the options that affect which sources are found, used to
+    /// check that cached results are still valid
+    pub(crate) fn cache_key(&self) -> String {
+        let key = format!(
+            "{:?}",
+            (
+                &self.orgs,
+                &self.families,
+                &self.hosts,
+                self.sample,
+                self.seed,
+                self.limit,
+                &self.since,
+                self.network(),
+                &self.tokens,
+            )
+        );
+        hex_string(&Sha256::digest(key.as_bytes()))
+    }
+
+    // we don't need real randomness, just an order that is stable
+    // across platforms and compiler versions
+    fn sample_key(&self, metadata: &Metadata) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_le_bytes());
+        hasher.update(metadata.name.as_bytes());
+        hasher.update(metadata.repo_url.as_deref().unwrap_or_default().as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// `true` if a font with this metadata should be checked
+    pub(crate) fn matches(&self, metadata: &Metadata) -> bool {
+        let url = metadata.repo_url.as_deref().unwrap_or_default();
+        let org = repo_name_and_org_from_url(url).map(|(org, _)| org);
+        (self.families.is_empty() || self.families.contains(&metadata.name))
+            && (self.orgs.is_empty()
+                || org.is_some_and(|org| self.orgs.iter().any(|o| o.eq_ignore_ascii_case(org))))
+            && (self.hosts.is_empty() || self.hosts.contains(&host_for_url(url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(name: &str, url: &str) -> Metadata {
+        Metadata {
+            name: name.into(),
+            repo_url: Some(url.into()),
+            config_yaml: None,
+            files: Default::default(),
+            license: None,
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let font = metadata("Family", "https://github.com/GoogleFonts/family");
+        assert!(DiscoveryOptions::default().matches(&font));
+
+        let mut options = DiscoveryOptions::default();
+        options.orgs.push("googlefonts".into());
+        assert!(options.matches(&font));
+        options.hosts.push(RepoHost::GitLab);
+        assert!(!options.matches(&font));
+        options.hosts.push(RepoHost::GitHub);
+        assert!(options.matches(&font));
+        options.families.push("Other".into());
+        assert!(!options.matches(&font));
+    }
+
+    #[test]
+    fn sample() {
+        let fonts = (0..20)
+            .map(|i| metadata(&format!("Family{i}"), "https://github.com/org/family"))
+            .collect::<BTreeSet<_>>();
+        let mut options = DiscoveryOptions {
+            sample: Some(5),
+            seed: 42,
+            ..Default::default()
+        };
+        let first = options.select(fonts.clone());
+        assert_eq!(first.len(), 5);
+        assert_eq!(options.select(fonts.clone()), first);
+        // not just the first five
+        assert_ne!(first, fonts.iter().take(5).cloned().collect());
+
+        options.seed = 7;
+        assert_ne!(options.select(fonts.clone()), first);
+        options.limit = Some(2);
+        assert_eq!(options.select(fonts).len(), 2);
+    }
+
+    #[test]
+    fn longest_token_prefix_wins() {
+        let mut options = DiscoveryOptions::default();
+        options.tokens.insert("github.com".into(), "GITHUB".into());
+        options.tokens.insert("github.com/org".into(), "ORG".into());
+        let token = |url| options.token_env(url);
+        assert_eq!(token("https://github.com/org/font"), Some("ORG"));
+        assert_eq!(token("https://github.com/org-other/font"), Some("GITHUB"));
+        assert_eq!(token("https://gitlab.com/org/font"), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! families skipped during discovery, and why
+
+use std::{fmt::Display, path::PathBuf};
+
+use crate::{credentials::redact, ErrorCode, RepoInfo};
+
+/// A font in google/fonts that was skipped during discovery because of an
+/// error.
+///
+/// Fonts that are skipped for expected reasons (for instance because they
+/// have no repository url, or their repository has no config file) are not
+/// problems.
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+pub struct Problem {
+    /// The name of the family, if its metadata could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    /// The font's directory in google/fonts, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// The repository url, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo_url: Option<String>,
+    /// The kind of problem
+    pub kind: ProblemKind,
+    /// A stable code for the underlying error, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    /// A description of what went wrong
+    pub message: String,
+}
+
+/// The reason a font was skipped
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ProblemKind {
+    /// The repository url is not in a format we understand
+    UnfamiliarUrl,
+    /// The current commit of the repository could not be determined
+    MissingCommit,
+    /// The font's METADATA.pb file could not be read or parsed
+    BadMetadata,
+    /// Looking for config files in the repository failed
+    ProbeFailed,
+    /// The repository has no config file.
+    ///
+    /// This is expected for most fonts in google/fonts, and is only reported
+    /// for repositories that were requested explicitly.
+    NoConfig,
+    /// The repository url is malformed or unsafe to use
+    InvalidUrl,
+    /// The repository could not be cloned or checked out
+    CheckoutFailed,
+    /// A config file could not be read or parsed
+    BadConfig,
+    /// A config file lists a source that does not exist
+    MissingSource,
+    /// The repository could not be reached, because it is not cached and
+    /// discovery was offline
+    Unreachable,
+}
+
+/// The result of checking every entry in a [`SourceSet`].
+///
+/// [`SourceSet`]: crate::SourceSet
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CheckReport {
+    /// The number of entries that were checked
+    pub checked: usize,
+    /// The number of entries in repositories with no problems
+    pub passed: usize,
+    /// Every problem that was found
+    pub failures: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// `true` if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Problem {
+    /// A problem with an entry in a source set
+    pub(crate) fn for_entry(info: &RepoInfo, kind: ProblemKind, message: String) -> Self {
+        Problem {
+            family_name: info.family_name().map(str::to_owned),
+            path: None,
+            repo_url: Some(info.repo_url.clone()),
+            kind,
+            code: Some(kind.code()),
+            message: redact(&message),
+        }
+    }
+
+    /// Replace this problem's [`ErrorCode`] with a more specific one
+    pub(crate) fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// This problem as a GitHub Actions workflow command.
+    ///
+    /// Printed during a workflow run, this is shown as an annotation on the
+    /// run. Problems with the data (such as bad metadata or config files) are
+    /// errors, and problems reaching repositories are warnings.
+    pub fn github_annotation(&self) -> String {
+        let level = if self.kind.is_data_error() {
+            "error"
+        } else {
+            "warning"
+        };
+        let title = escape_property(self.kind.description());
+        let message = escape_data(&self.to_string());
+        format!("::{level} title={title}::{message}")
+    }
+}
+
+impl ProblemKind {
+    /// The [`ErrorCode`] usually associated with this kind of problem
+    pub fn code(self) -> ErrorCode {
+        match self {
+            ProblemKind::UnfamiliarUrl => ErrorCode::UnknownHost,
+            ProblemKind::MissingCommit => ErrorCode::NoCommit,
+            ProblemKind::BadMetadata => ErrorCode::BadMetadata,
+            ProblemKind::ProbeFailed | ProblemKind::CheckoutFailed => ErrorCode::Git,
+            ProblemKind::NoConfig => ErrorCode::NoConfig,
+            ProblemKind::InvalidUrl => ErrorCode::InvalidUrl,
+            ProblemKind::BadConfig => ErrorCode::BadConfig,
+            ProblemKind::MissingSource => ErrorCode::MissingSource,
+            ProblemKind::Unreachable => ErrorCode::NotCached,
+        }
+    }
+
+    // problems that need a fix in google/fonts or the repository, as opposed
+    // to those that may go away on their own
+    fn is_data_error(self) -> bool {
+        matches!(
+            self,
+            ProblemKind::UnfamiliarUrl
+                | ProblemKind::BadMetadata
+                | ProblemKind::InvalidUrl
+                | ProblemKind::BadConfig
+                | ProblemKind::MissingSource
+        )
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            ProblemKind::UnfamiliarUrl => "Unfamiliar repository url",
+            ProblemKind::MissingCommit => "Missing commit",
+            ProblemKind::BadMetadata => "Bad metadata",
+            ProblemKind::ProbeFailed => "Probing repository failed",
+            ProblemKind::NoConfig => "No config file",
+            ProblemKind::InvalidUrl => "Invalid repository url",
+            ProblemKind::CheckoutFailed => "Checkout failed",
+            ProblemKind::BadConfig => "Bad config file",
+            ProblemKind::MissingSource => "Missing source",
+            ProblemKind::Unreachable => "Repository unreachable",
+        }
+    }
+}
+
+// the escaping used by workflow commands, from '@actions/core'
+fn escape_data(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(text: &str) -> String {
+    escape_data(text).replace(':', "%3A").replace(',', "%2C")
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .family_name
+            .as_deref()
+            .or(self.repo_url.as_deref())
+            .unwrap_or("unknown");
+        write!(f, "{name}: {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn github_annotations() {
+        let problem = Problem {
+            family_name: Some("Foo".into()),
+            path: None,
+            repo_url: None,
+            kind: ProblemKind::BadMetadata,
+            code: None,
+            message: "100% broken\non two lines".into(),
+        };
+        assert_eq!(
+            problem.github_annotation(),
+            "::error title=Bad metadata::Foo: 100%25 broken%0Aon two lines"
+        );
+        let problem = Problem {
+            kind: ProblemKind::Unreachable,
+            code: None,
+            message: "offline".into(),
+            ..problem
+        };
+        assert_eq!(
+            problem.github_annotation(),
+            "::warning title=Repository unreachable::Foo: offline"
+        );
+    }
+
+    #[test]
+    fn error_codes_are_serialized() {
+        let info = RepoInfo::new(
+            "https://github.com/hello/world".into(),
+            "abc123".into(),
+            vec!["config.yaml".into()],
+        )
+        .unwrap();
+        let problem = Problem::for_entry(&info, ProblemKind::CheckoutFailed, "oops".into());
+        assert_eq!(problem.code, Some(ErrorCode::Git));
+        let problem = problem.with_code(
+            crate::LoadRepoError::NoCommit {
+                sha: "abc123".into(),
+            }
+            .code(),
+        );
+        let json = serde_json::to_value(&problem).unwrap();
+        assert_eq!(json["code"], "E_NO_COMMIT");
+        assert_eq!(ErrorCode::NoCommit.to_string(), "E_NO_COMMIT");
+        let back: Problem = serde_json::from_value(json).unwrap();
+        assert_eq!(back, problem);
+    }
+}
@@ -0,0 +1,159 @@
+//! reporting progress during long operations
+
+use std::{
+    io::IsTerminal,
+    time::{Duration, Instant},
+};
+
+use kdam::{tqdm, Bar, BarExt};
+
+/// How often to log progress when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Progress through a fixed number of items.
+///
+/// If stderr is a terminal this draws a progress bar showing the current
+/// item and the estimated time remaining; otherwise a summary is logged
+/// periodically.
+pub(crate) struct Progress {
+    bar: Option<Bar>,
+    desc: &'static str,
+    total: usize,
+    done: usize,
+    cloned: usize,
+    started: Instant,
+    last_log: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(total: usize, desc: &'static str) -> Self {
+        let bar = std::io::stderr()
+            .is_terminal()
+            .then(|| tqdm!(total = total, desc = desc));
+        let now = Instant::now();
+        Progress {
+            bar,
+            desc,
+            total,
+            done: 0,
+            cloned: 0,
+            started: now,
+            last_log: now,
+        }
+    }
+
+    /// Add `n` items to the total, for work that is found as it goes
+    pub(crate) fn add_to_total(&mut self, n: usize) {
+        self.total += n;
+        if let Some(bar) = self.bar.as_mut() {
+            bar.total += n;
+        }
+    }
+
+    /// Show the name of the item currently being worked on
+    pub(crate) fn set_current(&mut self, name: &str) {
+        let postfix = format!("{} cloned, {name}", self.cloned);
+        if let Some(bar) = self.bar.as_mut() {
+            bar.set_postfix(postfix);
+            let _ = bar.refresh();
+        }
+    }
+
+    /// Record that a repository had to be cloned
+    pub(crate) fn cloned(&mut self) {
+        self.cloned += 1;
+    }
+
+    /// Record that an item is finished
+    pub(crate) fn finish_one(&mut self) {
+        self.done += 1;
+        match self.bar.as_mut() {
+            Some(bar) => {
+                let _ = bar.update(1);
+            }
+            None if self.last_log.elapsed() >= LOG_INTERVAL || self.done == self.total => {
+                self.last_log = Instant::now();
+                log::info!("{}", self.summary());
+            }
+            None => (),
+        }
+    }
+
+    /// Print a message without disrupting the progress bar
+    pub(crate) fn write(&mut self, msg: &str) {
+        match self.bar.as_mut() {
+            Some(bar) => {
+                let _ = bar.write(msg);
+            }
+            None => eprintln!("{msg}"),
+        }
+    }
+
+    /// Wait for `seconds`, showing a countdown if possible
+    pub(crate) fn cool_down(&mut self, seconds: usize) {
+        self.write(&format!(
+            "rate limit hit, cooling down for {seconds} seconds"
+        ));
+        let mut countdown = self.bar.is_some().then(|| {
+            tqdm!(
+                total = seconds,
+                desc = "cooldown",
+                position = 1,
+                leave = false,
+                bar_format = "{desc}|{animation}| {count}/{total}"
+            )
+        });
+        for _ in 0..seconds {
+            std::thread::sleep(Duration::from_secs(1));
+            if let Some(countdown) = countdown.as_mut() {
+                let _ = countdown.update(1);
+            }
+        }
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "{}: {}/{} ({} cloned)",
+            self.desc, self.done, self.total, self.cloned
+        );
+        if let Some(eta) = eta(self.started.elapsed(), self.done, self.total) {
+            summary.push_str(&format!(", about {} remaining", display_duration(eta)));
+        }
+        summary
+    }
+}
+
+// estimate the remaining time, assuming items take about the same time
+fn eta(elapsed: Duration, done: usize, total: usize) -> Option<Duration> {
+    if done == 0 || done >= total {
+        return None;
+    }
+    Some(elapsed.mul_f64((total - done) as f64 / done as f64))
+}
+
+fn display_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates() {
+        assert_eq!(eta(Duration::from_secs(10), 0, 10), None);
+        assert_eq!(
+            eta(Duration::from_secs(10), 2, 10),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(eta(Duration::from_secs(10), 10, 10), None);
+        assert_eq!(display_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(display_duration(Duration::from_secs(125)), "2m5s");
+        assert_eq!(display_duration(Duration::from_secs(7500)), "2h5m");
+    }
+}
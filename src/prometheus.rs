@@ -0,0 +1,291 @@
+//! prometheus metrics for the long-running '--watch' mode
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Event, Observer, SkipReason};
+
+// upper bounds of the repo duration histogram, in seconds
+const BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Counters collected across discovery runs, as an [`Observer`]
+#[derive(Debug, Default)]
+pub(crate) struct WatchMetrics(Mutex<State>);
+
+#[derive(Debug, Default)]
+struct State {
+    runs: u64,
+    failed_runs: u64,
+    repos_processed: u64,
+    retries: u64,
+    failures: BTreeMap<SkipReason, u64>,
+    sources: usize,
+    last_success: Option<u64>,
+    // the number of durations at or below each bucket's bound
+    bucket_counts: [u64; BUCKETS.len()],
+    duration_count: u64,
+    duration_sum: f64,
+    started: HashMap<String, Instant>,
+}
+
+impl WatchMetrics {
+    /// Record the outcome of a discovery run, and the size of its output
+    pub(crate) fn run_finished(&self, sources: Option<usize>) {
+        let mut state = self.0.lock().unwrap();
+        state.runs += 1;
+        state.started.clear();
+        match sources {
+            Some(sources) => {
+                state.sources = sources;
+                state.last_success = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs());
+            }
+            None => state.failed_runs += 1,
+        }
+    }
+
+    /// The metrics in the prometheus text format
+    pub(crate) fn render(&self) -> String {
+        let state = self.0.lock().unwrap();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str| {
+            writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap();
+        };
+        metric("gfsources_runs_total", "counter", "Discovery runs");
+        metric(
+            "gfsources_failed_runs_total",
+            "counter",
+            "Discovery runs that failed",
+        );
+        metric(
+            "gfsources_repos_processed_total",
+            "counter",
+            "Repositories checked for config files",
+        );
+        metric(
+            "gfsources_retries_total",
+            "counter",
+            "Attempts to reach a repository that were retried",
+        );
+        metric(
+            "gfsources_failures_total",
+            "counter",
+            "Fonts skipped because of an error, by reason",
+        );
+        metric(
+            "gfsources_sources",
+            "gauge",
+            "Sources in the most recent output",
+        );
+        metric(
+            "gfsources_last_success_timestamp_seconds",
+            "gauge",
+            "When the last successful run finished",
+        );
+        metric(
+            "gfsources_repo_duration_seconds",
+            "histogram",
+            "Time spent checking each repository, including any clone",
+        );
+        writeln!(out, "gfsources_runs_total {}", state.runs).unwrap();
+        writeln!(out, "gfsources_failed_runs_total {}", state.failed_runs).unwrap();
+        writeln!(
+            out,
+            "gfsources_repos_processed_total {}",
+            state.repos_processed
+        )
+        .unwrap();
+        writeln!(out, "gfsources_retries_total {}", state.retries).unwrap();
+        for (reason, count) in &state.failures {
+            writeln!(
+                out,
+                "gfsources_failures_total{{reason=\"{}\"}} {count}",
+                reason.name()
+            )
+            .unwrap();
+        }
+        writeln!(out, "gfsources_sources {}", state.sources).unwrap();
+        if let Some(last) = state.last_success {
+            writeln!(out, "gfsources_last_success_timestamp_seconds {last}").unwrap();
+        }
+        for (bound, count) in BUCKETS.iter().zip(state.bucket_counts) {
+            writeln!(
+                out,
+                "gfsources_repo_duration_seconds_bucket{{le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "gfsources_repo_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            state.duration_count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "gfsources_repo_duration_seconds_sum {}",
+            state.duration_sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "gfsources_repo_duration_seconds_count {}",
+            state.duration_count
+        )
+        .unwrap();
+        out
+    }
+
+    /// Serve the metrics at `http://{addr}/metrics`, from a background thread
+    pub(crate) fn serve(self: &Arc<Self>, addr: SocketAddr) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!(
+            "serving metrics at http://{}/metrics",
+            listener.local_addr()?
+        );
+        let metrics = self.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| metrics.respond(stream));
+                if let Err(e) = result {
+                    log::warn!("metrics request failed: '{e}'");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // read the headers, which we don't need, up to the blank line
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = match path {
+            "/metrics" => ("200 OK", self.render()),
+            _ => ("404 Not Found", String::from("not found\n")),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+impl Observer for WatchMetrics {
+    fn on_event(&self, event: &Event) {
+        let mut state = self.0.lock().unwrap();
+        match event {
+            Event::RepoStarted { repo_url, .. } => {
+                state.started.insert(repo_url.to_string(), Instant::now());
+            }
+            Event::RepoCompleted { repo_url, .. } => {
+                state.repos_processed += 1;
+                if let Some(started) = state.started.remove(*repo_url) {
+                    let secs = started.elapsed().as_secs_f64();
+                    for (bound, count) in BUCKETS.iter().zip(state.bucket_counts.iter_mut()) {
+                        if secs <= *bound {
+                            *count += 1;
+                        }
+                    }
+                    state.duration_count += 1;
+                    state.duration_sum += secs;
+                }
+            }
+            Event::Error(problem) => {
+                *state.failures.entry(problem.kind.into()).or_default() += 1;
+            }
+            Event::Retry { .. } => state.retries += 1,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Problem, ProblemKind};
+
+    #[test]
+    fn render_metrics() {
+        let metrics = WatchMetrics::default();
+        let url = "https://github.com/org/font";
+        metrics.on_event(&Event::RepoStarted {
+            repo_url: url,
+            family: None,
+        });
+        metrics.on_event(&Event::RepoCompleted {
+            repo_url: url,
+            family: None,
+            ok: true,
+        });
+        metrics.on_event(&Event::Error(&Problem {
+            family_name: None,
+            path: None,
+            repo_url: Some(url.into()),
+            kind: ProblemKind::MissingCommit,
+            code: None,
+            message: "gone".into(),
+        }));
+        metrics.on_event(&Event::Retry {
+            repo_url: url,
+            attempt: 1,
+            delay: std::time::Duration::from_secs(60),
+            error: "rate limited".into(),
+        });
+        metrics.run_finished(Some(42));
+        metrics.run_finished(None);
+
+        let text = metrics.render();
+        for line in [
+            "gfsources_runs_total 2",
+            "gfsources_failed_runs_total 1",
+            "gfsources_repos_processed_total 1",
+            "gfsources_retries_total 1",
+            "gfsources_failures_total{reason=\"no_commit\"} 1",
+            "gfsources_sources 42",
+            "gfsources_repo_duration_seconds_bucket{le=\"0.5\"} 1",
+            "gfsources_repo_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "gfsources_repo_duration_seconds_count 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing '{line}' in\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let metrics = Arc::new(WatchMetrics::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        metrics.serve(addr).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("gfsources_runs_total 0"));
+        assert!(get("/nope").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,379 @@
+//! rendering human-readable summaries of a source set
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use crate::{RepoInfo, SourceSet, SourceSetDiff};
+
+/// Render `set` as a Markdown document, with a table per repository owner.
+///
+/// If a `diff` against a previous run is provided, the report starts with a
+/// summary of the changes, new and updated entries are marked in the tables,
+/// and removed entries are listed at the end.
+///
+/// The output is intended to be pasted into GitHub issues or status updates.
+pub fn render_markdown(set: &SourceSet, diff: Option<&SourceSetDiff>) -> String {
+    let mut out = String::new();
+    let n_orgs = set
+        .iter()
+        .map(|info| info.repo_org().to_lowercase())
+        .collect::<HashSet<_>>()
+        .len();
+    writeln!(out, "# Font sources\n").unwrap();
+    writeln!(
+        out,
+        "{} sources in {} repositories from {n_orgs} owners.",
+        set.len(),
+        set.iter()
+            .map(|info| &info.repo_url)
+            .collect::<HashSet<_>>()
+            .len(),
+    )
+    .unwrap();
+    if let Some(rev) = set.google_fonts_rev() {
+        writeln!(out, "\ngoogle/fonts rev: `{rev}`").unwrap();
+    }
+
+    let mut added = HashSet::new();
+    let mut changed = HashSet::new();
+    if let Some(diff) = diff {
+        writeln!(
+            out,
+            "\nChanges since the previous run: {} added, {} removed, {} updated.",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        )
+        .unwrap();
+        added.extend(diff.added.iter().map(RepoInfo::key));
+        changed.extend(diff.changed.iter().map(|change| change.new.key()));
+    }
+
+    let mut by_org = BTreeMap::<String, Vec<&RepoInfo>>::new();
+    for info in set {
+        by_org
+            .entry(info.repo_org().to_lowercase())
+            .or_default()
+            .push(info);
+    }
+
+    for (org, mut entries) in by_org {
+        entries.sort();
+        writeln!(out, "\n## {}\n", escape(&org)).unwrap();
+        write!(out, "| Repository | Family | Rev | Config |").unwrap();
+        if diff.is_some() {
+            write!(out, " Change |").unwrap();
+        }
+        write!(out, "\n| --- | --- | --- | --- |").unwrap();
+        if diff.is_some() {
+            write!(out, " --- |").unwrap();
+        }
+        out.push('\n');
+        for info in entries {
+            write!(out, "{}", row(info)).unwrap();
+            if diff.is_some() {
+                let change = if added.contains(&info.key()) {
+                    "added"
+                } else if changed.contains(&info.key()) {
+                    "updated"
+                } else {
+                    ""
+                };
+                write!(out, " {change} |").unwrap();
+            }
+            out.push('\n');
+        }
+    }
+
+    if let Some(diff) = diff.filter(|diff| !diff.removed.is_empty()) {
+        writeln!(out, "\n## Removed\n").unwrap();
+        writeln!(out, "| Repository | Family | Rev | Config |").unwrap();
+        writeln!(out, "| --- | --- | --- | --- |").unwrap();
+        for info in &diff.removed {
+            writeln!(out, "{}", row(info)).unwrap();
+        }
+    }
+    out
+}
+
+/// Render `set` as a standalone HTML page.
+///
+/// This has the same contents as [`render_markdown`], and is intended for
+/// publishing as a static page.
+pub fn render_html(set: &SourceSet, diff: Option<&SourceSetDiff>) -> String {
+    let markdown = render_markdown(set, diff);
+    // everything interpolated into the markdown is escaped, but make sure
+    // that nothing can end up as raw markup on the page
+    let parser = pulldown_cmark::Parser::new_ext(&markdown, pulldown_cmark::Options::ENABLE_TABLES)
+        .map(|event| match event {
+            pulldown_cmark::Event::Html(html) | pulldown_cmark::Event::InlineHtml(html) => {
+                pulldown_cmark::Event::Text(html)
+            }
+            event => event,
+        });
+    let mut body = String::new();
+    pulldown_cmark::html::push_html(&mut body, parser);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Font sources</title>\n<style>{HTML_STYLE}</style>\n</head>\n\
+         <body>\n{body}</body>\n</html>\n"
+    )
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; margin: 2em; } \
+     table { border-collapse: collapse; } \
+     th, td { border: 1px solid #ccc; padding: 0.2em 0.5em; text-align: left; }";
+
+/// Render the changes between two runs as a Markdown changelog.
+///
+/// This lists the families that were added or removed, rev bumps (with a
+/// link to compare the revs upstream, where possible) and changes to config
+/// files; it is intended for posting updates from a scheduled job.
+pub fn render_changelog(diff: &SourceSetDiff) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Changes to font sources").unwrap();
+    if diff.is_empty() {
+        writeln!(out, "\nNo changes.").unwrap();
+        return out;
+    }
+    if !diff.added.is_empty() {
+        writeln!(out, "\n## Added\n").unwrap();
+        for info in sorted_by_label(&diff.added) {
+            writeln!(
+                out,
+                "- {} ({}) at `{}`",
+                label(info),
+                info.repo_url,
+                short_rev(info.git_rev())
+            )
+            .unwrap();
+        }
+    }
+    if !diff.removed.is_empty() {
+        writeln!(out, "\n## Removed\n").unwrap();
+        for info in sorted_by_label(&diff.removed) {
+            writeln!(out, "- {} ({})", label(info), info.repo_url).unwrap();
+        }
+    }
+    let mut changed = diff.changed.iter().collect::<Vec<_>>();
+    changed.sort_by_key(|change| label(&change.new));
+    if changed.iter().any(|change| change.rev_changed()) {
+        writeln!(out, "\n## Updated\n").unwrap();
+        for change in changed.iter().filter(|change| change.rev_changed()) {
+            let (old, new) = (change.old.git_rev(), change.new.git_rev());
+            write!(
+                out,
+                "- {}: `{}` → `{}`",
+                label(&change.new),
+                short_rev(old),
+                short_rev(new)
+            )
+            .unwrap();
+            if let Some(url) = change.new.compare_url(old) {
+                write!(out, " ([compare]({url}))").unwrap();
+            }
+            out.push('\n');
+        }
+    }
+    if changed.iter().any(|change| change.config_changed()) {
+        writeln!(out, "\n## Config changes\n").unwrap();
+        for change in changed.iter().filter(|change| change.config_changed()) {
+            writeln!(
+                out,
+                "- {}: {} → {}",
+                label(&change.new),
+                config_list(&change.old),
+                config_list(&change.new)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+// how an entry is named in the changelog
+fn label(info: &RepoInfo) -> String {
+    match (info.family_name(), info.subdir.as_deref()) {
+        (Some(family), _) => family.to_owned(),
+        (None, Some(subdir)) => format!("{}/{}", info.repo_name(), subdir.display()),
+        (None, None) => info.repo_name().to_owned(),
+    }
+}
+
+fn sorted_by_label(infos: &[RepoInfo]) -> Vec<&RepoInfo> {
+    let mut infos = infos.iter().collect::<Vec<_>>();
+    infos.sort_by_key(|info| label(info));
+    infos
+}
+
+fn short_rev(rev: &str) -> &str {
+    rev.get(..7).unwrap_or(rev)
+}
+
+fn config_list(info: &RepoInfo) -> String {
+    if info.config_files.is_empty() {
+        return "(none)".into();
+    }
+    info.config_files
+        .iter()
+        .map(|path| format!("`{}`", display_path(path)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// a table row for this entry, without the trailing newline
+fn row(info: &RepoInfo) -> String {
+    let name = match info.subdir.as_deref() {
+        Some(subdir) => format!("{}/{}", info.repo_name(), subdir.display()),
+        None => info.repo_name().to_owned(),
+    };
+    let rev = short_rev(info.git_rev());
+    let configs = info
+        .config_files
+        .iter()
+        .map(|path| format!("`{}`", escape(&display_path(path))))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "| [{}]({}) | {} | `{rev}` | {configs} |",
+        escape(&name),
+        link_url(&info.repo_url),
+        escape(info.family_name().unwrap_or_default()),
+    )
+}
+
+fn display_path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// escape characters that would break a table cell, or be read as markup
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' => escaped.push(' '),
+            '|' | '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '&' | '!' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// percent-encode characters that would end a markdown link destination
+fn link_url(url: &str) -> String {
+    let mut encoded = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            ' ' | '(' | ')' | '<' | '>' | '"' | '\\' | '|' | '`' => {
+                write!(encoded, "%{:02X}", c as u32).unwrap();
+            }
+            c if c.is_control() => write!(encoded, "%{:02X}", c as u32).unwrap(),
+            _ => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(url: &str, rev: &str) -> RepoInfo {
+        RepoInfo::new(url.into(), rev.into(), vec!["config.yaml".into()]).unwrap()
+    }
+
+    #[test]
+    fn grouped_by_org() {
+        let older = SourceSet::new(vec![
+            info("https://github.com/org/gone", "aaaaaaaaaa"),
+            info("https://github.com/org/bumped", "bbbbbbbbbb"),
+        ]);
+        let newer = SourceSet::new(vec![
+            info("https://github.com/org/bumped", "cccccccccc"),
+            info("https://github.com/Org/new", "dddddddddd"),
+            info("https://github.com/other/same", "eeeeeeeeee"),
+        ]);
+        let diff = newer.diff(&older);
+        let report = render_markdown(&newer, Some(&diff));
+        assert!(report.contains("3 sources in 3 repositories from 2 owners"));
+        assert!(report.contains("2 added, 1 removed, 1 updated"));
+        let org = report.find("## org").unwrap();
+        let other = report.find("## other").unwrap();
+        let removed = report.find("## Removed").unwrap();
+        assert!(org < other && other < removed);
+        assert!(report.contains(
+            "| [new](https://github.com/Org/new) |  | `ddddddd` | `config.yaml` | added |"
+        ));
+        assert!(report.contains("`ccccccc` | `config.yaml` | updated |"));
+        assert!(report[removed..].contains("[gone](https://github.com/org/gone)"));
+
+        let plain = render_markdown(&newer, None);
+        assert!(!plain.contains("Change"));
+    }
+
+    #[test]
+    fn html() {
+        let set = SourceSet::new(vec![info("https://github.com/org/repo", "aaaaaaaaaa")]);
+        let html = render_html(&set, None);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h2>org</h2>"));
+        assert!(html.contains("<td><a href=\"https://github.com/org/repo\">repo</a></td>"));
+        assert!(html.contains("<code>aaaaaaa</code>"));
+    }
+
+    #[test]
+    fn hostile_metadata_is_escaped() {
+        let metadata = crate::metadata::Metadata {
+            name: "<img src=x onerror=alert(1)> & [x](javascript:y) | z".into(),
+            repo_url: None,
+            config_yaml: None,
+            files: Default::default(),
+            license: None,
+        };
+        let mut hostile =
+            info("https://github.com/org/repo", "aaaaaaaaaa").with_metadata(&metadata);
+        hostile.repo_url = "https://github.com/org/repo) <b>".into();
+        let set = SourceSet::new(vec![hostile]);
+        let html = render_html(&set, None);
+        assert!(!html.contains("<img"), "{html}");
+        assert!(!html.contains("<b>"), "{html}");
+        assert!(!html.contains("href=\"javascript"), "{html}");
+        assert!(html.contains("&lt;img src=x onerror=alert(1)&gt; &amp; [x](javascript:y) | z"));
+        assert!(html.contains("href=\"https://github.com/org/repo%29%20%3Cb%3E\""));
+    }
+
+    #[test]
+    fn changelog() {
+        let mut config_changed = info("https://gitlab.com/org/configs", "1111111111");
+        let older = SourceSet::new(vec![
+            info("https://github.com/org/gone", "aaaaaaaaaa"),
+            info("https://github.com/org/bumped", "bbbbbbbbbb"),
+            config_changed.clone(),
+        ]);
+        config_changed
+            .config_files
+            .push("config-italic.yaml".into());
+        let newer = SourceSet::new(vec![
+            info("https://github.com/org/bumped", "cccccccccc"),
+            info("https://github.com/org/new", "dddddddddd"),
+            config_changed,
+        ]);
+        let changelog = render_changelog(&newer.diff(&older));
+        assert!(changelog.contains("## Added\n\n- new (https://github.com/org/new) at `ddddddd`\n"));
+        assert!(changelog.contains("## Removed\n\n- gone (https://github.com/org/gone)\n"));
+        assert!(changelog.contains(
+            "- bumped: `bbbbbbb` → `ccccccc` \
+             ([compare](https://github.com/org/bumped/compare/bbbbbbbbbb...cccccccccc))"
+        ));
+        assert!(
+            changelog.contains("- configs: `config.yaml` → `config.yaml`, `config-italic.yaml`")
+        );
+        assert!(render_changelog(&older.diff(&older)).contains("No changes."));
+    }
+}
@@ -0,0 +1,1101 @@
+//! a versioned collection of repositories
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    conflict::{find_rev_conflicts, RevConflict},
+    error::{MergeConflict, SourceSetError},
+    repo_info::url_has_prefix,
+    Problem, RepoHost, RepoInfo, SkipSummary, SourceSetDiff, Stats,
+};
+
+/// The current version of the serialized [`SourceSet`] format.
+///
+/// This is the only place the version should be changed. The policy is:
+///
+/// - additive changes (new optional fields) bump the minor version. Readers
+///   accept any minor version of their major version. Unknown top-level
+///   fields are preserved in [`SourceSet::extra`], so they survive a
+///   load/save round trip; unknown fields on individual entries are dropped,
+///   so new per-entry fields are lost if an older reader rewrites the file.
+/// - any other change bumps the major version, and should come with a
+///   conversion in `migrate` so that older files can still be read.
+///
+/// History:
+///
+/// - 1.0: the first versioned format
+/// - 1.1: added `problems`
+/// - 1.2: added `rev_conflicts`
+/// - 1.3: added `skipped`
+pub(crate) const CURRENT_VERSION: Version = Version { major: 1, minor: 3 };
+
+/// The version of the serialized [`SourceSet`] format.
+///
+/// This is serialized as a string, e.g. `"1.0"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    /// Incremented for changes that older readers cannot understand
+    pub major: u16,
+    /// Incremented for additive changes
+    pub minor: u16,
+}
+
+/// How to resolve conflicts when [merging](SourceSet::merge) two sets.
+///
+/// A conflict occurs when both sets contain an entry for the same project
+/// (the same repository url and subdir) and those entries are different.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MergeStrategy {
+    /// Use the entry from the set that was discovered most recently, or the
+    /// left set if this cannot be determined.
+    #[default]
+    PreferNewer,
+    /// Always use the entry from the left (`self`) set
+    PreferLeft,
+    /// Always use the entry from the right (`other`) set
+    PreferRight,
+    /// Return an error
+    Error,
+}
+
+/// The set of repositories found during discovery, along with information
+/// about how and when they were found.
+///
+/// This is the type written by the `google-fonts-sources` tool.
+///
+/// # Stable output
+///
+/// Serialization is deterministic, so that outputs can be stored in version
+/// control with minimal diffs between runs: entries are always written sorted
+/// by repository url (and then by subdir), fields are written in a fixed
+/// order, maps are sorted by key, and [`to_json`](Self::to_json) always
+/// pretty-prints with two-space indentation and a trailing newline. The order
+/// of entries in memory is not affected.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[non_exhaustive]
+pub struct SourceSet {
+    /// The version of the format, as `"major.minor"`
+    ///
+    /// Sets loaded from an older file are written out at the current version.
+    #[serde(serialize_with = "serialize_version")]
+    version: Version,
+    /// When discovery was run, in seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    discovered_at: Option<u64>,
+    /// The commit of google/fonts that was used for discovery
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    google_fonts_rev: Option<String>,
+    /// The version of this crate that created the set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_version: Option<String>,
+    /// The repositories in this set
+    #[serde(serialize_with = "serialize_sorted")]
+    sources: Vec<RepoInfo>,
+    /// Fonts that were skipped during discovery because of an error
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<Problem>,
+    /// Repositories that different entries pin at different revs.
+    ///
+    /// This is derived from `sources`, and is kept up to date as they change.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rev_conflicts: Vec<RevConflict>,
+    /// Why the fonts in google/fonts that are not in this set were skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    skipped: Option<SkipSummary>,
+    /// Top-level fields that were not recognized when loading.
+    ///
+    /// These are generally fields added in a newer minor version of the
+    /// format; they are kept so that they are written out again on save.
+    /// Unrecognized fields on individual entries are not kept.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    #[serde(skip)]
+    index: Index,
+}
+
+/// Lookup tables from url and family name to positions in `sources`.
+///
+/// This is built the first time it is needed, and reset if the sources change.
+#[derive(Clone, Debug, Default)]
+struct Index(OnceLock<IndexTables>);
+
+#[derive(Clone, Debug, Default)]
+struct IndexTables {
+    by_url: HashMap<Arc<str>, Vec<usize>>,
+    by_family: HashMap<Arc<str>, Vec<usize>>,
+}
+
+// large sets have many entries for the same repository, so only allocate a
+// key the first time it is seen
+fn push_index(table: &mut HashMap<Arc<str>, Vec<usize>>, key: &str, idx: usize) {
+    match table.get_mut(key) {
+        Some(indices) => indices.push(idx),
+        None => {
+            table.insert(key.into(), vec![idx]);
+        }
+    }
+}
+
+impl SourceSet {
+    /// Create a new set from a list of repositories.
+    pub fn new(sources: Vec<RepoInfo>) -> Self {
+        SourceSet {
+            version: CURRENT_VERSION,
+            discovered_at: None,
+            google_fonts_rev: None,
+            tool_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            sources,
+            problems: Vec::new(),
+            rev_conflicts: Vec::new(),
+            skipped: None,
+            extra: Default::default(),
+            index: Default::default(),
+        }
+        .sources_changed()
+    }
+
+    // update anything derived from the sources
+    fn update_derived(&mut self) {
+        self.index = Default::default();
+        self.rev_conflicts = find_rev_conflicts(sorted(&self.sources));
+    }
+
+    fn sources_changed(mut self) -> Self {
+        self.update_derived();
+        self
+    }
+
+    /// Create the set returned by discovery, recording the time and the
+    /// google/fonts commit that was used.
+    pub(crate) fn discovered(
+        sources: Vec<RepoInfo>,
+        google_fonts_rev: Option<String>,
+        mut problems: Vec<Problem>,
+    ) -> Self {
+        let discovered_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+        problems.sort();
+        SourceSet {
+            discovered_at,
+            google_fonts_rev,
+            problems,
+            ..Self::new(sources)
+        }
+    }
+
+    /// Record why fonts were left out during discovery
+    pub(crate) fn with_skipped(self, skipped: SkipSummary) -> Self {
+        SourceSet {
+            skipped: Some(skipped),
+            ..self
+        }
+    }
+
+    /// Load a set from a json file, such as one written by [`save`](Self::save).
+    ///
+    /// This checks the format version before parsing, so files written by an
+    /// incompatible version of this crate produce a helpful error.
+    ///
+    /// Gzip-compressed files are decompressed transparently.
+    pub fn load(path: &Path) -> Result<Self, SourceSetError> {
+        let bytes = std::fs::read(path)?;
+        let contents = if bytes.starts_with(&GZIP_MAGIC) {
+            let mut contents = String::new();
+            flate2::read::GzDecoder::new(bytes.as_slice()).read_to_string(&mut contents)?;
+            contents
+        } else {
+            String::from_utf8(bytes)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        };
+        Self::from_json(&contents)
+    }
+
+    /// Parse a set from a json string.
+    ///
+    /// In addition to the current format, this accepts the output of versions
+    /// of this crate before the format was versioned, which was a list of
+    /// [`RepoInfo`]s; it is converted to a set without header information.
+    pub fn from_json(json: &str) -> Result<Self, SourceSetError> {
+        Self::from_value(serde_json::from_str(json)?)
+    }
+
+    // check the version and migrate to the current format before parsing
+    pub(crate) fn from_value(value: serde_json::Value) -> Result<Self, SourceSetError> {
+        if value.is_array() {
+            log::info!("converting unversioned source list to the current format");
+            let sources = serde_json::from_value(value)?;
+            return Ok(SourceSet::new(sources));
+        }
+        let version = value
+            .get("version")
+            .and_then(|v| v.as_str())
+            .ok_or(SourceSetError::NoVersion)?;
+        let version = version
+            .parse::<Version>()
+            .map_err(|e| SourceSetError::Json(serde::de::Error::custom(e)))?;
+        let value = migrate(value, version)?;
+        let set: SourceSet = serde_json::from_value(value)?;
+        Ok(set.sources_changed())
+    }
+
+    /// A [JSON Schema] describing the serialized format of a set.
+    ///
+    /// This is generated from the Rust types, and can be used to validate
+    /// files or generate bindings in other languages.
+    ///
+    /// [JSON Schema]: https://json-schema.org
+    pub fn json_schema() -> serde_json::Value {
+        schemars::schema_for!(SourceSet).to_value()
+    }
+
+    /// Write this set to a json file.
+    ///
+    /// If the path ends in `.gz` the output is gzip-compressed.
+    pub fn save(&self, path: &Path) -> Result<(), SourceSetError> {
+        write_file(path, self.to_json()?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Serialize this set as pretty-printed json.
+    pub fn to_json(&self) -> Result<String, SourceSetError> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        Ok(json)
+    }
+
+    /// Serialize this set as yaml.
+    pub fn to_yaml(&self) -> Result<String, SourceSetError> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Serialize this set as toml.
+    ///
+    /// The entries are written as an array of tables named `sources`.
+    pub fn to_toml(&self) -> Result<String, SourceSetError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Write each entry in this set as a single line of json.
+    ///
+    /// This newline-delimited format omits the header, so that the output can
+    /// be streamed and processed with line-oriented tools.
+    pub fn write_ndjson(&self, mut writer: impl Write) -> Result<(), SourceSetError> {
+        for info in sorted(&self.sources) {
+            serde_json::to_writer(&mut writer, info)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Write this set as csv, with one row per entry.
+    ///
+    /// The columns are `repo_url`, `org`, `name`, `rev`, `config`, `license`
+    /// and `family`. If an entry has multiple config files they are separated
+    /// by `;`. The header of the set is not included.
+    pub fn write_csv(&self, mut writer: impl Write) -> Result<(), SourceSetError> {
+        writeln!(writer, "repo_url,org,name,rev,config,license,family")?;
+        for info in sorted(&self.sources) {
+            let configs = info
+                .config_files
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(";");
+            let fields = [
+                info.repo_url.as_str(),
+                info.repo_org(),
+                info.repo_name(),
+                info.git_rev(),
+                configs.as_str(),
+                info.license().map(|l| l.dir_name()).unwrap_or_default(),
+                info.family_name().unwrap_or_default(),
+            ];
+            let row = fields.map(csv_field).join(",");
+            writeln!(writer, "{row}")?;
+        }
+        Ok(())
+    }
+
+    /// Combine this set with `other`, for instance to add manually specified
+    /// repositories to a discovered set.
+    ///
+    /// Entries for the same project that differ are resolved using `strategy`.
+    /// The header (discovery time, google/fonts rev) is taken from `self`.
+    pub fn merge(self, other: SourceSet, strategy: MergeStrategy) -> Result<Self, MergeConflict> {
+        let prefer_left = match strategy {
+            MergeStrategy::PreferNewer => other.discovered_at <= self.discovered_at,
+            MergeStrategy::PreferLeft | MergeStrategy::Error => true,
+            MergeStrategy::PreferRight => false,
+        };
+        let mut merged = BTreeMap::new();
+        for info in &self.sources {
+            merged.insert(info.key(), info.clone());
+        }
+        for info in &other.sources {
+            match merged.get(&info.key()) {
+                Some(existing) if existing == info => (),
+                Some(existing) if strategy == MergeStrategy::Error => {
+                    return Err(MergeConflict {
+                        repo_url: info.repo_url.clone(),
+                        left_rev: existing.git_rev().to_owned(),
+                        right_rev: info.git_rev().to_owned(),
+                    });
+                }
+                Some(_) if prefer_left => (),
+                _ => {
+                    merged.insert(info.key(), info.clone());
+                }
+            }
+        }
+        let sources = merged.into_values().collect();
+        let mut extra = other.extra;
+        extra.extend(self.extra);
+        Ok(SourceSet {
+            version: CURRENT_VERSION,
+            sources,
+            extra,
+            ..self
+        }
+        .sources_changed())
+    }
+
+    /// Return a new set containing only the entries matching `predicate`.
+    ///
+    /// The header of the new set is the same as this one.
+    pub fn filter(&self, mut predicate: impl FnMut(&RepoInfo) -> bool) -> SourceSet {
+        SourceSet {
+            sources: self
+                .iter()
+                .filter(|info| predicate(info))
+                .cloned()
+                .collect(),
+            ..self.clone_header()
+        }
+        .sources_changed()
+    }
+
+    /// Remove all entries that do not match `predicate`.
+    pub fn retain(&mut self, predicate: impl FnMut(&RepoInfo) -> bool) {
+        self.sources.retain(predicate);
+        self.update_derived();
+    }
+
+    /// Use the token in the environment variable `var` for every repository
+    /// whose url starts with `url_prefix`, ignoring the scheme.
+    ///
+    /// The prefix is a host, optionally followed by a path, and only matches
+    /// whole path segments: `github.com/my-org` does not match repositories
+    /// in `github.com/my-org-other`.
+    ///
+    /// Entries that already name a token variable are not changed. See
+    /// [`RepoInfo::token_env`].
+    pub fn set_token_env(&mut self, url_prefix: &str, var: &str) {
+        for info in &mut self.sources {
+            if info.token_env.is_none() && url_has_prefix(&info.repo_url, url_prefix) {
+                info.token_env = Some(var.to_owned());
+            }
+        }
+    }
+
+    /// Return the entries for the repository with the given url.
+    ///
+    /// There may be more than one entry for a repository that contains
+    /// multiple projects. Trailing slashes are ignored.
+    pub fn by_repo_url(&self, repo_url: &str) -> impl Iterator<Item = &RepoInfo> {
+        let indices = self.index().by_url.get(repo_url.trim_end_matches('/'));
+        self.lookup(indices)
+    }
+
+    /// Return the entries for the family with the given name.
+    pub fn by_family(&self, family_name: &str) -> impl Iterator<Item = &RepoInfo> {
+        self.lookup(self.index().by_family.get(family_name))
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        indices: Option<&'a Vec<usize>>,
+    ) -> impl Iterator<Item = &'a RepoInfo> + 'a {
+        indices.into_iter().flatten().map(|idx| &self.sources[*idx])
+    }
+
+    fn index(&self) -> &IndexTables {
+        self.index.0.get_or_init(|| {
+            let mut tables = IndexTables::default();
+            for (i, info) in self.sources.iter().enumerate() {
+                push_index(&mut tables.by_url, info.repo_url.trim_end_matches('/'), i);
+                if let Some(family) = info.family_name() {
+                    push_index(&mut tables.by_family, family, i);
+                }
+            }
+            tables
+        })
+    }
+
+    /// Return a new set containing only repositories owned by `org`.
+    ///
+    /// As with urls on most hosts, the comparison is case-insensitive.
+    pub fn filter_org(&self, org: &str) -> SourceSet {
+        self.filter(|info| info.repo_org().eq_ignore_ascii_case(org))
+    }
+
+    /// Return a new set containing only repositories on the given host.
+    pub fn filter_host(&self, host: RepoHost) -> SourceSet {
+        self.filter(|info| info.repo_host() == host)
+    }
+
+    /// Return a new set containing only entries for the named family.
+    pub fn filter_family(&self, family_name: &str) -> SourceSet {
+        self.filter(|info| info.family_name() == Some(family_name))
+    }
+
+    // a copy of this set with no sources
+    pub(crate) fn clone_header(&self) -> SourceSet {
+        SourceSet {
+            version: self.version,
+            discovered_at: self.discovered_at,
+            google_fonts_rev: self.google_fonts_rev.clone(),
+            tool_version: self.tool_version.clone(),
+            sources: Vec::new(),
+            problems: self.problems.clone(),
+            rev_conflicts: Vec::new(),
+            skipped: self.skipped.clone(),
+            extra: self.extra.clone(),
+            index: Default::default(),
+        }
+    }
+
+    /// Count the entries in this set by host, owner, license and config file,
+    /// and count repositories pinned at more than one rev.
+    pub fn stats(&self) -> Stats {
+        Stats::new(self)
+    }
+
+    /// Compare this set with an `older` one.
+    ///
+    /// The result lists entries that were added, removed, or whose rev or
+    /// config files changed.
+    pub fn diff(&self, older: &SourceSet) -> SourceSetDiff {
+        SourceSetDiff::new(older, self)
+    }
+
+    /// The version of the format this set was loaded from.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// When this set was discovered, in seconds since the unix epoch.
+    pub fn discovered_at(&self) -> Option<u64> {
+        self.discovered_at
+    }
+
+    /// The sha of the commit in google/fonts used to discover this set.
+    pub fn google_fonts_rev(&self) -> Option<&str> {
+        self.google_fonts_rev.as_deref()
+    }
+
+    /// The version of this crate that created this set.
+    pub fn tool_version(&self) -> Option<&str> {
+        self.tool_version.as_deref()
+    }
+
+    /// Fonts that were skipped during discovery because of an error.
+    ///
+    /// These are not included in the set, but are recorded so that they can
+    /// be investigated.
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// Why fonts in google/fonts were left out of this set, if it was
+    /// discovered.
+    pub fn skipped(&self) -> Option<&SkipSummary> {
+        self.skipped.as_ref()
+    }
+
+    /// Repositories that different entries in this set pin at different revs.
+    pub fn rev_conflicts(&self) -> &[RevConflict] {
+        &self.rev_conflicts
+    }
+
+    /// `true` if other entries in this set pin the same repository as `info`
+    /// at a different rev.
+    pub fn has_rev_conflict(&self, info: &RepoInfo) -> bool {
+        let url = info.repo_url.trim_end_matches('/');
+        self.rev_conflicts.iter().any(|c| c.repo_url == url)
+    }
+
+    /// The repositories in this set
+    pub fn sources(&self) -> &[RepoInfo] {
+        &self.sources
+    }
+
+    /// Iterate over the repositories in this set
+    pub fn iter(&self) -> impl Iterator<Item = &RepoInfo> {
+        self.sources.iter()
+    }
+
+    /// The number of repositories in this set
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// `true` if this set contains no repositories
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Consume the set, returning the repositories
+    pub fn into_sources(self) -> Vec<RepoInfo> {
+        self.sources
+    }
+}
+
+/// Convert a serialized set in an older format to the current one.
+///
+/// This is where conversions should go when the format changes.
+fn migrate(
+    value: serde_json::Value,
+    version: Version,
+) -> Result<serde_json::Value, SourceSetError> {
+    if version.major == CURRENT_VERSION.major {
+        if version.minor > CURRENT_VERSION.minor {
+            log::warn!(
+                "source set has version {version}, newer than {CURRENT_VERSION}; \
+                 unknown top-level fields will be preserved, unknown entry fields dropped"
+            );
+        }
+        return Ok(value);
+    }
+    // there are no older major versions yet
+    Err(SourceSetError::UnsupportedVersion {
+        found: version,
+        supported: CURRENT_VERSION,
+    })
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Write `contents` to `path`, compressing it if the path ends in `.gz`
+pub(crate) fn write_file(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let file = std::fs::File::create(path)?;
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(contents)?;
+        encoder.finish()?.sync_all()
+    } else {
+        std::fs::write(path, contents)
+    }
+}
+
+/// Like [`write_file`], but `path` is replaced atomically, so that readers
+/// never see a partially written file, and an interrupted write leaves the
+/// previous contents intact
+pub(crate) fn replace_file(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+    let Some(file_name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "output must be a file",
+        ));
+    };
+    // the name is unique to this write, so concurrent writers (in this process
+    // or another) never share a temporary file; it also keeps the extension,
+    // so it is compressed the same way
+    let id = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+    let mut tmp_name = std::ffi::OsString::from(format!(".tmp-{}-{id}-", std::process::id()));
+    tmp_name.push(file_name);
+    let tmp = path.with_file_name(tmp_name);
+    if let Err(e) = write_file(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// the index is a cache, and doesn't affect equality
+impl PartialEq for Index {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Index {}
+
+// whatever was loaded, the output is in the current format
+fn serialize_version<S: serde::Serializer>(
+    version: &Version,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+    (*version).max(CURRENT_VERSION).serialize(serializer)
+}
+
+fn serialize_sorted<S: serde::Serializer>(
+    sources: &[RepoInfo],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+    sorted(sources).serialize(serializer)
+}
+
+// the order in which entries are written
+fn sorted(sources: &[RepoInfo]) -> Vec<&RepoInfo> {
+    let mut sorted = sources.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.key().cmp(&b.key()).then(a.cmp(b)));
+    sorted
+}
+
+// quote a field if needed, per RFC 4180
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+impl<'a> IntoIterator for &'a SourceSet {
+    type Item = &'a RepoInfo;
+    type IntoIter = std::slice::Iter<'a, RepoInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.sources.iter()
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl schemars::JsonSchema for Version {
+    fn schema_name() -> Cow<'static, str> {
+        "Version".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "pattern": r"^\d+\.\d+$",
+        })
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .split_once('.')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or_else(|| format!("invalid version '{s}'"))?;
+        Ok(Version { major, minor })
+    }
+}
+
+impl serde::Serialize for Version {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Version {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parsing_never_panics(
+            version in r"[0-9]{0,3}(\.[0-9]{0,3})?|\PC*",
+            sources in r#"\[(\{"repo_url": "[a-z:/.]*", "rev": "", "config_files": \[\]\},?)*\]|\PC*"#,
+        ) {
+            let _ = SourceSet::from_json(&format!(
+                r#"{{"version": "{version}", "sources": {sources}}}"#
+            ));
+            let _ = SourceSet::from_json(&version);
+        }
+
+        #[test]
+        fn loading_arbitrary_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..200)) {
+            let temp = tempfile::tempdir().unwrap();
+            for (name, prefix) in [("plain.json", &[][..]), ("gzipped.json.gz", &GZIP_MAGIC[..])] {
+                let path = temp.path().join(name);
+                std::fs::write(&path, [prefix, &bytes].concat()).unwrap();
+                let _ = SourceSet::load(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn version_roundtrip() {
+        let version: Version = "1.12".parse().unwrap();
+        assert_eq!(
+            version,
+            Version {
+                major: 1,
+                minor: 12
+            }
+        );
+        assert_eq!(version.to_string(), "1.12");
+        assert!("1".parse::<Version>().is_err());
+        assert!("1.x".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn header_is_serialized() {
+        let problem = Problem {
+            family_name: Some("Bad Font".into()),
+            path: None,
+            repo_url: Some("https://example.com/bad".into()),
+            kind: crate::ProblemKind::UnfamiliarUrl,
+            code: None,
+            message: "oh no".into(),
+        };
+        let set = SourceSet::discovered(Vec::new(), Some("abc123".into()), vec![problem]);
+        let json = serde_json::to_string(&set).unwrap();
+        assert!(
+            json.starts_with(&format!(
+                r#"{{"version":"{CURRENT_VERSION}","discovered_at":"#
+            )),
+            "{json}"
+        );
+        assert!(json.contains(r#""kind":"unfamiliar_url""#), "{json}");
+        let loaded: SourceSet = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, set);
+        assert_eq!(loaded.google_fonts_rev(), Some("abc123"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("sources.json");
+        let set = SourceSet::discovered(Vec::new(), None, Vec::new());
+        set.save(&path).unwrap();
+        assert_eq!(SourceSet::load(&path).unwrap(), set);
+    }
+
+    #[test]
+    fn stable_output() {
+        let one = RepoInfo::new("https://github.com/org/a".into(), "1".into(), Vec::new()).unwrap();
+        let two = RepoInfo::new("https://github.com/org/b".into(), "2".into(), Vec::new()).unwrap();
+        let mut set = SourceSet::new(vec![two.clone(), one.clone()]);
+        set.extra.insert("zzz".into(), 1.into());
+        set.extra.insert("aaa".into(), 2.into());
+        let json = set.to_json().unwrap();
+        assert!(json.ends_with("}\n"));
+        assert!(json.find("org/a").unwrap() < json.find("org/b").unwrap());
+        assert!(json.find("aaa").unwrap() < json.find("zzz").unwrap());
+        // the in-memory order is unchanged
+        assert_eq!(set.sources(), [two.clone(), one.clone()]);
+        let mut reordered = SourceSet::new(vec![one, two]);
+        reordered.extra.insert("aaa".into(), 2.into());
+        reordered.extra.insert("zzz".into(), 1.into());
+        assert_eq!(reordered.to_json().unwrap(), json);
+    }
+
+    #[test]
+    fn schema() {
+        let schema = SourceSet::json_schema();
+        let props = schema["properties"].as_object().unwrap();
+        assert!(props.contains_key("version"));
+        assert!(props.contains_key("sources"));
+        assert!(!props.contains_key("index"));
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&"sources".into()));
+        assert!(schema["$defs"]["RepoInfo"]["properties"]
+            .get("repo_url")
+            .is_some());
+    }
+
+    #[test]
+    fn ndjson() {
+        let set = SourceSet::new(vec![
+            RepoInfo::new("https://github.com/org/one".into(), "a".into(), Vec::new()).unwrap(),
+            RepoInfo::new("https://github.com/org/two".into(), "b".into(), Vec::new()).unwrap(),
+        ]);
+        let mut buf = Vec::new();
+        set.write_ndjson(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let first: RepoInfo = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(&first, &set.sources()[0]);
+    }
+
+    #[test]
+    fn yaml_and_toml() {
+        let mut set = SourceSet::discovered(
+            vec![
+                RepoInfo::new("https://github.com/org/one".into(), "a".into(), Vec::new()).unwrap(),
+            ],
+            Some("fff".into()),
+            Vec::new(),
+        );
+        set.extra.insert("note".into(), "hi".into());
+        let yaml = set.to_yaml().unwrap();
+        let from_yaml: SourceSet = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(from_yaml, set);
+        let toml = set.to_toml().unwrap();
+        assert!(toml.contains("[[sources]]"), "{toml}");
+        let from_toml: SourceSet = toml::from_str(&toml).unwrap();
+        assert_eq!(from_toml, set);
+    }
+
+    #[test]
+    fn csv() {
+        let set = SourceSet::new(vec![RepoInfo::new(
+            "https://github.com/org/repo".into(),
+            "abc".into(),
+            vec!["config.yaml".into(), "other, config.yaml".into()],
+        )
+        .unwrap()]);
+        let mut buf = Vec::new();
+        set.write_csv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "repo_url,org,name,rev,config,license,family\n\
+             https://github.com/org/repo,org,repo,abc,\"config.yaml;other, config.yaml\",,\n"
+        );
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn gzip() {
+        let set = SourceSet::new(vec![RepoInfo::new(
+            "https://github.com/org/one".into(),
+            "a".into(),
+            Vec::new(),
+        )
+        .unwrap()]);
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("sources.json.gz");
+        set.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(&GZIP_MAGIC));
+        assert_eq!(SourceSet::load(&path).unwrap(), set);
+        // detection doesn't depend on the extension when reading
+        let renamed = temp.path().join("sources.json");
+        std::fs::rename(&path, &renamed).unwrap();
+        assert_eq!(SourceSet::load(&renamed).unwrap(), set);
+    }
+
+    #[test]
+    fn replace_leaves_no_temporary_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("sources.json");
+        replace_file(&path, b"one").unwrap();
+        replace_file(&path, b"two").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+        // a failed rename doesn't leave the temporary file behind either
+        std::fs::create_dir(temp.path().join("dir")).unwrap();
+        std::fs::write(temp.path().join("dir/file"), "").unwrap();
+        assert!(replace_file(&temp.path().join("dir"), b"").is_err());
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn unsupported_versions() {
+        assert!(matches!(
+            SourceSet::from_json(r#"{"version": "2.0", "sources": []}"#),
+            Err(SourceSetError::UnsupportedVersion { .. })
+        ));
+        assert!(matches!(
+            SourceSet::from_json(r#"{"sources": []}"#),
+            Err(SourceSetError::NoVersion)
+        ));
+        assert!(SourceSet::from_json(r#"{"version": "1.7", "sources": []}"#).is_ok());
+    }
+
+    #[test]
+    fn merge_strategies() {
+        let info = |name: &str, rev: &str| {
+            RepoInfo::new(
+                format!("https://github.com/org/{name}"),
+                rev.into(),
+                vec!["config.yaml".into()],
+            )
+            .unwrap()
+        };
+        let mut left = SourceSet::new(vec![info("one", "aaa"), info("two", "bbb")]);
+        let mut right = SourceSet::new(vec![info("two", "ccc"), info("three", "ddd")]);
+        left.discovered_at = Some(10);
+        right.discovered_at = Some(20);
+
+        let revs = |set: SourceSet| {
+            set.iter()
+                .map(|info| format!("{}@{}", info.repo_name(), info.git_rev()))
+                .collect::<Vec<_>>()
+        };
+        let merged = left.clone().merge(right.clone(), MergeStrategy::PreferLeft);
+        assert_eq!(revs(merged.unwrap()), ["one@aaa", "three@ddd", "two@bbb"]);
+        let merged = left
+            .clone()
+            .merge(right.clone(), MergeStrategy::PreferNewer);
+        assert_eq!(revs(merged.unwrap()), ["one@aaa", "three@ddd", "two@ccc"]);
+        let err = left.merge(right, MergeStrategy::Error).unwrap_err();
+        assert_eq!(
+            (err.left_rev.as_str(), err.right_rev.as_str()),
+            ("bbb", "ccc")
+        );
+    }
+
+    #[test]
+    fn token_envs() {
+        let info = |url: &str| RepoInfo::new(url.into(), "a".into(), Vec::new()).unwrap();
+        let mut set = SourceSet::new(vec![
+            info("https://github.com/private/font"),
+            info("https://github.com/public/font"),
+            info("https://github.com/private-other/font"),
+            info("https://gitlab.com/org/font"),
+            info("https://gitlab.com.evil.example/org/font"),
+        ]);
+        set.set_token_env("github.com/private", "PRIVATE_TOKEN");
+        set.set_token_env("gitlab.com", "GITLAB_TOKEN");
+        let tokens = set
+            .iter()
+            .map(|info| info.token_env.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Some("PRIVATE_TOKEN"),
+                None,
+                None,
+                Some("GITLAB_TOKEN"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let set = SourceSet::new(
+            [
+                "https://github.com/googlefonts/one",
+                "https://github.com/GoogleFonts/two",
+                "https://gitlab.com/googlefonts/three",
+                "https://github.com/other/four",
+            ]
+            .into_iter()
+            .map(|url| RepoInfo::new(url.into(), "HEAD".into(), Vec::new()).unwrap())
+            .collect(),
+        );
+        assert_eq!(set.filter_org("googlefonts").len(), 3);
+        assert_eq!(set.filter_host(RepoHost::GitLab).len(), 1);
+        assert_eq!(
+            set.filter_org("googlefonts")
+                .filter_host(RepoHost::GitHub)
+                .len(),
+            2
+        );
+        assert!(set.filter_family("Four").is_empty());
+        let mut set = set;
+        assert_eq!(set.by_repo_url("https://github.com/other/four/").count(), 1);
+        set.retain(|info| info.repo_name().len() == 3);
+        assert_eq!(set.len(), 2);
+        // the index is rebuilt after the set changes
+        assert_eq!(set.by_repo_url("https://github.com/other/four").count(), 0);
+        assert_eq!(
+            set.by_repo_url("https://github.com/GoogleFonts/two")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn load_unversioned_list() {
+        let json = r#"[
+  {
+    "repo_url": "https://github.com/PaoloBiagini/Joan",
+    "rev": "2b3e6ba",
+    "config_files": [
+      "config.yaml"
+    ]
+  }
+]"#;
+        let set = SourceSet::from_json(json).unwrap();
+        assert_eq!(set.version(), CURRENT_VERSION);
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.sources()[0].repo_name(), "Joan");
+        assert_eq!(set.google_fonts_rev(), None);
+        let mut count = 0;
+        crate::read_streaming(json.as_bytes(), |_| count += 1).unwrap();
+        assert_eq!(count, 1);
+        // but other shapes are still an error
+        assert!(matches!(
+            SourceSet::from_json(r#"{"sources": []}"#),
+            Err(SourceSetError::NoVersion)
+        ));
+    }
+
+    #[test]
+    fn newer_minor_version_keeps_unknown_fields() {
+        let json = r#"{"version": "1.7", "sources": [], "new_field": {"a": 1}}"#;
+        let set = SourceSet::from_json(json).unwrap();
+        assert_eq!(set.version().minor, 7);
+        assert_eq!(set.extra["new_field"]["a"], 1);
+        let reloaded = SourceSet::from_json(&set.to_json().unwrap()).unwrap();
+        assert_eq!(reloaded, set);
+    }
+
+    #[test]
+    fn newer_minor_version_drops_unknown_entry_fields() {
+        let json = r#"{"version": "1.7", "sources": [
+            {"repo_url": "https://github.com/org/repo", "rev": "abc", "config_files": [], "new_field": 1}
+        ]}"#;
+        let set = SourceSet::from_json(json).unwrap();
+        assert_eq!(set.len(), 1);
+        assert!(!set.to_json().unwrap().contains("new_field"));
+    }
+
+    #[test]
+    fn older_version_saved_as_current() {
+        let set = SourceSet::from_json(r#"{"version": "1.0", "sources": []}"#).unwrap();
+        assert_eq!(set.version(), Version { major: 1, minor: 0 });
+        let reloaded = SourceSet::from_json(&set.to_json().unwrap()).unwrap();
+        assert_eq!(reloaded.version(), CURRENT_VERSION);
+
+        let out = crate::SourceSetWriter::new(Vec::new(), &set).unwrap();
+        let out = out.finish().unwrap();
+        let streamed = SourceSet::from_json(std::str::from_utf8(&out).unwrap()).unwrap();
+        assert_eq!(streamed.version(), CURRENT_VERSION);
+    }
+
+    #[test]
+    fn index_has_one_key_per_repo() {
+        let json = r#"{"version": "1.3", "sources": [
+            {"repo_url": "https://github.com/org/multi", "rev": "abc", "config_files": [], "subdir": "One"},
+            {"repo_url": "https://github.com/org/multi", "rev": "abc", "config_files": [], "subdir": "Two"}
+        ]}"#;
+        let set = SourceSet::from_json(json).unwrap();
+        assert_eq!(set.by_repo_url("https://github.com/org/multi/").count(), 2);
+        assert_eq!(set.index().by_url.len(), 1);
+        assert_eq!(set.index().by_url["https://github.com/org/multi"], [0, 1]);
+    }
+}
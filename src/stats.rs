@@ -0,0 +1,139 @@
+//! summary statistics for a source set
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::{RepoHost, SourceSet};
+
+/// Counts of the entries in a [`SourceSet`], by various properties.
+///
+/// Unless otherwise noted, counts are of entries; a repository containing
+/// multiple projects is counted once for each.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Stats {
+    /// The total number of entries
+    pub sources: usize,
+    /// The number of distinct repositories
+    pub repos: usize,
+    /// Entries per hosting service
+    pub by_host: BTreeMap<RepoHost, usize>,
+    /// Entries per repository owner (lowercased)
+    pub by_org: BTreeMap<String, usize>,
+    /// Entries per license directory in google/fonts, or `"unknown"`
+    pub by_license: BTreeMap<String, usize>,
+    /// Config files per filename, e.g. `config.yaml`
+    pub by_config_file: BTreeMap<String, usize>,
+    /// Repositories that are pinned at more than one rev by different entries
+    pub conflicted_repos: usize,
+    /// Entries in a repository that is pinned at more than one rev
+    pub conflicted_sources: usize,
+}
+
+impl Stats {
+    pub(crate) fn new(set: &SourceSet) -> Self {
+        let mut stats = Stats {
+            sources: set.len(),
+            ..Default::default()
+        };
+        let mut repos = BTreeSet::new();
+        for info in set {
+            *stats.by_host.entry(info.repo_host()).or_default() += 1;
+            *stats
+                .by_org
+                .entry(info.repo_org().to_lowercase())
+                .or_default() += 1;
+            let license = info.license().map(|l| l.dir_name()).unwrap_or("unknown");
+            *stats.by_license.entry(license.to_owned()).or_default() += 1;
+            for config in &info.config_files {
+                let name = config
+                    .file_name()
+                    .unwrap_or(config.as_os_str())
+                    .to_string_lossy();
+                *stats.by_config_file.entry(name.into_owned()).or_default() += 1;
+            }
+            repos.insert(info.repo_url.trim_end_matches('/'));
+        }
+        stats.repos = repos.len();
+        stats.conflicted_repos = set.rev_conflicts().len();
+        stats.conflicted_sources = set
+            .rev_conflicts()
+            .iter()
+            .map(|conflict| conflict.revs.values().map(Vec::len).sum::<usize>())
+            .sum();
+        stats
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} sources in {} repositories", self.sources, self.repos)?;
+        writeln!(
+            f,
+            "{} repositories ({} sources) are pinned at more than one rev",
+            self.conflicted_repos, self.conflicted_sources
+        )?;
+        writeln!(f, "\nby host:")?;
+        for (host, count) in &self.by_host {
+            writeln!(f, "  {host:?}: {count}")?;
+        }
+        writeln!(f, "\nby license:")?;
+        for (license, count) in &self.by_license {
+            writeln!(f, "  {license}: {count}")?;
+        }
+        writeln!(f, "\nby config file:")?;
+        for (name, count) in &self.by_config_file {
+            writeln!(f, "  {name}: {count}")?;
+        }
+        writeln!(f, "\nby org:")?;
+        let mut orgs = self.by_org.iter().collect::<Vec<_>>();
+        // most common first
+        orgs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (org, count) in orgs {
+            writeln!(f, "  {org}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RepoInfo;
+
+    #[test]
+    fn counts() {
+        let set = SourceSet::new(vec![
+            RepoInfo::new(
+                "https://github.com/org/one".into(),
+                "a".into(),
+                vec!["config.yaml".into()],
+            )
+            .unwrap(),
+            RepoInfo::new(
+                "https://github.com/org/one/".into(),
+                "b".into(),
+                vec!["config.yaml".into(), "config-italic.yaml".into()],
+            )
+            .unwrap(),
+            RepoInfo::new(
+                "https://gitlab.com/Other/two".into(),
+                "c".into(),
+                vec!["config.yaml".into()],
+            )
+            .unwrap(),
+        ]);
+        let stats = set.stats();
+        assert_eq!(stats.sources, 3);
+        assert_eq!(stats.repos, 2);
+        assert_eq!(stats.by_host[&RepoHost::GitHub], 2);
+        assert_eq!(stats.by_host[&RepoHost::GitLab], 1);
+        assert_eq!(stats.by_org["other"], 1);
+        assert_eq!(stats.by_license["unknown"], 3);
+        assert_eq!(stats.by_config_file["config.yaml"], 3);
+        assert_eq!(stats.by_config_file["config-italic.yaml"], 1);
+        assert_eq!(stats.conflicted_repos, 1);
+        assert_eq!(stats.conflicted_sources, 2);
+    }
+}
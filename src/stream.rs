@@ -0,0 +1,223 @@
+//! reading and writing source sets one entry at a time
+
+use std::{
+    fmt,
+    io::{BufReader, Read, Write},
+};
+
+use serde::de::{DeserializeSeed, Error as _, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Value};
+
+use crate::{error::SourceSetError, source_set::CURRENT_VERSION, RepoInfo, SourceSet};
+
+/// Writes a [`SourceSet`] incrementally, one entry at a time.
+///
+/// The output can be read with [`SourceSet::load`] or [`read_streaming`], but
+/// unlike [`SourceSet::to_json`] entries are written in the order they are
+/// provided, one per line, and are not sorted.
+///
+/// [`finish`](Self::finish) must be called to complete the output.
+pub struct SourceSetWriter<W: Write> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> SourceSetWriter<W> {
+    /// Start writing a set, using the header fields (version, discovery time,
+    /// etc) of `header`.
+    ///
+    /// Any entries in `header` are ignored.
+    pub fn new(mut inner: W, header: &SourceSet) -> Result<Self, SourceSetError> {
+        let Value::Object(mut fields) = serde_json::to_value(header.clone_header())? else {
+            unreachable!("SourceSet always serializes as an object");
+        };
+        fields.remove("sources");
+        let fields = serde_json::to_string(&fields)?;
+        // the header without the closing brace; sources always come last
+        let fields = fields.strip_suffix('}').unwrap_or(&fields);
+        write!(inner, "{fields},\"sources\":[")?;
+        Ok(SourceSetWriter { inner, count: 0 })
+    }
+
+    /// Write a single entry.
+    pub fn write(&mut self, info: &RepoInfo) -> Result<(), SourceSetError> {
+        if self.count > 0 {
+            self.inner.write_all(b",")?;
+        }
+        self.inner.write_all(b"\n")?;
+        serde_json::to_writer(&mut self.inner, info)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Complete the output, returning the inner writer.
+    pub fn finish(mut self) -> Result<W, SourceSetError> {
+        if self.count > 0 {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.write_all(b"]}\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Read a serialized [`SourceSet`] incrementally, calling `f` with each entry.
+///
+/// This avoids holding the whole set in memory. The returned set contains the
+/// header fields (version, discovery time, etc) but no entries.
+///
+/// The version of the input is checked before any entries are read, which
+/// requires the `version` field to come before `sources`; this is true of
+/// anything written by this crate. As with [`SourceSet::from_json`], the
+/// unversioned list written by older versions of this crate is also accepted.
+pub fn read_streaming(
+    reader: impl Read,
+    f: impl FnMut(RepoInfo),
+) -> Result<SourceSet, SourceSetError> {
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let mut header_error = None;
+    let visitor = SetVisitor {
+        f,
+        error: &mut header_error,
+    };
+    let fields = match serde::Deserializer::deserialize_any(&mut de, visitor) {
+        Ok(fields) => fields,
+        Err(e) => return Err(header_error.unwrap_or(e.into())),
+    };
+    de.end()?;
+    header_from_fields(fields)
+}
+
+fn header_from_fields(mut fields: Map<String, Value>) -> Result<SourceSet, SourceSetError> {
+    fields.insert("sources".into(), Value::Array(Vec::new()));
+    SourceSet::from_value(Value::Object(fields))
+}
+
+// visits the top-level object, collecting header fields and passing
+// entries to the callback as they're parsed
+struct SetVisitor<'a, F> {
+    f: F,
+    error: &'a mut Option<SourceSetError>,
+}
+
+impl<'de, F: FnMut(RepoInfo)> Visitor<'de> for SetVisitor<'_, F> {
+    type Value = Map<String, Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a source set")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut fields = Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "sources" {
+                // make sure we can read this version before reading entries
+                if let Err(e) = header_from_fields(fields.clone()) {
+                    let msg = e.to_string();
+                    *self.error = Some(e);
+                    return Err(A::Error::custom(msg));
+                }
+                map.next_value_seed(SourcesSeed { f: &mut self.f })?;
+            } else {
+                fields.insert(key, map.next_value()?);
+            }
+        }
+        Ok(fields)
+    }
+
+    // the unversioned format, which was just a list of entries
+    fn visit_seq<A: SeqAccess<'de>>(mut self, seq: A) -> Result<Self::Value, A::Error> {
+        SourcesSeed { f: &mut self.f }.visit_seq(seq)?;
+        let mut fields = Map::new();
+        fields.insert("version".into(), CURRENT_VERSION.to_string().into());
+        Ok(fields)
+    }
+}
+
+struct SourcesSeed<'a, F> {
+    f: &'a mut F,
+}
+
+impl<'de, F: FnMut(RepoInfo)> DeserializeSeed<'de> for SourcesSeed<'_, F> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F: FnMut(RepoInfo)> Visitor<'de> for SourcesSeed<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of sources")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(info) = seq.next_element::<RepoInfo>()? {
+            (self.f)(info);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(url: &str, rev: &str) -> RepoInfo {
+        RepoInfo::new(url.into(), rev.into(), vec!["config.yaml".into()]).unwrap()
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut header = SourceSet::discovered(Vec::new(), Some("abc".into()), Vec::new());
+        header.extra.insert("note".into(), "hi".into());
+        let entries = [
+            info("https://github.com/org/b", "1"),
+            info("https://github.com/org/a", "2"),
+        ];
+
+        let mut writer = SourceSetWriter::new(Vec::new(), &header).unwrap();
+        for entry in &entries {
+            writer.write(entry).unwrap();
+        }
+        let json = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(json.lines().count(), 4, "{json}");
+
+        let loaded = SourceSet::from_json(&json).unwrap();
+        assert_eq!(loaded.sources(), entries);
+        assert_eq!(loaded.google_fonts_rev(), Some("abc"));
+        assert_eq!(loaded.extra, header.extra);
+
+        let mut seen = Vec::new();
+        let read_header = read_streaming(json.as_bytes(), |info| seen.push(info)).unwrap();
+        assert_eq!(seen, entries);
+        assert_eq!(read_header, header);
+
+        // and regular output can be read too
+        let regular = SourceSet::new(entries.to_vec()).to_json().unwrap();
+        let mut count = 0;
+        read_streaming(regular.as_bytes(), |_| count += 1).unwrap();
+        assert_eq!(count, 2);
+
+        // as can an empty set
+        let empty = SourceSetWriter::new(Vec::new(), &header).unwrap();
+        let empty = empty.finish().unwrap();
+        assert!(SourceSet::from_json(std::str::from_utf8(&empty).unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn version_checked_before_entries() {
+        let json = r#"{"version": "2.0", "sources": [{"repo_url": "https://github.com/a/b", "rev": "1", "config_files": []}]}"#;
+        let mut called = false;
+        let result = read_streaming(json.as_bytes(), |_| called = true);
+        assert!(matches!(
+            result,
+            Err(SourceSetError::UnsupportedVersion { .. })
+        ));
+        assert!(!called);
+    }
+}